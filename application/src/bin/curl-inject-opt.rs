@@ -1,4 +1,5 @@
 use curl_inject_opt_shared::{config, serialize_options};
+use std::ffi::{OsStr, OsString};
 use std::os::unix::ffi::OsStrExt;
 use std::path::PathBuf;
 use yansi::Paint;
@@ -10,8 +11,26 @@ fn main() {
 
 	let args       = curl_inject_opt::build_cli().get_matches();
 	let print_env  = args.get_flag("print-env");
-	let debug      = args.get_flag("debug");
 	let no_inherit = args.get_flag("no-inherit");
+	let https = match (args.get_flag("upgrade-https"), args.get_flag("require-https")) {
+		(_, true)     => Some("require"),
+		(true, false) => Some("upgrade"),
+		_             => None,
+	};
+	let add_ca = args.get_many::<PathBuf>("add-ca").map(|paths| {
+		let paths = paths.map(std::path::absolute).collect::<Result<Vec<_>, _>>()?;
+		std::env::join_paths(paths).map_err(std::io::Error::other)
+	});
+	let add_ca = match add_ca.transpose() {
+		Ok(x)  => x,
+		Err(e) => {
+			eprintln!("{} invalid path for --add-ca: {}", Paint::red("Error:").bold(), e);
+			std::process::exit(1);
+		}
+	};
+
+	let summary_file = args.get_one::<PathBuf>("summary-file");
+	let summary      = args.get_flag("summary") || summary_file.is_some();
 
 	let preload_lib = match config::rely_on_search() {
 		true  => PathBuf::from("libcurl_inject_opt_preload.so"),
@@ -28,16 +47,56 @@ fn main() {
 	};
 
 	// Serialize CURL options for passing through the environment.
-	let serialized_options = serialize_options(set_options.iter());
+	let serialized_options = serialize_options(&set_options);
+
+	// The environment variables for the preloaded library, from the arguments that were given.
+	let flag   = |name: &str| args.get_flag(name).then(|| OsString::from("1"));
+	let string = |name: &str| args.get_one::<String>(name).map(OsString::from);
+	let number = |name: &str| args.get_one::<u64>(name).map(|x| OsString::from(x.to_string()));
+	let list   = |name: &str| args.get_many::<String>(name).map(|x| OsString::from(x.cloned().collect::<Vec<_>>().join(",")));
+	let path   = |name: &str| absolute_path_arg(&args, name).map(OsString::from);
+	let env: Vec<(&str, OsString)> = [
+		("CURL_INJECT_OPT_DEBUG", flag("debug")),
+		("CURL_INJECT_OPT_NO_INHERIT", no_inherit.then(|| preload_lib.clone().into_os_string())),
+		("CURL_INJECT_OPT_APPLY_ONCE", flag("apply-once")),
+		("CURL_INJECT_OPT_ON_ERROR", string("on-error")),
+		("CURL_INJECT_OPT_STRICT", flag("strict")),
+		("CURL_INJECT_OPT_STRICT_MULTI_CODE", args.get_one::<i32>("strict-multi-code").map(|x| OsString::from(x.to_string()))),
+		("CURL_INJECT_OPT_HTTPS", https.map(OsString::from)),
+		("CURL_INJECT_OPT_HTTPS_EXCEPT", list("https-except")),
+		("CURL_INJECT_OPT_OFFLINE", flag("offline")),
+		("CURL_INJECT_OPT_ALLOW_HOSTS", list("allow-host")),
+		("CURL_INJECT_OPT_DENY_HOSTS", list("deny-host")),
+		("CURL_INJECT_OPT_DENY_CODE", string("deny-code")),
+		("CURL_INJECT_OPT_TLS_POLICY", list("tls-policy")),
+		("CURL_INJECT_OPT_TLS_MIN_VERSION", string("tls-min-version")),
+		("CURL_INJECT_OPT_ADD_CA", add_ca),
+		("CURL_INJECT_OPT_AUDIT", path("audit")),
+		("CURL_INJECT_OPT_REQUEST_LOG", path("request-log")),
+		("CURL_INJECT_OPT_SIGN_KEY", path("sign-key")),
+		("CURL_INJECT_OPT_SIGN_KEY_ID", string("sign-key-id")),
+		("CURL_INJECT_OPT_METRICS_TEXTFILE", path("metrics-textfile")),
+		("CURL_INJECT_OPT_METRICS_INTERVAL", number("metrics-interval")),
+		("CURL_INJECT_OPT_STATSD", string("statsd")),
+		("CURL_INJECT_OPT_OTLP_FILE", path("otlp-file")),
+		("CURL_INJECT_OPT_OTLP_ENDPOINT", string("otlp-endpoint")),
+		("CURL_INJECT_OPT_HEADER_LOG", path("header-log")),
+		("CURL_INJECT_OPT_HEADER_LOG_REDACT", string("header-log-redact")),
+		("CURL_INJECT_OPT_CAPTURE_DIR", path("capture-dir")),
+		("CURL_INJECT_OPT_CAPTURE_MAX_SIZE", number("capture-max-size")),
+		("CURL_INJECT_OPT_TRACE_DIR", path("trace-dir")),
+		("CURL_INJECT_OPT_TRACE_ASCII", flag("trace-ascii")),
+		("CURL_INJECT_OPT_HAR", path("har")),
+		("CURL_INJECT_OPT_HAR_MAX_BODY", number("har-max-body")),
+		("CURL_INJECT_OPT_REPLAY", path("replay")),
+		("CURL_INJECT_OPT_REPLAY_UNMATCHED", string("replay-unmatched")),
+		("CURL_INJECT_OPT", Some(OsString::from(OsStr::from_bytes(&serialized_options)))),
+	].into_iter().filter_map(|(name, value)| Some((name, value?))).collect();
 
 	if print_env {
-		if debug {
-			println!("CURL_INJECT_OPT_DEBUG=1");
+		for (name, value) in &env {
+			println!("{}={}", name, value.to_string_lossy());
 		}
-		if no_inherit {
-			println!("CURL_INJECT_OPT_NO_INHERIT={}", preload_lib.display());
-		}
-		println!("CURL_INJECT_OPT={}", String::from_utf8_lossy(&serialized_options));
 		return;
 	}
 
@@ -60,17 +119,77 @@ fn main() {
 		child = child.env("LD_PRELOAD", preload_lib.as_os_str());
 	}
 
-	if debug {
-		child = child.env("CURL_INJECT_OPT_DEBUG", "1");
+	for (name, value) in &env {
+		child = child.env(name, value);
 	}
 
-	if no_inherit {
-		child = child.env("CURL_INJECT_OPT_NO_INHERIT", preload_lib);
+	if summary {
+		run_with_summary(child, summary_file);
 	}
 
-	child.env("CURL_INJECT_OPT", std::ffi::OsStr::from_bytes(&serialized_options));
-
 	let error = child.exec();
 	eprintln!("{} failed to execute command: {}", Paint::red("Error:").bold(), error);
 	std::process::exit(2);
 }
+
+/// Get the path given for an option as an absolute path.
+///
+/// Exits with an error if the path can not be made absolute.
+fn absolute_path_arg(args: &clap::ArgMatches, name: &str) -> Option<PathBuf> {
+	match std::path::absolute(args.get_one::<PathBuf>(name)?) {
+		Ok(x)  => Some(x),
+		Err(e) => {
+			eprintln!("{} invalid path for --{}: {}", Paint::red("Error:").bold(), name, e);
+			std::process::exit(1);
+		}
+	}
+}
+
+/// Run the command and wait for it, then print the summary of all transfers that its processes recorded.
+///
+/// Exits with the exit status of the command.
+fn run_with_summary(child: &mut std::process::Command, summary_file: Option<&PathBuf>) -> ! {
+	use std::os::unix::process::ExitStatusExt;
+
+	let records = std::env::temp_dir().join(format!("curl-inject-opt-summary-{}", std::process::id()));
+	if let Err(e) = std::fs::OpenOptions::new().write(true).create_new(true).open(&records) {
+		eprintln!("{} failed to create {}: {}", Paint::red("Error:").bold(), records.display(), e);
+		std::process::exit(1);
+	}
+
+	let status = child.env("CURL_INJECT_OPT_SUMMARY", &records).spawn().and_then(|mut child| {
+		// Like a shell, let the command decide what to do on Ctrl+C, and print the summary afterwards.
+		// SAFETY: Setting the disposition of a signal is always safe.
+		unsafe {
+			libc::signal(libc::SIGINT, libc::SIG_IGN);
+			libc::signal(libc::SIGQUIT, libc::SIG_IGN);
+		}
+		child.wait()
+	});
+	let data = std::fs::read(&records);
+	let _ = std::fs::remove_file(&records);
+
+	let status = match status {
+		Ok(x)  => x,
+		Err(e) => {
+			eprintln!("{} failed to execute command: {}", Paint::red("Error:").bold(), e);
+			std::process::exit(2);
+		}
+	};
+	let summary = match data {
+		Ok(data) => curl_inject_opt_shared::summary::Summary::parse(&String::from_utf8_lossy(&data)),
+		Err(e)   => {
+			eprintln!("{} failed to read {}: {}", Paint::red("Error:").bold(), records.display(), e);
+			Default::default()
+		},
+	};
+
+	match summary_file {
+		None       => eprint!("{}", summary),
+		Some(path) => if let Err(e) = std::fs::write(path, summary.to_string()) {
+			eprintln!("{} failed to write summary to {}: {}", Paint::red("Error:").bold(), path.display(), e);
+		},
+	}
+
+	std::process::exit(status.code().unwrap_or_else(|| 128 + status.signal().unwrap_or(0)))
+}
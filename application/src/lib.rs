@@ -1,5 +1,6 @@
-use curl_inject_opt_shared::{OPTIONS, SetOption};
-use std::os::unix::ffi::OsStrExt;
+use curl_inject_opt_shared::{HeaderRule, Kind, Meta, OPTIONS, OptionSet, Rewrite, Rule, RuleBlock, SetOption, Value};
+use std::ffi::OsStr;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
 
 pub fn build_cli() -> clap::Command {
 	let mut app = clap::Command::new("curl-inject-opt")
@@ -15,6 +16,314 @@ pub fn build_cli() -> clap::Command {
 			.action(clap::ArgAction::SetTrue)
 			.help("Do not inject options for child processes of the subcommand.")
 		)
+		.arg(clap::Arg::new("apply-once")
+			.long("apply-once")
+			.action(clap::ArgAction::SetTrue)
+			.help("Set the options only once per handle instead of before every transfer.")
+		)
+		.arg(clap::Arg::new("on-error")
+			.long("on-error")
+			.value_name("POLICY")
+			.value_parser(["abort", "warn", "ignore"])
+			.action(clap::ArgAction::Set)
+			.help("What to do when an option can not be injected: abort the command, print a warning (default) or ignore the error.")
+		)
+		.arg(clap::Arg::new("strict")
+			.long("strict")
+			.action(clap::ArgAction::SetTrue)
+			.help("Fail the transfer instead of running it when an option can not be set.")
+		)
+		.arg(clap::Arg::new("strict-multi-code")
+			.long("strict-multi-code")
+			.value_name("CODE")
+			.value_parser(clap::value_parser!(i32))
+			.action(clap::ArgAction::Set)
+			.requires("strict")
+			.help("The error code to return from curl_multi_add_handle() in strict mode (default: CURLM_BAD_EASY_HANDLE).")
+		)
+		.arg(clap::Arg::new("merge")
+			.long("merge")
+			.value_name("OPTION")
+			.action(clap::ArgAction::Append)
+			.number_of_values(1)
+			.value_parser(OPTIONS.iter().filter(|x| x.is_mergeable()).map(|x| x.name).collect::<Vec<_>>())
+			.help("Merge the values of a list option with the value set by the program, instead of replacing it.")
+		)
+		.arg(clap::Arg::new("match")
+			.long("match")
+			.value_name("PATTERN")
+			.action(clap::ArgAction::Append)
+			.number_of_values(1)
+			.help("Apply the CURL options that follow only to URLs matching [!][SCHEME://][HOST][:PORT][/PATH], where HOST may contain wildcards.")
+		)
+		.arg(clap::Arg::new("rewrite-prefix")
+			.long("rewrite-prefix")
+			.value_names(["FROM", "TO"])
+			.action(clap::ArgAction::Append)
+			.number_of_values(2)
+			.help("Rewrite URLs starting with FROM to start with TO instead.")
+		)
+		.arg(clap::Arg::new("rewrite-regex")
+			.long("rewrite-regex")
+			.value_names(["REGEX", "REPLACEMENT"])
+			.action(clap::ArgAction::Append)
+			.number_of_values(2)
+			.help("Replace the first match of REGEX in URLs, where REPLACEMENT can refer to capture groups with $1 or ${name}.")
+		)
+		.arg(clap::Arg::new("add-query")
+			.long("add-query")
+			.value_name("NAME=VALUE")
+			.action(clap::ArgAction::Append)
+			.number_of_values(1)
+			.help("Add a query parameter to URLs, replacing existing parameters with the same name.")
+		)
+		.arg(clap::Arg::new("header-add")
+			.long("header-add")
+			.value_name("NAME: VALUE")
+			.action(clap::ArgAction::Append)
+			.number_of_values(1)
+			.help("Add a request header to the headers set by the program. The value can contain the placeholders %{uuid}, %{time}, %{counter} and %{pid}.")
+		)
+		.arg(clap::Arg::new("header-set")
+			.long("header-set")
+			.value_name("NAME: VALUE")
+			.action(clap::ArgAction::Append)
+			.number_of_values(1)
+			.help("Replace all request headers with the same name set by the program, or add the header if there are none. The value can contain the same placeholders as --header-add.")
+		)
+		.arg(clap::Arg::new("header-remove")
+			.long("header-remove")
+			.value_name("NAME")
+			.action(clap::ArgAction::Append)
+			.number_of_values(1)
+			.help("Remove all request headers with a name, including headers that CURL adds by itself such as Expect.")
+		)
+		.arg(clap::Arg::new("header-sign")
+			.long("header-sign")
+			.value_name("NAME")
+			.action(clap::ArgAction::Append)
+			.number_of_values(1)
+			.requires("sign-key")
+			.help("Sign the request with HMAC-SHA256 over the method, path, date and body hash, and put the signature in header NAME.")
+		)
+		.arg(clap::Arg::new("sign-key")
+			.long("sign-key")
+			.value_name("FILE")
+			.value_parser(clap::value_parser!(std::path::PathBuf))
+			.action(clap::ArgAction::Set)
+			.help("Read the secret key for --header-sign from FILE.")
+		)
+		.arg(clap::Arg::new("sign-key-id")
+			.long("sign-key-id")
+			.value_name("ID")
+			.action(clap::ArgAction::Set)
+			.requires("sign-key")
+			.help("Include ID in the signature header, to tell the server which key was used.")
+		)
+		.arg(clap::Arg::new("upgrade-https")
+			.long("upgrade-https")
+			.action(clap::ArgAction::SetTrue)
+			.conflicts_with("require-https")
+			.help("Rewrite plaintext http:// URLs to https:// before the transfer starts, and refuse redirects to plaintext URLs.")
+		)
+		.arg(clap::Arg::new("require-https")
+			.long("require-https")
+			.action(clap::ArgAction::SetTrue)
+			.help("Fail transfers with plaintext http:// URLs or redirects with CURLE_UNSUPPORTED_PROTOCOL.")
+		)
+		.arg(clap::Arg::new("https-except")
+			.long("https-except")
+			.value_name("PATTERN")
+			.action(clap::ArgAction::Append)
+			.number_of_values(1)
+			.value_parser(|x: &str| Rule::parse(x).map(|_| x.to_string()))
+			.help("Allow plaintext URLs that match a pattern like --match, for example a host such as localhost or *.lan.")
+		)
+		.arg(clap::Arg::new("offline")
+			.long("offline")
+			.action(clap::ArgAction::SetTrue)
+			.help("Deny transfers to anything but loopback hosts and file:// URLs, except hosts allowed with --allow-host.")
+		)
+		.arg(clap::Arg::new("allow-host")
+			.long("allow-host")
+			.value_name("PATTERN")
+			.action(clap::ArgAction::Append)
+			.number_of_values(1)
+			.value_parser(|x: &str| Rule::parse(x).map(|_| x.to_string()))
+			.help("Only allow transfers to URLs matching one of these patterns, with the same syntax as --match.")
+		)
+		.arg(clap::Arg::new("deny-host")
+			.long("deny-host")
+			.value_name("PATTERN")
+			.action(clap::ArgAction::Append)
+			.number_of_values(1)
+			.value_parser(|x: &str| Rule::parse(x).map(|_| x.to_string()))
+			.help("Deny transfers to URLs matching a pattern, with the same syntax as --match.")
+		)
+		.arg(clap::Arg::new("deny-code")
+			.long("deny-code")
+			.value_name("ERROR")
+			.value_parser(["resolve", "timeout"])
+			.action(clap::ArgAction::Set)
+			.help("Fail denied transfers with CURLE_COULDNT_RESOLVE_HOST (resolve, default) or CURLE_OPERATION_TIMEDOUT (timeout).")
+		)
+		.arg(clap::Arg::new("tls-policy")
+			.long("tls-policy")
+			.value_name("[CHECK=]ACTION")
+			.action(clap::ArgAction::Append)
+			.number_of_values(1)
+			.value_parser(parse_tls_policy)
+			.help("What to do with insecure TLS settings of the program: off, warn, fix or reject. CHECK is one of verify-peer, verify-host, min-version, ciphers or proxy-verify-peer, without CHECK the action applies to all checks.")
+		)
+		.arg(clap::Arg::new("tls-min-version")
+			.long("tls-min-version")
+			.value_name("VERSION")
+			.value_parser(["1.0", "1.1", "1.2", "1.3"])
+			.action(clap::ArgAction::Set)
+			.help("The minimum TLS version for the min-version check of --tls-policy (default: 1.2).")
+		)
+		.arg(clap::Arg::new("add-ca")
+			.long("add-ca")
+			.value_name("PEM")
+			.value_parser(clap::value_parser!(std::path::PathBuf))
+			.action(clap::ArgAction::Append)
+			.number_of_values(1)
+			.help("Trust the CA certificates in a PEM file in addition to the CA bundle of the program, instead of replacing it like --ca-info.")
+		)
+		.arg(clap::Arg::new("audit")
+			.long("audit")
+			.value_name("FILE")
+			.value_parser(clap::value_parser!(std::path::PathBuf))
+			.action(clap::ArgAction::Set)
+			.help("Append a report of insecure uses of libcurl to FILE when each process exits, without changing anything.")
+		)
+		.arg(clap::Arg::new("request-log")
+			.long("request-log")
+			.value_name("FILE")
+			.value_parser(clap::value_parser!(std::path::PathBuf))
+			.action(clap::ArgAction::Set)
+			.help("Append a line of JSON with the URL, status, sizes and timings of each finished transfer to FILE.")
+		)
+		.arg(clap::Arg::new("metrics-textfile")
+			.long("metrics-textfile")
+			.value_name("FILE")
+			.value_parser(clap::value_parser!(std::path::PathBuf))
+			.action(clap::ArgAction::Set)
+			.help("Write metrics of finished transfers to FILE in the Prometheus text format, periodically and when a process exits. A %p in FILE is replaced by the process ID.")
+		)
+		.arg(clap::Arg::new("metrics-interval")
+			.long("metrics-interval")
+			.value_name("SECONDS")
+			.value_parser(clap::value_parser!(u64))
+			.action(clap::ArgAction::Set)
+			.requires("metrics-textfile")
+			.help("Rewrite the metrics textfile every SECONDS seconds, or only when a process exits if 0. Defaults to 15.")
+		)
+		.arg(clap::Arg::new("statsd")
+			.long("statsd")
+			.value_name("HOST:PORT")
+			.action(clap::ArgAction::Set)
+			.help("Send metrics of finished transfers to a StatsD server over UDP, with DogStatsD tags.")
+		)
+		.arg(clap::Arg::new("otlp-file")
+			.long("otlp-file")
+			.value_name("FILE")
+			.value_parser(clap::value_parser!(std::path::PathBuf))
+			.action(clap::ArgAction::Set)
+			.help("Create a span for each transfer, send a traceparent header, and append the span to FILE as a line of OTLP-JSON.")
+		)
+		.arg(clap::Arg::new("otlp-endpoint")
+			.long("otlp-endpoint")
+			.value_name("URL")
+			.action(clap::ArgAction::Set)
+			.help("Like --otlp-file, but send the spans to an OTLP/HTTP collector, such as http://localhost:4318.")
+		)
+		.arg(clap::Arg::new("header-log")
+			.long("header-log")
+			.value_name("FILE")
+			.value_parser(clap::value_parser!(std::path::PathBuf))
+			.action(clap::ArgAction::Set)
+			.help("Append the status line and headers of each response to FILE.")
+		)
+		.arg(clap::Arg::new("header-log-redact")
+			.long("header-log-redact")
+			.value_name("NAMES")
+			.action(clap::ArgAction::Set)
+			.requires("header-log")
+			.help("Comma separated names of headers whose value is replaced by REDACTED in the --header-log (default: Set-Cookie,Authorization). Use an empty string to redact nothing.")
+		)
+		.arg(clap::Arg::new("capture-dir")
+			.long("capture-dir")
+			.value_name("DIR")
+			.value_parser(clap::value_parser!(std::path::PathBuf))
+			.action(clap::ArgAction::Set)
+			.help("Write the request and response body of each transfer to files in DIR, with a JSON file of metadata.")
+		)
+		.arg(clap::Arg::new("capture-max-size")
+			.long("capture-max-size")
+			.value_name("BYTES")
+			.value_parser(clap::value_parser!(u64))
+			.action(clap::ArgAction::Set)
+			.requires("capture-dir")
+			.help("Only write the first BYTES bytes of each body captured with --capture-dir.")
+		)
+		.arg(clap::Arg::new("trace-dir")
+			.long("trace-dir")
+			.value_name("DIR")
+			.value_parser(clap::value_parser!(std::path::PathBuf))
+			.action(clap::ArgAction::Set)
+			.help("Write a trace of the transfers of each handle to a file in DIR, like curl --trace, with timestamps and redacted Authorization headers.")
+		)
+		.arg(clap::Arg::new("trace-ascii")
+			.long("trace-ascii")
+			.action(clap::ArgAction::SetTrue)
+			.requires("trace-dir")
+			.help("Write the traces of --trace-dir like curl --trace-ascii, without a hex dump.")
+		)
+		.arg(clap::Arg::new("har")
+			.long("har")
+			.value_name("FILE")
+			.value_parser(clap::value_parser!(std::path::PathBuf))
+			.action(clap::ArgAction::Set)
+			.help("Record all transfers in an HTTP Archive (HAR 1.2) file, which is written when the command exits.")
+		)
+		.arg(clap::Arg::new("har-max-body")
+			.long("har-max-body")
+			.value_name("BYTES")
+			.value_parser(clap::value_parser!(u64))
+			.action(clap::ArgAction::Set)
+			.requires("har")
+			.help("Only record the first BYTES bytes of each body in the --har file [default: 1048576].")
+		)
+		.arg(clap::Arg::new("replay")
+			.long("replay")
+			.value_name("FILE")
+			.value_parser(clap::value_parser!(std::path::PathBuf))
+			.action(clap::ArgAction::Set)
+			.help("Answer transfers with the responses recorded in FILE, a HAR file or a JSON array of fixtures, without touching the network. Only curl_easy_perform() is replayed: transfers of the multi interface with a recorded response are refused.")
+		)
+		.arg(clap::Arg::new("replay-unmatched")
+			.long("replay-unmatched")
+			.value_name("ACTION")
+			.value_parser(["fail", "pass"])
+			.action(clap::ArgAction::Set)
+			.requires("replay")
+			.help("What to do with transfers without a recorded response in the --replay file: fail them (default) or let them pass.")
+		)
+		.arg(clap::Arg::new("summary")
+			.long("summary")
+			.action(clap::ArgAction::SetTrue)
+			.conflicts_with("print-env")
+			.help("Print a summary of all transfers of the command and its child processes to stderr when the command exits.")
+		)
+		.arg(clap::Arg::new("summary-file")
+			.long("summary-file")
+			.value_name("FILE")
+			.value_parser(clap::value_parser!(std::path::PathBuf))
+			.action(clap::ArgAction::Set)
+			.conflicts_with("print-env")
+			.help("Write the summary of --summary to FILE instead of stderr.")
+		)
 		.arg(clap::Arg::new("print-env")
 			.long("print-env")
 			.action(clap::ArgAction::SetTrue)
@@ -28,10 +337,16 @@ pub fn build_cli() -> clap::Command {
 		);
 
 	for option in OPTIONS {
+		let (value_name, action) = match option.kind {
+			Kind::CSlist => ("VAL", clap::ArgAction::Append),
+			Kind::CBlob  => ("FILE", clap::ArgAction::Set),
+			Kind::CFile  => ("FILE", clap::ArgAction::Set),
+			_            => ("VAL", clap::ArgAction::Set),
+		};
 		app = app.arg(clap::Arg::new(option.name)
 			.long(option.name)
-			.value_name("VAL")
-			.action(clap::ArgAction::Set)
+			.value_name(value_name)
+			.action(action)
 			.number_of_values(1)
 			.help(option.help)
 		);
@@ -40,23 +355,129 @@ pub fn build_cli() -> clap::Command {
 	app
 }
 
-pub fn extract_curl_options(matches: &clap::ArgMatches) -> Result<Vec<SetOption>, String> {
-	// Collect all occurences of curl options into a vector with the clap index, so we can sort on it.
+/// Check a `[CHECK=]ACTION` entry for `--tls-policy`.
+fn parse_tls_policy(value: &str) -> Result<String, String> {
+	const CHECKS: [&str; 5] = ["verify-peer", "verify-host", "min-version", "ciphers", "proxy-verify-peer"];
+	const ACTIONS: [&str; 4] = ["off", "warn", "fix", "reject"];
+	let (check, action) = match value.split_once('=') {
+		Some((check, action)) => (Some(check), action),
+		None                  => (None, value),
+	};
+	if let Some(check) = check.filter(|x| !CHECKS.contains(x)) {
+		return Err(format!("unknown check: {}, expected one of {}", check, CHECKS.join(", ")));
+	}
+	if !ACTIONS.contains(&action) {
+		return Err(format!("unknown action: {}, expected one of {}", action, ACTIONS.join(", ")));
+	}
+	Ok(value.to_string())
+}
+
+/// Something on the command line that ends up in the serialized options.
+enum Item<'a> {
+	Option(&'a Meta, &'a OsStr),
+	Match(&'a OsStr),
+	Rewrite(&'static str, Vec<&'a OsStr>),
+	Header(&'static str, &'a OsStr),
+}
+
+fn to_str<'a>(value: &'a OsStr, what: &str) -> Result<&'a str, String> {
+	value.to_str().ok_or_else(|| format!("{} contains invalid UTF-8: {}", what, value.to_string_lossy()))
+}
+
+pub fn extract_curl_options(matches: &clap::ArgMatches) -> Result<OptionSet, String> {
+	// Collect all occurences of curl options, rules, rewrites and header rules into a vector with the clap index, so we can sort on it.
 	// Clap stores matches in a hash map, so we have no saner way to do this.
+	let mut items = Vec::new();
+	for option in OPTIONS {
+		if let Some(values) = matches.get_raw(option.name) {
+			let indices = matches.indices_of(option.name).expect("clap match has values, but no indices");
+			items.extend(indices.zip(values).map(|(index, value)| (index, Item::Option(option, value))));
+		}
+	}
+	if let Some(values) = matches.get_raw("match") {
+		let indices = matches.indices_of("match").expect("clap match has values, but no indices");
+		items.extend(indices.zip(values).map(|(index, value)| (index, Item::Match(value))));
+	}
+	for name in ["rewrite-prefix", "rewrite-regex", "add-query"] {
+		if let Some(occurrences) = matches.get_raw_occurrences(name) {
+			let indices = matches.indices_of(name).expect("clap match has values, but no indices");
+			let mut indices = indices.step_by(if name == "add-query" { 1 } else { 2 });
+			for values in occurrences {
+				let index = indices.next().expect("clap match has fewer indices than occurrences");
+				items.push((index, Item::Rewrite(name, values.collect())));
+			}
+		}
+	}
 
-	let mut options : Vec<_> = OPTIONS.iter().filter_map(|option| {
-		let values  = matches.get_raw(option.name)?;
-		let indices = matches.indices_of(option.name).expect("clap match has values, but no indices");
-		Some((option, values, indices))
-	}).flat_map(|(option, values, indices)| {
-		std::iter::repeat(option).zip(values).zip(indices)
-	}).collect();
+	for name in HeaderRule::NAMES {
+		if let Some(values) = matches.get_raw(name) {
+			let indices = matches.indices_of(name).expect("clap match has values, but no indices");
+			items.extend(indices.zip(values).map(|(index, value)| (index, Item::Header(name, value))));
+		}
+	}
 
 	// Sort by index on the command line.
-	options.sort_unstable_by_key(|(_, index)| *index);
+	items.sort_unstable_by_key(|(index, _)| *index);
+
+	let merge: Vec<&String> = matches.get_many("merge").unwrap_or_default().collect();
+
+	// Parse the options, merging repeated list options into the first occurrence in the same block.
+	let mut result = OptionSet::<SetOption>::default();
+	for (_, item) in items {
+		let (option, value) = match item {
+			Item::Match(pattern) => {
+				let rule = Rule::parse(to_str(pattern, "URL pattern")?)?;
+				result.blocks.push(RuleBlock::new(rule));
+				continue;
+			},
+			Item::Header(name, value) => {
+				let header = HeaderRule::from_arg(name, to_str(value, "header")?)?;
+				match result.blocks.last_mut() {
+					Some(block) => block.headers.push(header),
+					None        => result.headers.push(header),
+				}
+				continue;
+			},
+			Item::Rewrite(name, values) => {
+				let rewrite = match name {
+					"add-query" => Rewrite::query(to_str(values[0], "query parameter")?)?,
+					_           => Rewrite::from_args(name, [to_str(values[0], name)?, to_str(values[1], name)?])?,
+				};
+				match result.blocks.last_mut() {
+					Some(block) => block.rewrites.push(rewrite),
+					None        => result.rewrites.push(rewrite),
+				}
+				continue;
+			},
+			Item::Option(option, value) => (option, value),
+		};
+
+		let value = match option.kind {
+			Kind::CBlob => blob_path(option.name, value)?,
+			_           => value.as_bytes().to_vec(),
+		};
+		let option = SetOption::parse_value(*option, &value)?;
+		let option = SetOption { merge: merge.iter().any(|x| *x == option.name), ..option };
+		let parsed = match result.blocks.last_mut() {
+			Some(block) => &mut block.options,
+			None        => &mut result.options,
+		};
+		match (parsed.iter_mut().find(|x| x.option == option.option), option.value) {
+			(Some(SetOption { value: Value::CSlist(items), .. }), Value::CSlist(new_items)) => items.extend(new_items),
+			(_, value) => parsed.push(SetOption { value, ..option }),
+		}
+	}
+
+	Ok(result)
+}
 
-	// Parse the options.
-	options.into_iter().map(|((option, value), _)| SetOption::parse_value(*option, value.as_bytes())).collect()
+/// Get the absolute path of a file for a blob option, which the preloaded library reads.
+///
+/// The file is opened here so that a missing file is reported before the command starts.
+fn blob_path(name: &str, path: &OsStr) -> Result<Vec<u8>, String> {
+	let error = |e: std::io::Error| format!("failed to read {} for option {}: {}", path.to_string_lossy(), name, e);
+	std::fs::File::open(path).map_err(error)?;
+	Ok(std::path::absolute(path).map_err(error)?.into_os_string().into_vec())
 }
 
 pub fn should_color(output: &impl std::io::IsTerminal) -> bool {
@@ -0,0 +1,19 @@
+fn main() {
+	// Define the symbol versions of libcurl, so we can export our hooks with matching versions.
+	let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").expect("failed to get CARGO_MANIFEST_DIR from environment");
+	println!("cargo:rerun-if-changed=symbols.map");
+	println!("cargo:rustc-cdylib-link-arg=-Wl,--version-script={}/symbols.map", manifest_dir);
+
+	// The hooks for curl_easy_setopt() are written in C, because they are variadic.
+	// Build them twice, with and without symbol versions, and let lib.rs pick one (see hooks.c).
+	let out_dir = std::env::var("OUT_DIR").expect("failed to get OUT_DIR from environment");
+	println!("cargo:rerun-if-changed=src/hooks.c");
+	println!("cargo:rustc-link-search=native={}", out_dir);
+	cc::Build::new().file("src/hooks.c").define("CURL_INJECT_OPT_SYMVER", None).cargo_metadata(false).compile("curl_inject_opt_hooks");
+	cc::Build::new().file("src/hooks.c").cargo_metadata(false).compile("curl_inject_opt_hooks_unversioned");
+
+	// Recording stand-in for curl_easy_setopt() used by the unit tests.
+	// Build scripts can not tell if the crate is built for tests, so only the tests link it (see setopt.rs).
+	println!("cargo:rerun-if-changed=src/setopt_test.c");
+	cc::Build::new().file("src/setopt_test.c").cargo_metadata(false).compile("curl_inject_opt_setopt_test");
+}
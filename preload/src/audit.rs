@@ -0,0 +1,108 @@
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use crate::lock_ignore_poison;
+
+/// An insecure use of libcurl.
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct Finding {
+	/// The host of the transfer, or `-` if it is unknown.
+	pub host: String,
+
+	/// The option involved.
+	pub option: &'static str,
+
+	/// What is insecure about it.
+	pub issue: String,
+}
+
+/// Collects insecure uses of libcurl, to write a report when the process exits.
+#[derive(Default)]
+pub struct Audit {
+	/// The file to append the report to, or `None` if auditing is disabled.
+	path: Option<PathBuf>,
+
+	/// The findings so far, without duplicates.
+	findings: Mutex<BTreeSet<Finding>>,
+}
+
+impl Audit {
+	/// Get the report file from the CURL_INJECT_OPT_AUDIT environment variable.
+	pub fn from_env() -> Self {
+		Self {
+			path: std::env::var_os("CURL_INJECT_OPT_AUDIT").filter(|x| !x.is_empty()).map(PathBuf::from),
+			findings: Mutex::default(),
+		}
+	}
+
+	/// Check if auditing is enabled.
+	pub fn is_enabled(&self) -> bool {
+		self.path.is_some()
+	}
+
+	/// Record a finding.
+	pub fn record(&self, host: Option<&str>, option: &'static str, issue: impl Into<String>) {
+		if !self.is_enabled() {
+			return;
+		}
+		let finding = Finding {
+			host: host.filter(|x| !x.is_empty()).unwrap_or("-").to_string(),
+			option,
+			issue: issue.into(),
+		};
+		lock_ignore_poison(&self.findings).insert(finding);
+	}
+
+	/// Append the report for this process to the report file.
+	///
+	/// Nothing is written if there are no findings.
+	/// The report is written with a single `write()` call, so reports of processes that exit at the same time are not mixed up.
+	pub fn write_report(&self) -> Result<(), String> {
+		let Some(path) = &self.path else {
+			return Ok(());
+		};
+		let findings = std::mem::take(&mut *lock_ignore_poison(&self.findings));
+		if findings.is_empty() {
+			return Ok(());
+		}
+
+		let executable = std::env::current_exe().map_or_else(|_| String::from("-"), |x| x.display().to_string());
+		let mut report = format!("# {} (pid {})\n", executable, std::process::id());
+		for finding in &findings {
+			report += &format!("{}\t{}\t{}\t{}\n", executable, finding.host, finding.option, finding.issue);
+		}
+
+		let mut file = std::fs::OpenOptions::new()
+			.create(true)
+			.append(true)
+			.open(path)
+			.map_err(|e| format!("failed to open audit report {}: {}", path.display(), e))?;
+		file.write_all(report.as_bytes()).map_err(|e| format!("failed to write audit report {}: {}", path.display(), e))
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::testing::TempPath;
+
+	#[test]
+	fn test_report() {
+		let path = TempPath::new("audit");
+		let audit = Audit { path: Some(path.to_path_buf()), findings: Mutex::default() };
+		audit.record(Some("example.com"), "CURLOPT_SSL_VERIFYPEER", "TLS peer verification disabled");
+		audit.record(Some("example.com"), "CURLOPT_SSL_VERIFYPEER", "TLS peer verification disabled");
+		audit.record(None, "CURLOPT_PROTOCOLS", "all protocols allowed");
+		audit.write_report().unwrap();
+		audit.write_report().unwrap();
+
+		let report = std::fs::read_to_string(&path).unwrap();
+		let lines: Vec<_> = report.lines().collect();
+		assert!(lines.len() == 3);
+		assert!(lines[0].starts_with("# "));
+		assert!(lines[1].ends_with("\t-\tCURLOPT_PROTOCOLS\tall protocols allowed"));
+		assert!(lines[2].ends_with("\texample.com\tCURLOPT_SSL_VERIFYPEER\tTLS peer verification disabled"));
+	}
+}
@@ -0,0 +1,59 @@
+/// The standard base64 alphabet.
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Encode data as base64 with padding.
+pub fn encode(data: &[u8]) -> String {
+	let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+	for chunk in data.chunks(3) {
+		let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, x)| bits | u32::from(*x) << (16 - 8 * i));
+		for i in 0..4 {
+			match i <= chunk.len() {
+				true  => encoded.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char),
+				false => encoded.push('='),
+			}
+		}
+	}
+	encoded
+}
+
+/// Decode base64 with or without padding, ignoring whitespace.
+pub fn decode(encoded: &str) -> Result<Vec<u8>, String> {
+	let mut decoded = Vec::with_capacity(encoded.len() / 4 * 3);
+	let mut bits = 0u32;
+	let mut count = 0;
+	for c in encoded.trim_end_matches(|c: char| c == '=' || c.is_ascii_whitespace()).bytes().filter(|x| !x.is_ascii_whitespace()) {
+		let Some(value) = ALPHABET.iter().position(|x| *x == c) else {
+			return Err(format!("invalid base64 character: {:?}", c as char));
+		};
+		bits = bits << 6 | value as u32;
+		count += 1;
+		if count == 4 {
+			decoded.extend_from_slice(&bits.to_be_bytes()[1..]);
+			bits = 0;
+			count = 0;
+		}
+	}
+	match count {
+		0 => (),
+		2 => decoded.push((bits >> 4) as u8),
+		3 => decoded.extend_from_slice(&((bits >> 2) as u16).to_be_bytes()),
+		_ => return Err(String::from("invalid base64 length")),
+	}
+	Ok(decoded)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_base64() {
+		for data in [&b""[..], b"a", b"ab", b"abc", b"\xff\x00\x01\x02\x03"] {
+			assert_eq!(decode(&encode(data)).unwrap(), data);
+		}
+		assert_eq!(encode(b"ab"), "YWI=");
+		assert_eq!(decode("YW\nJj").unwrap(), b"abc");
+		assert!(decode("YWJjZ").is_err());
+		assert!(decode("YW*j").is_err());
+	}
+}
@@ -0,0 +1,336 @@
+use std::fs::File;
+use std::io::Write;
+use std::os::raw::{c_char, c_void};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use curl_inject_opt_shared::reexports::curl_sys;
+
+use curl_sys::CURL;
+use curl_sys::CURLcode;
+
+use crate::{forward_write, lock_ignore_poison, post_fields, stdin, with_state, CurlInjectOpt};
+use crate::curl::{self, Library};
+use crate::date::Date;
+use crate::error_policy;
+use crate::handles::{PostFields, RequestKind};
+use crate::json;
+use crate::request_log::Transfer;
+use crate::setopt::{self, Arg};
+
+/// Captures the request and response bodies of transfers to files in a directory.
+#[derive(Default)]
+pub struct Capture {
+	/// The directory to write the files to, or `None` if capturing is disabled.
+	dir: Option<PathBuf>,
+
+	/// The maximum number of bytes to capture of each body, or `None` for no limit.
+	max_size: Option<u64>,
+
+	/// The number of transfers captured by the process, to give each transfer its own files.
+	transfers: AtomicU64,
+}
+
+impl Capture {
+	/// Read the directory from CURL_INJECT_OPT_CAPTURE_DIR, and the size limit from CURL_INJECT_OPT_CAPTURE_MAX_SIZE.
+	///
+	/// The directory is created if it does not exist yet.
+	pub fn from_env() -> Result<Self, String> {
+		let Some(dir) = std::env::var_os("CURL_INJECT_OPT_CAPTURE_DIR").filter(|x| !x.is_empty()) else {
+			return Ok(Self::default());
+		};
+		let dir = PathBuf::from(dir);
+		std::fs::create_dir_all(&dir).map_err(|e| format!("failed to create capture directory {}: {}", dir.display(), e))?;
+		let max_size = match std::env::var("CURL_INJECT_OPT_CAPTURE_MAX_SIZE") {
+			Ok(x) if !x.is_empty() => Some(x.parse::<u64>().map_err(|_| format!("invalid capture size limit: {}", x))?),
+			_                      => None,
+		};
+		Ok(Self { dir: Some(dir), max_size, transfers: AtomicU64::new(0) })
+	}
+
+	/// Check if bodies are captured.
+	pub fn is_enabled(&self) -> bool {
+		self.dir.is_some()
+	}
+
+	/// Start capturing a new transfer of a handle.
+	///
+	/// The files are named `<pid>-<handle>-<n>` with the extensions `.req`, `.resp` and `.json`, where `<n>` counts the transfers of the process.
+	pub fn start(&self, handle: *mut CURL) -> Option<CapturedTransfer> {
+		let dir = self.dir.as_ref()?;
+		let number = self.transfers.fetch_add(1, Ordering::Relaxed) + 1;
+		let pid = std::process::id();
+		let name = format!("{}-{:x}-{}", pid, handle as usize, number);
+		Some(CapturedTransfer {
+			base: dir.join(name),
+			pid,
+			handle: handle as usize,
+			number,
+			started: Date::now(),
+			request: Mutex::new(Body::new(self.max_size)),
+			response: Mutex::new(Body::new(self.max_size)),
+		})
+	}
+}
+
+/// The files of one captured transfer.
+pub struct CapturedTransfer {
+	/// The path of the files, without extension.
+	base: PathBuf,
+
+	/// The process that ran the transfer.
+	pid: u32,
+
+	/// The address of the handle of the transfer.
+	handle: usize,
+
+	/// The number of the transfer in the process.
+	number: u64,
+
+	/// When the transfer started.
+	started: Date,
+
+	/// The request body, which goes to the `.req` file.
+	request: Mutex<Body>,
+
+	/// The response body, which goes to the `.resp` file.
+	response: Mutex<Body>,
+}
+
+impl CapturedTransfer {
+	/// Append data to the captured request body.
+	pub fn request(&self, data: &[u8]) {
+		lock_ignore_poison(&self.request).write(self.base.with_extension("req"), data);
+	}
+
+	/// Append data to the captured response body.
+	pub fn response(&self, data: &[u8]) {
+		lock_ignore_poison(&self.response).write(self.base.with_extension("resp"), data);
+	}
+
+	/// Write the metadata of the finished transfer to the `.json` file.
+	pub fn finish(&self, transfer: &Transfer) -> Result<(), String> {
+		let path = self.base.with_extension("json");
+		std::fs::write(&path, self.to_json(transfer)).map_err(|e| format!("failed to write {}: {}", path.display(), e))
+	}
+
+	fn to_json(&self, transfer: &Transfer) -> String {
+		let request = lock_ignore_poison(&self.request);
+		let response = lock_ignore_poison(&self.response);
+		let mut json = json::Object::new()
+			.number("pid", Some(self.pid))
+			.string("handle", &format!("{:#x}", self.handle))
+			.number("transfer", Some(self.number))
+			.string("started", &self.started.rfc3339())
+			.optional_string("method", transfer.method.as_deref())
+			.optional_string("url", transfer.url.as_deref())
+			.number("result", Some(transfer.result))
+			.number("response_code", transfer.response_code)
+			.optional_string("content_type", transfer.content_type.as_deref())
+			.number("request_bytes", Some(request.size))
+			.number("request_captured", Some(request.written))
+			.number("response_bytes", Some(response.size))
+			.number("response_captured", Some(response.written))
+			.finish();
+		json.push('\n');
+		json
+	}
+}
+
+/// One captured body.
+struct Body {
+	/// The file, once the first data arrived.
+	file: Option<File>,
+
+	/// The maximum number of bytes to write, or `None` for no limit.
+	max_size: Option<u64>,
+
+	/// The size of the body, including the part that was not written.
+	size: u64,
+
+	/// The number of bytes written to the file.
+	written: u64,
+
+	/// True if writing failed, so the error is only reported once.
+	failed: bool,
+}
+
+impl Body {
+	fn new(max_size: Option<u64>) -> Self {
+		Self { file: None, max_size, size: 0, written: 0, failed: false }
+	}
+
+	/// Append data to the file, up to the size limit.
+	fn write(&mut self, path: PathBuf, data: &[u8]) {
+		self.size += data.len() as u64;
+		let room = self.max_size.map_or(u64::MAX, |x| x.saturating_sub(self.written));
+		let data = &data[..data.len().min(usize::try_from(room).unwrap_or(usize::MAX))];
+		if data.is_empty() || self.failed {
+			return;
+		}
+		let result = match &mut self.file {
+			Some(file) => file.write_all(data),
+			None       => File::create(&path).and_then(|mut file| {
+				file.write_all(data)?;
+				self.file = Some(file);
+				Ok(())
+			}),
+		};
+		match result {
+			Ok(()) => self.written += data.len() as u64,
+			Err(e) => {
+				self.failed = true;
+				error_policy::report(format_args!("failed to capture body to {}: {}", path.display(), e));
+			},
+		}
+	}
+}
+
+impl CurlInjectOpt {
+	/// Install our write and read callbacks to capture or record the bodies of the next transfer.
+	///
+	/// The callbacks of the application, or the default `fwrite()` and `fread()`, are called from ours.
+	/// A body set with CURLOPT_POSTFIELDS is captured right away, since libcurl does not read it through a callback.
+	pub fn install_body_callbacks(&self, library: &Library, handle: *mut CURL) -> CURLcode {
+		let captured = self.capture.start(handle).map(Arc::new);
+		let har = self.har.start().map(Arc::new);
+		if captured.is_none() && har.is_none() {
+			return curl_sys::CURLE_OK;
+		}
+		let (request, read, read_data) = self.handles.with(handle, |state| {
+			state.captured = captured.clone();
+			state.har = har.clone();
+			(state.request.clone(), state.read, state.read_data)
+		});
+		let fields = match (request.kind, &request.fields) {
+			// SAFETY: The application keeps the data alive for the transfer, and libcurl accepted the size.
+			(RequestKind::Post, Some(PostFields::Pointer(address))) => Some(unsafe { post_fields(*address, request.fields_size) }),
+			(RequestKind::Post, Some(PostFields::Copy(data)))       => Some(data.as_slice()),
+			_ => None,
+		};
+		if let Some(fields) = fields {
+			captured.iter().for_each(|x| x.request(fields));
+			har.iter().for_each(|x| x.request(fields));
+		}
+
+		let mut callbacks = vec![(curl_sys::CURLOPT_WRITEFUNCTION, write_callback_fn(), curl_sys::CURLOPT_WRITEDATA)];
+		// Streamed form parts are read with CURLOPT_READFUNCTION, but with their own user data pointer instead of CURLOPT_READDATA.
+		// Forms are not captured, and the read callback of the application is restored for them.
+		if request.kind == RequestKind::PostForm {
+			for code in [
+				self.setopt(library, handle, curl_sys::CURLOPT_READFUNCTION, Arg::Function(read), "restore CURLOPT_READFUNCTION"),
+				self.setopt(library, handle, curl_sys::CURLOPT_READDATA, Arg::Pointer(read_data as *mut c_void), "restore CURLOPT_READDATA"),
+			] {
+				if code != curl_sys::CURLE_OK {
+					return code;
+				}
+			}
+		} else {
+			callbacks.push((curl_sys::CURLOPT_READFUNCTION, read_callback_fn(), curl_sys::CURLOPT_READDATA));
+		}
+		for (option, callback, data) in callbacks {
+			let code = self.setopt(library, handle, option, Arg::Function(Some(callback)), "install capture callback");
+			if code != curl_sys::CURLE_OK {
+				return code;
+			}
+			let code = self.setopt(library, handle, data, Arg::Pointer(handle.cast()), "set user data of capture callback");
+			if code != curl_sys::CURLE_OK {
+				return code;
+			}
+		}
+		curl_sys::CURLE_OK
+	}
+}
+
+/// Our CURLOPT_WRITEFUNCTION callback, which captures the response body.
+///
+/// The user data pointer is the handle itself.
+extern "C" fn write_callback(buffer: *mut c_char, size: usize, nitems: usize, data: *mut c_void) -> usize {
+	let handle: *mut CURL = data.cast();
+	let (write, write_data) = with_state(|init| init.handles.with_existing(handle, |state| (state.write, state.write_data))).flatten().unwrap_or((None, 0));
+	// SAFETY: libcurl passes a valid buffer, and the application set the callback and user data.
+	let written = unsafe { forward_write(write, buffer, size, nitems, write_data) };
+
+	// Only capture what the application accepted, and nothing if it paused or failed the transfer.
+	if written <= size * nitems {
+		let (captured, har) = with_state(|init| init.handles.with_existing(handle, |state| (state.captured.clone(), state.har.clone()))).flatten().unwrap_or_default();
+		// SAFETY: libcurl passes a buffer of `size * nitems` bytes.
+		let data = unsafe { std::slice::from_raw_parts(buffer.cast::<u8>(), written) };
+		captured.iter().for_each(|x| x.response(data));
+		har.iter().for_each(|x| x.response(data));
+	}
+	written
+}
+
+/// Our CURLOPT_READFUNCTION callback, which captures the request body.
+///
+/// The user data pointer is the handle itself.
+extern "C" fn read_callback(buffer: *mut c_char, size: usize, nitems: usize, data: *mut c_void) -> usize {
+	let handle: *mut CURL = data.cast();
+	let (read, read_data, captured, har) = with_state(|init| init.handles.with_existing(handle, |state| {
+		(state.read, state.read_data, state.captured.clone(), state.har.clone())
+	})).flatten().unwrap_or((None, 0, None, None));
+	let count = match read {
+		// SAFETY: The application set this function as CURLOPT_READFUNCTION, so it has this signature.
+		Some(read) => unsafe {
+			let read = std::mem::transmute::<setopt::Function, curl::CurlReadCallback>(read);
+			read(buffer, size, nitems, read_data as *mut c_void)
+		},
+		// SAFETY: libcurl passes a buffer of `size * nitems` bytes, and the application set the file or left the default.
+		None => unsafe {
+			let file = if read_data == 0 { stdin } else { read_data as *mut libc::FILE };
+			libc::fread(buffer.cast(), size, nitems, file)
+		},
+	};
+
+	// Aborting or pausing the transfer is signalled with values larger than the buffer.
+	if count <= size * nitems {
+		// SAFETY: The callback filled the first `count` bytes of the buffer.
+		let data = unsafe { std::slice::from_raw_parts(buffer.cast::<u8>(), count) };
+		captured.iter().for_each(|x| x.request(data));
+		har.iter().for_each(|x| x.request(data));
+	}
+	count
+}
+
+/// Get our capture callbacks as generic function pointers.
+fn write_callback_fn() -> setopt::Function {
+	let callback: curl::CurlWriteCallback = write_callback;
+	// SAFETY: Function pointers all have the same representation, libcurl calls it with the right signature.
+	unsafe { std::mem::transmute::<curl::CurlWriteCallback, setopt::Function>(callback) }
+}
+
+fn read_callback_fn() -> setopt::Function {
+	let callback: curl::CurlReadCallback = read_callback;
+	// SAFETY: Function pointers all have the same representation, libcurl calls it with the right signature.
+	unsafe { std::mem::transmute::<curl::CurlReadCallback, setopt::Function>(callback) }
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::testing::{TempPath, fake_handle};
+
+	#[test]
+	fn test_capture() {
+		let dir = TempPath::new("capture");
+		std::fs::create_dir_all(&dir).unwrap();
+		let capture = Capture { dir: Some(dir.to_path_buf()), max_size: Some(5), transfers: AtomicU64::new(0) };
+		let captured = capture.start(fake_handle(1)).unwrap();
+		captured.request(b"abc");
+		captured.response(b"hello ");
+		captured.response(b"world");
+		let transfer = Transfer { method: Some("POST".into()), response_code: Some(200), ..Transfer::default() };
+		captured.finish(&transfer).unwrap();
+
+		let base = dir.join(format!("{}-1234-1", std::process::id()));
+		let request = std::fs::read(base.with_extension("req")).unwrap();
+		let response = std::fs::read(base.with_extension("resp")).unwrap();
+		let meta = std::fs::read_to_string(base.with_extension("json")).unwrap();
+		assert_eq!(request, b"abc");
+		assert_eq!(response, b"hello");
+		assert!(meta.contains(r#""handle":"0x1234","transfer":1,"#));
+		assert!(meta.trim_end().ends_with(r#""request_bytes":3,"request_captured":3,"response_bytes":11,"response_captured":5}"#));
+	}
+}
@@ -0,0 +1,230 @@
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_long, c_uint, c_void};
+
+use curl_inject_opt_shared::reexports::curl_sys;
+
+use curl_sys::CURL;
+use curl_sys::CURLM;
+use curl_sys::CURLMcode;
+use curl_sys::CURLMsg;
+use curl_sys::CURLINFO;
+use curl_sys::CURLcode;
+use curl_sys::CURLoption;
+use curl_sys::curl_off_t;
+
+use crate::symbols::{NextFn, Symbol};
+
+pub type CurlEasyInit       = extern "C" fn() -> *mut CURL;
+pub type CurlEasySetOpt     = extern "C" fn(handle: *mut CURL, option: CURLoption, ...) -> CURLcode;
+pub type CurlEasyGetInfo    = extern "C" fn(handle: *mut CURL, info: CURLINFO, ...) -> CURLcode;
+pub type CurlEasyPerform    = extern "C" fn(handle: *mut CURL) -> CURLcode;
+pub type CurlEasyReset      = extern "C" fn(handle: *mut CURL);
+pub type CurlEasyDupHandle  = extern "C" fn(handle: *mut CURL) -> *mut CURL;
+pub type CurlEasyCleanup    = extern "C" fn(handle: *mut CURL);
+pub type CurlMultiAddHandle = extern "C" fn(multi_handle: *mut CURLM, handle: *mut CURL) -> CURLMcode;
+pub type CurlMultiInfoRead  = extern "C" fn(multi_handle: *mut CURLM, msgs_in_queue: *mut c_int) -> *mut CURLMsg;
+pub type CurlPrereqCallback = extern "C" fn(data: *mut c_void, primary_ip: *mut c_char, local_ip: *mut c_char, primary_port: c_int, local_port: c_int) -> c_int;
+pub type CurlWriteCallback  = extern "C" fn(buffer: *mut c_char, size: usize, nitems: usize, data: *mut c_void) -> usize;
+pub type CurlReadCallback   = extern "C" fn(buffer: *mut c_char, size: usize, nitems: usize, data: *mut c_void) -> usize;
+pub type CurlDebugCallback  = extern "C" fn(handle: *mut CURL, kind: curl_sys::curl_infotype, data: *mut c_char, size: usize, user_data: *mut c_void) -> c_int;
+pub type CurlUrl            = extern "C" fn() -> *mut CURLU;
+pub type CurlUrlSet         = extern "C" fn(url: *mut CURLU, part: CURLUPart, content: *const c_char, flags: c_uint) -> CURLUcode;
+pub type CurlUrlGet         = extern "C" fn(url: *mut CURLU, part: CURLUPart, content: *mut *mut c_char, flags: c_uint) -> CURLUcode;
+pub type CurlUrlCleanup     = extern "C" fn(url: *mut CURLU);
+pub type CurlFree           = extern "C" fn(pointer: *mut c_void);
+
+/// The URL API of libcurl is not in curl-sys.
+#[allow(clippy::upper_case_acronyms)]
+pub enum CURLU {}
+pub type CURLUPart = c_int;
+pub type CURLUcode = c_int;
+
+pub const CURLOPT_CURLU: CURLoption = curl_sys::CURLOPTTYPE_OBJECTPOINT + 282;
+pub const CURLOPT_PREREQFUNCTION: CURLoption = curl_sys::CURLOPTTYPE_FUNCTIONPOINT + 312;
+pub const CURLOPT_PREREQDATA: CURLoption = curl_sys::CURLOPTTYPE_OBJECTPOINT + 313;
+pub const CURL_PREREQFUNC_OK: c_int = 0;
+pub const CURL_PREREQFUNC_ABORT: c_int = 1;
+pub const CURLOPT_XOAUTH2_BEARER: CURLoption = curl_sys::CURLOPTTYPE_OBJECTPOINT + 220;
+pub const CURLOPT_MIMEPOST: CURLoption = curl_sys::CURLOPTTYPE_OBJECTPOINT + 269;
+pub const CURLOPT_PROTOCOLS_STR: CURLoption = curl_sys::CURLOPTTYPE_OBJECTPOINT + 318;
+pub const CURLINFO_CAINFO: CURLINFO = curl_sys::CURLINFO_STRING + 61;
+pub const CURLINFO_EFFECTIVE_METHOD: CURLINFO = curl_sys::CURLINFO_STRING + 58;
+pub const CURLINFO_HTTP_VERSION: CURLINFO = curl_sys::CURLINFO_LONG + 46;
+pub const CURLINFO_PROTOCOL: CURLINFO = curl_sys::CURLINFO_LONG + 48;
+pub const CURLINFO_SCHEME: CURLINFO = curl_sys::CURLINFO_STRING + 49;
+pub const CURLINFO_OFF_T: CURLINFO = 0x600000;
+pub const CURLINFO_SIZE_UPLOAD_T: CURLINFO = CURLINFO_OFF_T + 7;
+pub const CURLINFO_SIZE_DOWNLOAD_T: CURLINFO = CURLINFO_OFF_T + 8;
+pub const CURLINFO_TOTAL_TIME_T: CURLINFO = CURLINFO_OFF_T + 50;
+pub const CURLINFO_NAMELOOKUP_TIME_T: CURLINFO = CURLINFO_OFF_T + 51;
+pub const CURLINFO_CONNECT_TIME_T: CURLINFO = CURLINFO_OFF_T + 52;
+pub const CURLINFO_PRETRANSFER_TIME_T: CURLINFO = CURLINFO_OFF_T + 53;
+pub const CURLINFO_STARTTRANSFER_TIME_T: CURLINFO = CURLINFO_OFF_T + 54;
+pub const CURLINFO_APPCONNECT_TIME_T: CURLINFO = CURLINFO_OFF_T + 56;
+pub const CURL_MAX_WRITE_SIZE: usize = 16384;
+pub const CURLUPART_URL: CURLUPart = 0;
+pub const CURLUE_OK: CURLUcode = 0;
+pub const CURLU_NON_SUPPORT_SCHEME: c_uint = 1 << 3;
+pub const CURLU_GUESS_SCHEME: c_uint = 1 << 9;
+pub const CURLU_URLENCODE: c_uint = 1 << 7;
+pub const CURLU_ALLOW_SPACE: c_uint = 1 << 11;
+
+/// The functions of one flavour of libcurl.
+///
+/// Debian and derivatives ship libcurl in different flavours, distinguished by the symbol version of the exported functions.
+/// Each flavour gets its own set of hooks, so calls are always forwarded to the library they were meant for.
+pub struct Library {
+	pub curl_easy_init: NextFn<CurlEasyInit>,
+	pub curl_easy_perform: NextFn<CurlEasyPerform>,
+	pub curl_easy_setopt: NextFn<CurlEasySetOpt>,
+	pub curl_easy_getinfo: NextFn<CurlEasyGetInfo>,
+	pub curl_easy_reset: NextFn<CurlEasyReset>,
+	pub curl_easy_duphandle: NextFn<CurlEasyDupHandle>,
+	pub curl_easy_cleanup: NextFn<CurlEasyCleanup>,
+	pub curl_multi_add_handle: NextFn<CurlMultiAddHandle>,
+	pub curl_multi_info_read: NextFn<CurlMultiInfoRead>,
+	pub curl_url: NextFn<CurlUrl>,
+	pub curl_url_set: NextFn<CurlUrlSet>,
+	pub curl_url_get: NextFn<CurlUrlGet>,
+	pub curl_url_cleanup: NextFn<CurlUrlCleanup>,
+	pub curl_free: NextFn<CurlFree>,
+}
+
+/// Unversioned libcurl, as shipped by upstream and most distributions.
+pub static LIBCURL: Library = Library::new(None);
+
+/// The OpenSSL flavour of libcurl on Debian (libcurl.so.4).
+pub static LIBCURL_OPENSSL_4: Library = Library::new(Some(c"CURL_OPENSSL_4"));
+
+/// The GnuTLS flavour of libcurl on Debian (libcurl-gnutls.so.4).
+pub static LIBCURL_GNUTLS_3: Library = Library::new(Some(c"CURL_GNUTLS_3"));
+
+/// All supported flavours, versioned flavours first.
+pub static LIBRARIES: [&Library; 3] = [&LIBCURL_OPENSSL_4, &LIBCURL_GNUTLS_3, &LIBCURL];
+
+impl Library {
+	const fn new(version: Option<&'static CStr>) -> Self {
+		Self {
+			curl_easy_init: NextFn::new(c"curl_easy_init", version),
+			curl_easy_perform: NextFn::new(c"curl_easy_perform", version),
+			curl_easy_setopt: NextFn::new(c"curl_easy_setopt", version),
+			curl_easy_getinfo: NextFn::new(c"curl_easy_getinfo", version),
+			curl_easy_reset: NextFn::new(c"curl_easy_reset", version),
+			curl_easy_duphandle: NextFn::new(c"curl_easy_duphandle", version),
+			curl_easy_cleanup: NextFn::new(c"curl_easy_cleanup", version),
+			curl_multi_add_handle: NextFn::new(c"curl_multi_add_handle", version),
+			curl_multi_info_read: NextFn::new(c"curl_multi_info_read", version),
+			curl_url: NextFn::new(c"curl_url", version),
+			curl_url_set: NextFn::new(c"curl_url_set", version),
+			curl_url_get: NextFn::new(c"curl_url_get", version),
+			curl_url_cleanup: NextFn::new(c"curl_url_cleanup", version),
+			curl_free: NextFn::new(c"curl_free", version),
+		}
+	}
+
+	/// Get all functions that we need from the library.
+	pub fn symbols(&self) -> [&Symbol; 14] {
+		[
+			&self.curl_easy_init.symbol,
+			&self.curl_easy_perform.symbol,
+			&self.curl_easy_setopt.symbol,
+			&self.curl_easy_getinfo.symbol,
+			&self.curl_easy_reset.symbol,
+			&self.curl_easy_duphandle.symbol,
+			&self.curl_easy_cleanup.symbol,
+			&self.curl_multi_add_handle.symbol,
+			&self.curl_multi_info_read.symbol,
+			&self.curl_url.symbol,
+			&self.curl_url_set.symbol,
+			&self.curl_url_get.symbol,
+			&self.curl_url_cleanup.symbol,
+			&self.curl_free.symbol,
+		]
+	}
+
+	/// Get a string from `curl_easy_getinfo()`, or `None` if libcurl returned a null pointer.
+	pub fn info_string(&self, handle: *mut CURL, info: CURLINFO) -> Result<Option<CString>, String> {
+		let curl_easy_getinfo = self.curl_easy_getinfo.get()?;
+		let mut value: *const c_char = std::ptr::null();
+		let code = curl_easy_getinfo(handle, info, &mut value);
+		if code != curl_sys::CURLE_OK {
+			return Err(format!("failed to get info {:#x}: error {}", info, code));
+		}
+		// SAFETY: curl_easy_getinfo() returned a null-terminated string owned by the handle or libcurl, or null.
+		Ok((!value.is_null()).then(|| unsafe { CStr::from_ptr(value) }.to_owned()))
+	}
+
+	/// Get a `long` from `curl_easy_getinfo()`.
+	pub fn info_long(&self, handle: *mut CURL, info: CURLINFO) -> Result<c_long, String> {
+		let curl_easy_getinfo = self.curl_easy_getinfo.get()?;
+		let mut value: c_long = 0;
+		let code = curl_easy_getinfo(handle, info, &mut value);
+		if code != curl_sys::CURLE_OK {
+			return Err(format!("failed to get info {:#x}: error {}", info, code));
+		}
+		Ok(value)
+	}
+
+	/// Get a `curl_off_t` from `curl_easy_getinfo()`.
+	pub fn info_off_t(&self, handle: *mut CURL, info: CURLINFO) -> Result<curl_off_t, String> {
+		let curl_easy_getinfo = self.curl_easy_getinfo.get()?;
+		let mut value: curl_off_t = 0;
+		let code = curl_easy_getinfo(handle, info, &mut value);
+		if code != curl_sys::CURLE_OK {
+			return Err(format!("failed to get info {:#x}: error {}", info, code));
+		}
+		Ok(value)
+	}
+
+	/// Get the URL that a handle is currently using, which may be the target of a redirect.
+	pub fn effective_url(&self, handle: *mut CURL) -> Result<CString, String> {
+		self.info_string(handle, curl_sys::CURLINFO_EFFECTIVE_URL)?.ok_or_else(|| String::from("failed to get effective URL"))
+	}
+
+	/// Get the full URL from a CURLU handle.
+	pub fn url_from_curlu(&self, curlu: *mut CURLU) -> Result<CString, String> {
+		let curl_url_get = self.curl_url_get.get()?;
+		let curl_free = self.curl_free.get()?;
+		let mut url = std::ptr::null_mut();
+		let code = curl_url_get(curlu, CURLUPART_URL, &mut url, 0);
+		if code != CURLUE_OK || url.is_null() {
+			return Err(format!("failed to get URL from CURLU handle: error {}", code));
+		}
+		// SAFETY: curl_url_get() returned a null-terminated string that we must free with curl_free().
+		let result = unsafe { CStr::from_ptr(url) }.to_owned();
+		curl_free(url.cast());
+		Ok(result)
+	}
+
+	/// Resolve a URL relative to a base URL, like libcurl does for the target of a redirect.
+	pub fn join_url(&self, base: &CStr, url: &CStr) -> Result<CString, String> {
+		self.parse_url(&[(base, 0), (url, CURLU_URLENCODE | CURLU_ALLOW_SPACE)])
+			.map_err(|code| format!("failed to resolve URL {} relative to {}: error {}", url.to_string_lossy(), base.to_string_lossy(), code))
+	}
+
+	/// Normalize a URL like libcurl does before a transfer, so equal URLs compare equal.
+	pub fn normalize_url(&self, url: &CStr) -> Result<CString, String> {
+		self.parse_url(&[(url, CURLU_GUESS_SCHEME | CURLU_NON_SUPPORT_SCHEME)])
+			.map_err(|code| format!("failed to parse URL {}: error {}", url.to_string_lossy(), code))
+	}
+
+	/// Set URLs one after another on a new CURLU handle with the given flags, and get the resulting URL.
+	fn parse_url(&self, urls: &[(&CStr, c_uint)]) -> Result<CString, String> {
+		let curl_url = self.curl_url.get()?;
+		let curl_url_set = self.curl_url_set.get()?;
+		let curl_url_cleanup = self.curl_url_cleanup.get()?;
+		let curlu = curl_url();
+		if curlu.is_null() {
+			return Err(String::from("failed to create CURLU handle"));
+		}
+		let code = urls.iter()
+			.map(|(url, flags)| curl_url_set(curlu, CURLUPART_URL, url.as_ptr(), *flags))
+			.find(|&code| code != CURLUE_OK);
+		let result = match code {
+			None       => self.url_from_curlu(curlu),
+			Some(code) => Err(code.to_string()),
+		};
+		curl_url_cleanup(curlu);
+		result
+	}
+}
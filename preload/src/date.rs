@@ -0,0 +1,79 @@
+use std::time::SystemTime;
+
+/// A point in time in UTC, split in calendar fields.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Date {
+	year: i64,
+	month: u32,
+	day: u32,
+	hour: u32,
+	minute: u32,
+	second: u32,
+
+	/// The day of the week, where 0 is Sunday.
+	weekday: u32,
+}
+
+impl Date {
+	/// Get the current time.
+	pub fn now() -> Self {
+		let seconds = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |x| x.as_secs());
+		Self::from_unix(seconds as i64)
+	}
+
+	/// Convert seconds since the Unix epoch to calendar fields.
+	pub fn from_unix(seconds: i64) -> Self {
+		let days = seconds.div_euclid(86_400);
+		let time = seconds.rem_euclid(86_400) as u32;
+
+		// Convert days to a civil date, from http://howardhinnant.github.io/date_algorithms.html#civil_from_days.
+		let z = days + 719_468;
+		let era = z.div_euclid(146_097);
+		let day_of_era = z.rem_euclid(146_097);
+		let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+		let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+		let mp = (5 * day_of_year + 2) / 153;
+		let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+		let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+		let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+		Self {
+			year,
+			month,
+			day,
+			hour: time / 3600,
+			minute: time / 60 % 60,
+			second: time % 60,
+			weekday: (days + 4).rem_euclid(7) as u32,
+		}
+	}
+
+	/// Format the date as RFC 3339 timestamp, like `2026-10-19T12:00:00Z`.
+	pub fn rfc3339(&self) -> String {
+		format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", self.year, self.month, self.day, self.hour, self.minute, self.second)
+	}
+
+	/// Format the date as HTTP date, like `Mon, 19 Oct 2026 12:00:00 GMT`.
+	pub fn http(&self) -> String {
+		const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+		const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+		format!(
+			"{}, {:02} {} {:04} {:02}:{:02}:{:02} GMT",
+			WEEKDAYS[self.weekday as usize], self.day, MONTHS[self.month as usize - 1], self.year, self.hour, self.minute, self.second,
+		)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_format() {
+		assert_eq!(Date::from_unix(0).rfc3339(), "1970-01-01T00:00:00Z");
+		assert_eq!(Date::from_unix(0).http(), "Thu, 01 Jan 1970 00:00:00 GMT");
+		assert_eq!(Date::from_unix(1_792_411_200).rfc3339(), "2026-10-19T12:00:00Z");
+		assert_eq!(Date::from_unix(1_792_411_200).http(), "Mon, 19 Oct 2026 12:00:00 GMT");
+		assert_eq!(Date::from_unix(951_825_599).rfc3339(), "2000-02-29T11:59:59Z");
+	}
+}
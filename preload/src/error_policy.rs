@@ -0,0 +1,72 @@
+use std::io::Write;
+use std::sync::LazyLock;
+
+/// What to do when something goes wrong while injecting options.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ErrorPolicy {
+	/// Print the error and abort the process.
+	Abort,
+
+	/// Print the error and continue.
+	Warn,
+
+	/// Silently continue.
+	Ignore,
+}
+
+/// The error policy from the CURL_INJECT_OPT_ON_ERROR environment variable.
+pub static ON_ERROR: LazyLock<ErrorPolicy> = LazyLock::new(ErrorPolicy::from_env);
+
+impl ErrorPolicy {
+	/// Parse an error policy from a string.
+	pub fn parse(value: &str) -> Result<Self, String> {
+		if value.eq_ignore_ascii_case("abort") {
+			Ok(Self::Abort)
+		} else if value.eq_ignore_ascii_case("warn") {
+			Ok(Self::Warn)
+		} else if value.eq_ignore_ascii_case("ignore") {
+			Ok(Self::Ignore)
+		} else {
+			Err(format!("invalid error policy: {}, expected abort, warn or ignore", value))
+		}
+	}
+
+	/// Get the error policy from the CURL_INJECT_OPT_ON_ERROR environment variable.
+	///
+	/// If the variable is not set or invalid, the policy is `Warn`.
+	fn from_env() -> Self {
+		let value = match std::env::var("CURL_INJECT_OPT_ON_ERROR") {
+			Ok(x)  => x,
+			Err(_) => return Self::Warn,
+		};
+		match Self::parse(&value) {
+			Ok(x)  => x,
+			Err(e) => {
+				let _ = writeln!(std::io::stderr(), "curl-inject-opt: {}", e);
+				Self::Warn
+			},
+		}
+	}
+
+	/// Report an error according to the policy.
+	///
+	/// This never panics, so it is safe to use from code called directly by C.
+	pub fn report(self, message: impl std::fmt::Display) {
+		match self {
+			Self::Abort => {
+				let _ = writeln!(std::io::stderr(), "curl-inject-opt: {}", message);
+				let _ = writeln!(std::io::stderr(), "curl-inject-opt: aborting because CURL_INJECT_OPT_ON_ERROR is set to abort");
+				std::process::abort();
+			},
+			Self::Warn => {
+				let _ = writeln!(std::io::stderr(), "curl-inject-opt: {}", message);
+			},
+			Self::Ignore => (),
+		}
+	}
+}
+
+/// Report an error according to the policy from the environment.
+pub fn report(message: impl std::fmt::Display) {
+	ON_ERROR.report(message)
+}
@@ -0,0 +1,344 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+
+use std::ffi::CString;
+use std::fs::File;
+
+use curl_inject_opt_shared::reexports::curl_sys::CURL;
+use curl_inject_opt_shared::reexports::curl_sys::CURLcode;
+use curl_inject_opt_shared::reexports::curl_sys::CURLoption;
+
+use crate::capture::CapturedTransfer;
+use crate::curl::Library;
+use crate::har::Recording;
+use crate::lock_ignore_poison;
+use crate::otel::Span;
+use crate::replay::Replayed;
+use crate::setopt::{Function, Saved, Slist};
+use crate::tls;
+
+/// State tracked for a single CURL easy handle.
+#[derive(Clone, Default)]
+pub struct HandleState {
+	/// True if the injected options have been set on the handle since it was created or last reset.
+	pub applied: bool,
+
+	/// The URL set by the application with CURLOPT_URL.
+	pub url: Option<CString>,
+
+	/// The address of the CURLU handle set by the application with CURLOPT_CURLU, or 0.
+	///
+	/// If set, it takes precedence over CURLOPT_URL.
+	pub curlu: usize,
+
+	/// True if the URL of the handle was rewritten for the last transfer.
+	pub rewritten: bool,
+
+	/// The libcurl flavour that the last transfer of the handle was started with.
+	pub library: Option<&'static Library>,
+
+	/// The CURLOPT_PREREQFUNCTION callback set by the application, which our own callback forwards to.
+	pub prereq: Option<Function>,
+
+	/// The CURLOPT_PREREQDATA pointer set by the application.
+	pub prereq_data: usize,
+
+	/// The CURLOPT_WRITEFUNCTION callback set by the application, or `None` for the default `fwrite()`.
+	pub write: Option<Function>,
+
+	/// The CURLOPT_WRITEDATA pointer set by the application, or 0 for the default `stdout`.
+	pub write_data: usize,
+
+	/// The CURLOPT_READFUNCTION callback set by the application, or `None` for the default `fread()`.
+	pub read: Option<Function>,
+
+	/// The CURLOPT_READDATA pointer set by the application, or 0 for the default `stdin`.
+	pub read_data: usize,
+
+	/// The CURLOPT_HEADERFUNCTION callback set by the application.
+	pub header: Option<Function>,
+
+	/// The CURLOPT_HEADERDATA pointer set by the application.
+	pub header_data: usize,
+
+	/// The CURLOPT_DEBUGFUNCTION callback set by the application.
+	pub debug: Option<Function>,
+
+	/// True if the application turned on CURLOPT_VERBOSE.
+	pub verbose: bool,
+
+	/// The CURLOPT_STDERR file set by the application, or 0 for the default `stderr`.
+	pub stderr: usize,
+
+	/// Where verbose output of the current transfer goes if it is traced, or `None` if the transfer is not verbose.
+	///
+	/// This is the `FILE *` for the default output of libcurl, or 0 for `stderr`, and is ignored if the application set a debug callback.
+	pub verbose_output: Option<usize>,
+
+	/// The trace file of the handle, once it was opened.
+	pub trace: Option<Arc<File>>,
+
+	/// The bodies of the current transfer of the handle, if they are captured.
+	pub captured: Option<Arc<CapturedTransfer>>,
+
+	/// The headers and bodies of the current transfer of the handle, if it is recorded in the HAR file.
+	pub har: Option<Arc<Recording>>,
+
+	/// The status line and headers of the response that is being received, for the header log.
+	pub response_headers: String,
+
+	/// The last transfer of the handle, if it was answered with a recorded response instead of running it.
+	pub replayed: Option<Arc<Replayed>>,
+
+	/// The error code for the transfer, if a policy denied it.
+	pub denied: Option<CURLcode>,
+
+	/// The CURLOPT_HTTPHEADER list set by the application, or 0.
+	pub httpheader: usize,
+
+	/// Our modified copy of the CURLOPT_HTTPHEADER list, if header rules applied to the last transfer.
+	///
+	/// It is kept alive for as long as libcurl may use it.
+	pub headers: Option<Arc<Slist>>,
+
+	/// The names of the options with credentials that the application set.
+	pub credentials: BTreeSet<&'static str>,
+
+	/// True if the application restricted the allowed protocols with CURLOPT_PROTOCOLS or CURLOPT_PROTOCOLS_STR.
+	pub protocols_restricted: bool,
+
+	/// Insecure TLS settings of the application, with the name of the option, for the audit report.
+	pub insecure: HashMap<CURLoption, (&'static str, tls::Check)>,
+
+	/// True if the application turned on CURLOPT_FOLLOWLOCATION.
+	pub follow_location: bool,
+
+	/// True if the current transfer follows redirects, because the application or an injected option turned on CURLOPT_FOLLOWLOCATION.
+	pub follows_redirects: bool,
+
+	/// The Location header of the response that is being received, if it has one.
+	pub location: Option<CString>,
+
+	/// True if the current transfer of the handle started with an HTTPS URL.
+	pub https_transfer: bool,
+
+	/// The CA bundle file set by the application with CURLOPT_CAINFO.
+	pub cainfo: Option<CString>,
+
+	/// The CA bundle set by the application with CURLOPT_CAINFO_BLOB.
+	pub cainfo_blob: Option<Vec<u8>>,
+
+	/// True while `curl_easy_perform()` runs for the handle.
+	///
+	/// libcurl calls `curl_multi_add_handle()` and `curl_multi_info_read()` internally,
+	/// which must not set the options or report the transfer a second time.
+	pub performing: bool,
+
+	/// The method and body of the request, if requests are signed or captured.
+	pub request: Request,
+
+	/// The span of the current transfer of the handle, if tracing is enabled.
+	pub span: Option<Span>,
+
+	/// Values set by the application for options that rule blocks may override or that are merged with ours.
+	pub saved: HashMap<CURLoption, Saved>,
+
+	/// Merged lists that are currently set on the handle, kept alive for as long as libcurl may use them.
+	pub merged: HashMap<CURLoption, Arc<Slist>>,
+
+	/// Options that were set by a rule block for the last transfer.
+	pub scoped: Vec<CURLoption>,
+}
+
+impl HandleState {
+	/// Copy the state for a handle made by `curl_easy_duphandle()`.
+	///
+	/// libcurl copies the options of the handle, so the values saved for them and the lists it points to are kept,
+	/// but nothing of the current transfer, which belongs to the source handle.
+	pub fn duplicate(&self) -> Self {
+		Self {
+			verbose_output: None,
+			trace: None,
+			captured: None,
+			har: None,
+			response_headers: String::new(),
+			replayed: None,
+			denied: None,
+			follows_redirects: false,
+			location: None,
+			https_transfer: false,
+			performing: false,
+			span: None,
+			..self.clone()
+		}
+	}
+}
+
+/// The method and body that the application set for the requests of a handle.
+#[derive(Clone, Default)]
+pub struct Request {
+	/// The kind of request, which decides the method libcurl uses.
+	pub kind: RequestKind,
+
+	/// The method set with CURLOPT_CUSTOMREQUEST, which replaces the method of the kind.
+	pub custom_method: Option<CString>,
+
+	/// The body set with CURLOPT_POSTFIELDS or CURLOPT_COPYPOSTFIELDS.
+	pub fields: Option<PostFields>,
+
+	/// The size set with CURLOPT_POSTFIELDSIZE or CURLOPT_POSTFIELDSIZE_LARGE, or `None` if the body is a null-terminated string.
+	pub fields_size: Option<usize>,
+}
+
+/// The kind of request, following the options that libcurl uses to decide on the method.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum RequestKind {
+	#[default]
+	Get,
+	Head,
+	Post,
+	PostForm,
+	Put,
+}
+
+/// The body of a POST request.
+#[derive(Clone)]
+pub enum PostFields {
+	/// The address of the data set with CURLOPT_POSTFIELDS, which the application keeps alive during the transfer.
+	Pointer(usize),
+
+	/// A copy of the data set with CURLOPT_COPYPOSTFIELDS.
+	Copy(Arc<Vec<u8>>),
+}
+
+impl Request {
+	/// Get the method that libcurl will use for an HTTP request.
+	pub fn method(&self) -> String {
+		if let Some(method) = &self.custom_method {
+			return method.to_string_lossy().into_owned();
+		}
+		let method = match self.kind {
+			RequestKind::Get                          => "GET",
+			RequestKind::Head                         => "HEAD",
+			RequestKind::Post | RequestKind::PostForm => "POST",
+			RequestKind::Put                          => "PUT",
+		};
+		method.to_string()
+	}
+}
+
+/// Table of per-handle state, keyed by the address of the handle.
+///
+/// Raw pointers are not `Send`, so the handles are stored as `usize`.
+#[derive(Default)]
+pub struct HandleTable {
+	handles: Mutex<HashMap<usize, HandleState>>,
+}
+
+impl HandleTable {
+	/// Register a newly created handle with fresh state.
+	pub fn insert(&self, handle: *mut CURL) {
+		if handle.is_null() {
+			return;
+		}
+		lock_ignore_poison(&self.handles).insert(handle as usize, HandleState::default());
+	}
+
+	/// Register a duplicated handle, copying the state of the source handle.
+	pub fn duplicate(&self, source: *mut CURL, handle: *mut CURL) {
+		if handle.is_null() {
+			return;
+		}
+		let mut handles = lock_ignore_poison(&self.handles);
+		let state = handles.get(&(source as usize)).map(HandleState::duplicate).unwrap_or_default();
+		handles.insert(handle as usize, state);
+	}
+
+	/// Reset the state of a handle, as done by `curl_easy_reset()`.
+	pub fn reset(&self, handle: *mut CURL) {
+		self.insert(handle)
+	}
+
+	/// Forget about a handle, as done by `curl_easy_cleanup()`.
+	pub fn remove(&self, handle: *mut CURL) -> Option<HandleState> {
+		lock_ignore_poison(&self.handles).remove(&(handle as usize))
+	}
+
+	/// Mark the injected options as set on a handle, and return true if they were set already since it was created or last reset.
+	pub fn mark_applied(&self, handle: *mut CURL) -> bool {
+		self.with(handle, |state| std::mem::replace(&mut state.applied, true))
+	}
+
+	/// Run a function with mutable access to the state of a handle.
+	///
+	/// Handles that were never registered (for example because they were created before the library was loaded) get fresh state.
+	pub fn with<R>(&self, handle: *mut CURL, fun: impl FnOnce(&mut HandleState) -> R) -> R {
+		let mut handles = lock_ignore_poison(&self.handles);
+		fun(handles.entry(handle as usize).or_default())
+	}
+
+	/// Run a function with mutable access to the state of a handle, if the handle is known.
+	///
+	/// Callbacks use this, so a callback for a handle that is being cleaned up never brings back its state.
+	pub fn with_existing<R>(&self, handle: *mut CURL, fun: impl FnOnce(&mut HandleState) -> R) -> Option<R> {
+		lock_ignore_poison(&self.handles).get_mut(&(handle as usize)).map(fun)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use curl_inject_opt_shared::reexports::curl_sys;
+	use crate::testing::fake_handle;
+
+	#[test]
+	fn test_lifecycle() {
+		let table = HandleTable::default();
+		let handle = fake_handle(1);
+		let copy = fake_handle(2);
+
+		table.insert(handle);
+		table.with(handle, |state| state.url = Some(CString::from(c"http://example.com/")));
+		assert!(!table.mark_applied(handle));
+		assert!(table.mark_applied(handle));
+
+		// A duplicated handle keeps the options of the source handle, so they are not set again, but not its current transfer.
+		table.with(handle, |state| {
+			state.performing = true;
+			state.denied = Some(curl_sys::CURLE_COULDNT_CONNECT);
+			state.response_headers = String::from("HTTP/1.1 200 OK\r\n");
+		});
+		table.duplicate(handle, copy);
+		assert!(table.with(copy, |state| state.url.clone()) == Some(CString::from(c"http://example.com/")));
+		assert!(table.with(copy, |state| !state.performing && state.denied.is_none() && state.response_headers.is_empty()));
+		assert!(table.mark_applied(copy));
+
+		// A reset handle has lost all options.
+		table.reset(handle);
+		assert!(table.with(handle, |state| state.url.is_none()));
+		assert!(!table.mark_applied(handle));
+
+		// The duplicate is independent of the source handle.
+		assert!(table.remove(handle).is_some_and(|state| state.applied));
+		assert!(table.remove(handle).is_none());
+		assert!(table.with(copy, |state| state.applied));
+
+		// Callbacks for a removed handle do not bring back its state.
+		assert!(table.with_existing(handle, |state| state.applied).is_none());
+		assert!(table.remove(handle).is_none());
+		assert!(table.with_existing(copy, |state| state.applied) == Some(true));
+
+		// Null handles from failed calls are not registered.
+		table.insert(std::ptr::null_mut());
+		table.duplicate(copy, std::ptr::null_mut());
+		assert!(table.remove(std::ptr::null_mut()).is_none());
+	}
+
+	#[test]
+	fn test_duplicate_unknown_handle() {
+		let table = HandleTable::default();
+		let handle = fake_handle(1);
+		table.duplicate(fake_handle(2), handle);
+		assert!(!table.mark_applied(handle));
+	}
+}
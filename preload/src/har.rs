@@ -0,0 +1,441 @@
+use std::fs::File;
+use std::io::Read;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use curl_inject_opt_shared::headers::has_name;
+
+use crate::base64;
+use crate::date::Date;
+use crate::json;
+use crate::lock_ignore_poison;
+use crate::request_log::Transfer;
+
+/// The number of bytes of each body that is recorded if CURL_INJECT_OPT_HAR_MAX_BODY is not set.
+const DEFAULT_MAX_BODY: u64 = 1024 * 1024;
+
+/// Headers whose value is replaced by `REDACTED` in the archive.
+const REDACTED_HEADERS: [&str; 4] = ["Authorization", "Proxy-Authorization", "Cookie", "Set-Cookie"];
+
+/// The start of the archive up to the entries, which are written one per line.
+const HEADER: &str = concat!(r#"{"log":{"version":"1.2","creator":{"name":"curl-inject-opt","version":""#, env!("CARGO_PKG_VERSION"), r#""},"entries":["#);
+
+/// The end of the archive after the entries.
+const FOOTER: &str = "]}}";
+
+/// Records all transfers of the process in an HTTP Archive (HAR 1.2) file, adding each transfer when it finishes.
+#[derive(Default)]
+pub struct Har {
+	/// The archive file, or `None` if recording is disabled.
+	path: Option<PathBuf>,
+
+	/// The maximum number of bytes to record of each body.
+	max_body: u64,
+}
+
+impl Har {
+	/// Read the path of the archive from CURL_INJECT_OPT_HAR, and the body size limit from CURL_INJECT_OPT_HAR_MAX_BODY.
+	pub fn from_env() -> Result<Self, String> {
+		let Some(path) = std::env::var_os("CURL_INJECT_OPT_HAR").filter(|x| !x.is_empty()) else {
+			return Ok(Self::default());
+		};
+		let max_body = match std::env::var("CURL_INJECT_OPT_HAR_MAX_BODY") {
+			Ok(x) if !x.is_empty() => x.parse::<u64>().map_err(|_| format!("invalid HAR body size limit: {}", x))?,
+			_                      => DEFAULT_MAX_BODY,
+		};
+		Ok(Self::new(PathBuf::from(path), max_body))
+	}
+
+	/// Record transfers in the archive at `path`, with at most `max_body` bytes of each body.
+	pub fn new(path: PathBuf, max_body: u64) -> Self {
+		Self { path: Some(path), max_body }
+	}
+
+	/// Check if transfers are recorded.
+	pub fn is_enabled(&self) -> bool {
+		self.path.is_some()
+	}
+
+	/// Start recording a new transfer.
+	pub fn start(&self) -> Option<Recording> {
+		self.path.as_ref()?;
+		Some(Recording {
+			started: SystemTime::now(),
+			state: Mutex::new(RecordingState {
+				request_headers: Vec::new(),
+				response_headers: Vec::new(),
+				request: Body::default(),
+				response: Body::default(),
+			}),
+			max_body: self.max_body,
+		})
+	}
+
+	/// Add a finished transfer to the archive file.
+	///
+	/// The entry is written right away, so it is not lost when the process ends without running `atexit()` handlers, like on `exec()` or a signal.
+	pub fn record(&self, recording: &Recording, transfer: &Transfer) -> Result<(), String> {
+		let Some(path) = &self.path else {
+			return Ok(());
+		};
+		append(path, recording.to_entry(transfer))
+	}
+}
+
+/// Add an entry to the archive file.
+///
+/// The file is locked while it is updated, so processes that share it add their entries one after another.
+/// The new archive is written to a temporary file that replaces the old one, so a process that dies while writing leaves the old archive intact.
+fn append(path: &Path, entry: String) -> Result<(), String> {
+	let error = |e: std::io::Error| format!("failed to write HAR file {}: {}", path.display(), e);
+	let mut file = lock(path).map_err(error)?;
+	let mut existing = String::new();
+	file.read_to_string(&mut existing).map_err(error)?;
+	let Some(archive) = merge(&existing, &[entry]) else {
+		return Err(format!("failed to write HAR file {}: the file exists but was not written by curl-inject-opt", path.display()));
+	};
+
+	let mut temporary = path.as_os_str().to_owned();
+	temporary.push(format!(".{}.tmp", std::process::id()));
+	std::fs::write(&temporary, archive).map_err(|e| format!("failed to write HAR file {}: {}", temporary.to_string_lossy(), e))?;
+	std::fs::rename(&temporary, path).map_err(|e| {
+		let _ = std::fs::remove_file(&temporary);
+		error(e)
+	})
+}
+
+/// Open the archive file and lock it, creating it if it does not exist.
+///
+/// Other processes replace the file while they hold the lock, so the file is opened again if it was replaced while we waited for the lock.
+fn lock(path: &Path) -> std::io::Result<File> {
+	loop {
+		let file = std::fs::OpenOptions::new()
+			.read(true)
+			.write(true)
+			.create(true)
+			.truncate(false)
+			.open(path)?;
+		// SAFETY: The file descriptor is valid, and the lock is released when the file is closed.
+		if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+			return Err(std::io::Error::last_os_error());
+		}
+		let locked = file.metadata()?;
+		match std::fs::metadata(path) {
+			Ok(current) if (current.dev(), current.ino()) == (locked.dev(), locked.ino()) => return Ok(file),
+			Ok(_)                                                                        => continue,
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound                          => continue,
+			Err(e)                                                                       => return Err(e),
+		}
+	}
+}
+
+/// Add entries to an archive written by us, or to an empty file.
+///
+/// Returns `None` if the existing file is not an archive in the layout that we write.
+fn merge(existing: &str, entries: &[String]) -> Option<String> {
+	let existing = existing.trim_end();
+	let mut archive = match existing.is_empty() {
+		true  => String::from(HEADER),
+		false => {
+			if !existing.starts_with(r#"{"log":{"version":"1.2","creator":{"name":"curl-inject-opt""#) {
+				return None;
+			}
+			let head = existing.strip_suffix(FOOTER)?.trim_end();
+			let mut archive = String::from(head);
+			if !head.ends_with('[') {
+				archive.push(',');
+			}
+			archive
+		},
+	};
+	archive.push('\n');
+	archive.push_str(&entries.join(",\n"));
+	archive.push('\n');
+	archive.push_str(FOOTER);
+	archive.push('\n');
+	Some(archive)
+}
+
+/// The headers and bodies of one transfer, as they pass through the callbacks.
+pub struct Recording {
+	/// When the transfer started.
+	started: SystemTime,
+
+	/// The headers and bodies seen so far.
+	state: Mutex<RecordingState>,
+
+	/// The maximum number of bytes to record of each body.
+	max_body: u64,
+}
+
+struct RecordingState {
+	/// The request headers of the last request, as sent by libcurl.
+	request_headers: Vec<u8>,
+
+	/// The status line and header lines of the last response.
+	response_headers: Vec<Vec<u8>>,
+
+	/// The request body.
+	request: Body,
+
+	/// The response body.
+	response: Body,
+}
+
+/// A recorded body.
+#[derive(Default)]
+struct Body {
+	/// The recorded data, up to the size limit.
+	data: Vec<u8>,
+
+	/// The size of the body, including the part that was not recorded.
+	size: u64,
+}
+
+impl Body {
+	fn write(&mut self, data: &[u8], max_size: u64) {
+		self.size += data.len() as u64;
+		let room = usize::try_from(max_size.saturating_sub(self.data.len() as u64)).unwrap_or(usize::MAX);
+		self.data.extend_from_slice(&data[..data.len().min(room)]);
+	}
+}
+
+impl Recording {
+	/// Add request headers from the debug callback.
+	///
+	/// The headers of a new request, such as after a redirect, replace those of the previous request.
+	pub fn request_header(&self, data: &[u8]) {
+		let mut state = lock_ignore_poison(&self.state);
+		if state.request_headers.ends_with(b"\n\r\n") || state.request_headers.ends_with(b"\n\n") {
+			state.request_headers.clear();
+		}
+		state.request_headers.extend_from_slice(data);
+	}
+
+	/// Add a line from the header callback to the response headers.
+	///
+	/// A status line starts a new response, such as after a redirect or a `100 Continue`.
+	pub fn response_header(&self, line: &[u8]) {
+		let mut state = lock_ignore_poison(&self.state);
+		if line.starts_with(b"HTTP/") {
+			state.response_headers.clear();
+		}
+		state.response_headers.push(line.to_vec());
+	}
+
+	/// Append data to the request body.
+	pub fn request(&self, data: &[u8]) {
+		let max_body = self.max_body;
+		lock_ignore_poison(&self.state).request.write(data, max_body);
+	}
+
+	/// Append data to the response body.
+	pub fn response(&self, data: &[u8]) {
+		let max_body = self.max_body;
+		lock_ignore_poison(&self.state).response.write(data, max_body);
+	}
+
+	/// Encode the transfer as a HAR entry.
+	fn to_entry(&self, transfer: &Transfer) -> String {
+		let state = lock_ignore_poison(&self.state);
+		let started = self.started.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+		let started = format!("{}.{:03}Z", Date::from_unix(started.as_secs() as i64).rfc3339().trim_end_matches('Z'), started.subsec_millis());
+		let url = transfer.url.as_deref().unwrap_or("");
+
+		let mut request_lines = split_lines(&state.request_headers);
+		let request_line = request_lines.next().unwrap_or_default();
+		let request_headers: Vec<_> = request_lines.filter_map(parse_header).collect();
+		let request_version = request_line.rsplit(' ').next().filter(|x| x.starts_with("HTTP/")).unwrap_or("");
+		let method = transfer.method.as_deref().or_else(|| request_line.split(' ').next()).unwrap_or("");
+
+		let response_lines: Vec<_> = state.response_headers.iter().map(|x| String::from_utf8_lossy(x).trim_end().to_string()).collect();
+		let status_line = response_lines.first().map_or("", |x| x.as_str());
+		let response_headers: Vec<_> = response_lines.iter().skip(1).filter_map(parse_header).collect();
+		let mut status_parts = status_line.splitn(3, ' ');
+		let response_version = status_parts.next().unwrap_or("");
+		let status_text = status_parts.nth(1).unwrap_or("");
+		let redirect_url = response_headers.iter().find(|(name, _)| name.eq_ignore_ascii_case("Location")).map_or("", |(_, value)| value);
+
+		let query = url.split('#').next().unwrap_or("").split_once('?').map_or("", |(_, x)| x);
+		let query: Vec<_> = query.split('&').filter(|x| !x.is_empty()).map(|x| {
+			let (name, value) = x.split_once('=').unwrap_or((x, ""));
+			json::Object::new().string("name", name).string("value", value).finish()
+		}).collect();
+
+		let mut request = json::Object::new();
+		request
+			.string("method", method)
+			.string("url", url)
+			.string("httpVersion", request_version)
+			.raw("cookies", "[]")
+			.raw("headers", &encode_headers(&request_headers))
+			.raw("queryString", &json::array(query))
+			.number("headersSize", Some(state.request_headers.len()))
+			.number("bodySize", Some(state.request.size));
+		if state.request.size > 0 {
+			let mime_type = request_headers.iter().find(|(name, _)| name.eq_ignore_ascii_case("Content-Type")).map_or("", |(_, value)| value);
+			let mut post_data = json::Object::new();
+			post_data.string("mimeType", mime_type);
+			encode_body(&mut post_data, &state.request);
+			request.raw("postData", &post_data.finish());
+		}
+
+		let mut content = json::Object::new();
+		content
+			.number("size", Some(state.response.size))
+			.string("mimeType", transfer.content_type.as_deref().unwrap_or(""));
+		encode_body(&mut content, &state.response);
+		let response_headers_size = state.response_headers.iter().map(|x| x.len()).sum::<usize>();
+		let response = json::Object::new()
+			.number("status", Some(transfer.response_code.unwrap_or(0)))
+			.string("statusText", status_text)
+			.string("httpVersion", response_version)
+			.raw("cookies", "[]")
+			.raw("headers", &encode_headers(&response_headers))
+			.raw("content", &content.finish())
+			.string("redirectURL", redirect_url)
+			.number("headersSize", Some(response_headers_size))
+			.number("bodySize", Some(transfer.bytes_down.unwrap_or(state.response.size as i64)))
+			.finish();
+
+		let mut entry = json::Object::new();
+		entry
+			.string("startedDateTime", &started)
+			.number("time", Some(millis(transfer.total.unwrap_or(0))))
+			.raw("request", &request.finish())
+			.raw("response", &response)
+			.raw("cache", "{}")
+			.raw("timings", &timings(transfer));
+		if let Some(remote_ip) = &transfer.remote_ip {
+			entry.string("serverIPAddress", remote_ip);
+		}
+		entry
+			.number("_pid", Some(std::process::id()))
+			.number("_result", Some(transfer.result))
+			.finish()
+	}
+}
+
+/// Split raw header data in lines, without line endings and without the empty line at the end.
+fn split_lines(data: &[u8]) -> impl Iterator<Item = String> + '_ {
+	data.split(|x| *x == b'\n')
+		.map(|x| String::from_utf8_lossy(x.strip_suffix(b"\r").unwrap_or(x)).into_owned())
+		.filter(|x| !x.is_empty())
+}
+
+/// Split a header line in name and value, with the value of credential headers redacted.
+fn parse_header(line: impl AsRef<str>) -> Option<(String, String)> {
+	let (name, value) = line.as_ref().split_once(':')?;
+	let value = match REDACTED_HEADERS.iter().any(|x| has_name(line.as_ref().as_bytes(), x)) {
+		true  => "REDACTED",
+		false => value.trim(),
+	};
+	Some((name.to_string(), value.to_string()))
+}
+
+/// Encode headers as a HAR list of name and value objects.
+fn encode_headers(headers: &[(String, String)]) -> String {
+	json::array(headers.iter().map(|(name, value)| json::Object::new().string("name", name).string("value", value).finish()))
+}
+
+/// Add the recorded part of a body to a HAR `content` or `postData` object, as text or as base64 if it is not valid UTF-8.
+fn encode_body(object: &mut json::Object, body: &Body) {
+	match std::str::from_utf8(&body.data) {
+		Ok(text) => object.string("text", text),
+		Err(_)   => object.string("text", &base64::encode(&body.data)).string("encoding", "base64"),
+	};
+	if body.size > body.data.len() as u64 {
+		object.string("comment", &format!("truncated to {} of {} bytes", body.data.len(), body.size));
+	}
+}
+
+/// Encode the timings of a transfer as a HAR `timings` object.
+///
+/// libcurl measures each phase from the start of the transfer, so the phases are the differences between them.
+/// The TLS handshake is part of the connect time, like HAR wants, and the phases add up to the total time.
+fn timings(transfer: &Transfer) -> String {
+	let name_lookup = transfer.name_lookup.unwrap_or(0);
+	let app_connect = transfer.app_connect.filter(|x| *x > 0);
+	let connect = transfer.connect.filter(|x| *x > 0);
+	let connected = app_connect.or(connect).unwrap_or(name_lookup);
+	let pre_transfer = transfer.pre_transfer.unwrap_or(connected).max(connected);
+	let start_transfer = transfer.start_transfer.unwrap_or(pre_transfer).max(pre_transfer);
+	let total = transfer.total.unwrap_or(start_transfer).max(start_transfer);
+	json::Object::new()
+		.number("blocked", Some(-1))
+		.number("dns", Some(transfer.name_lookup.map_or(String::from("-1"), millis)))
+		.number("connect", Some(connect.map_or(String::from("-1"), |_| millis(connected - name_lookup))))
+		.number("ssl", Some(app_connect.zip(connect).map_or(String::from("-1"), |(app_connect, connect)| millis(app_connect - connect))))
+		.number("send", Some(millis(pre_transfer - connected)))
+		.number("wait", Some(millis(start_transfer - pre_transfer)))
+		.number("receive", Some(millis(total - start_transfer)))
+		.finish()
+}
+
+/// Format a duration in microseconds as milliseconds, without losing precision to floating point.
+fn millis(microseconds: i64) -> String {
+	let microseconds = microseconds.max(0);
+	format!("{}.{:03}", microseconds / 1000, microseconds % 1000)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::testing::TempPath;
+
+	#[test]
+	fn test_entry() {
+		let har = Har::new(PathBuf::from("test.har"), 4);
+		let recording = har.start().unwrap();
+		recording.request_header(b"POST /old HTTP/1.1\r\nHost: example.com\r\n\r\n");
+		recording.request_header(b"POST /items?a=1&b HTTP/1.1\r\nHost: example.com\r\nAuthorization: Bearer secret\r\n");
+		recording.request_header(b"Content-Type: text/plain\r\n\r\n");
+		recording.request(b"hi");
+		recording.response_header(b"HTTP/1.1 100 Continue\r\n");
+		recording.response_header(b"HTTP/1.1 201 Created\r\n");
+		recording.response_header(b"Location: /items/1\r\n");
+		recording.response_header(b"\r\n");
+		recording.response(b"\xff\x00\x01\x02\x03");
+		let transfer = Transfer {
+			url: Some(String::from("http://example.com/items?a=1&b")),
+			method: Some(String::from("POST")),
+			response_code: Some(201),
+			name_lookup: Some(1_000),
+			connect: Some(3_000),
+			pre_transfer: Some(3_500),
+			start_transfer: Some(10_000),
+			total: Some(12_345),
+			..Transfer::default()
+		};
+		let entry = recording.to_entry(&transfer);
+		assert!(entry.contains(r#""time":12.345,"request":{"method":"POST","url":"http://example.com/items?a=1&b","httpVersion":"HTTP/1.1","cookies":[],"headers":[{"name":"Host","value":"example.com"},{"name":"Authorization","value":"REDACTED"},{"name":"Content-Type","value":"text/plain"}],"queryString":[{"name":"a","value":"1"},{"name":"b","value":""}],"headersSize":105,"bodySize":2,"postData":{"mimeType":"text/plain","text":"hi"}}"#));
+		assert!(entry.contains(r#""status":201,"statusText":"Created","httpVersion":"HTTP/1.1","cookies":[],"headers":[{"name":"Location","value":"/items/1"}],"content":{"size":5,"mimeType":"","text":"/wABAg==","encoding":"base64","comment":"truncated to 4 of 5 bytes"},"redirectURL":"/items/1""#));
+		assert!(entry.contains(r#""timings":{"blocked":-1,"dns":1.000,"connect":2.000,"ssl":-1,"send":0.500,"wait":6.500,"receive":2.345}"#));
+	}
+
+	#[test]
+	fn test_merge() {
+		let first = merge("", &[String::from("{}")]).unwrap();
+		assert!(first.starts_with(HEADER));
+		assert!(first.ends_with("[\n{}\n]}}\n"));
+		let second = merge(&first, &[String::from(r#"{"a":1}"#), String::from(r#"{"b":2}"#)]).unwrap();
+		assert!(second.ends_with("[\n{},\n{\"a\":1},\n{\"b\":2}\n]}}\n"));
+		assert_eq!(merge(r#"{"log":{}}"#, &[String::from("{}")]), None);
+	}
+
+	#[test]
+	fn test_append_concurrently() {
+		let path = TempPath::new("har");
+		let threads: Vec<_> = (0..4).map(|thread| {
+			let path = path.to_path_buf();
+			std::thread::spawn(move || (0..5).for_each(|i| append(&path, format!("{{\"n\":{}}}", thread * 5 + i)).unwrap()))
+		}).collect();
+		threads.into_iter().for_each(|x| x.join().unwrap());
+		let archive = std::fs::read_to_string(&path).unwrap();
+		let entries = json::parse(&archive).unwrap().get("log").and_then(|x| x.get("entries")).and_then(|x| x.as_array()).map(|x| x.len());
+		assert_eq!(entries, Some(20));
+	}
+}
@@ -0,0 +1,197 @@
+use std::ffi::CString;
+use std::fs::File;
+use std::io::Write;
+use std::os::raw::{c_char, c_void};
+
+use curl_inject_opt_shared::UrlParts;
+use curl_inject_opt_shared::headers::has_name;
+use curl_inject_opt_shared::reexports::curl_sys;
+
+use curl_sys::CURL;
+use curl_sys::CURLcode;
+
+use crate::{forward_header, with_state, CurlInjectOpt};
+use crate::curl::{self, Library};
+use crate::date::Date;
+use crate::error_policy;
+use crate::request_log;
+use crate::setopt::{self, Arg};
+
+/// The headers that are redacted if CURL_INJECT_OPT_HEADER_LOG_REDACT is not set.
+const DEFAULT_REDACT: [&str; 2] = ["Set-Cookie", "Authorization"];
+
+/// Appends the status line and headers of every response to a file.
+#[derive(Default)]
+pub struct HeaderLog {
+	/// The log file, or `None` if the log is disabled.
+	file: Option<File>,
+
+	/// The names of the headers whose value is replaced by `REDACTED`.
+	redact: Vec<String>,
+}
+
+impl HeaderLog {
+	/// Open the log file from CURL_INJECT_OPT_HEADER_LOG, with the comma separated headers to redact from CURL_INJECT_OPT_HEADER_LOG_REDACT.
+	pub fn from_env() -> Result<Self, String> {
+		let Some(path) = std::env::var_os("CURL_INJECT_OPT_HEADER_LOG").filter(|x| !x.is_empty()) else {
+			return Ok(Self::default());
+		};
+		let redact = match std::env::var("CURL_INJECT_OPT_HEADER_LOG_REDACT") {
+			Ok(x)  => x.split(',').map(str::trim).filter(|x| !x.is_empty()).map(String::from).collect(),
+			Err(_) => DEFAULT_REDACT.map(String::from).to_vec(),
+		};
+		let file = std::fs::OpenOptions::new()
+			.create(true)
+			.append(true)
+			.open(&path)
+			.map_err(|e| format!("failed to open header log {}: {}", path.to_string_lossy(), e))?;
+		Ok(Self { file: Some(file), redact })
+	}
+
+	/// Check if the log is enabled.
+	pub fn is_enabled(&self) -> bool {
+		self.file.is_some()
+	}
+
+	/// Format a line from the header callback for the log, without line ending and with the value of redacted headers replaced.
+	pub fn format_line(&self, line: &[u8]) -> String {
+		let line = line.strip_suffix(b"\n").unwrap_or(line);
+		let line = line.strip_suffix(b"\r").unwrap_or(line);
+		match self.redact.iter().find(|x| has_name(line, x)) {
+			Some(name) => format!("{}: REDACTED", String::from_utf8_lossy(&line[..name.len()])),
+			None       => String::from_utf8_lossy(line).into_owned(),
+		}
+	}
+
+	/// Append the headers of one response to the log.
+	///
+	/// Each response is written with a single `write()` call to a file opened for appending,
+	/// so responses from other threads and processes are not mixed up.
+	pub fn write(&self, response: &str) -> Result<(), String> {
+		let Some(mut file) = self.file.as_ref() else {
+			return Ok(());
+		};
+		file.write_all(response.as_bytes()).map_err(|e| format!("failed to write header log: {}", e))
+	}
+}
+
+impl CurlInjectOpt {
+	/// Install our CURLOPT_HEADERFUNCTION callback to log the response headers of the next transfer.
+	///
+	/// It is also needed to capture bodies: without a header callback, libcurl passes headers to our write callback with the wrong user data.
+	/// The callback of the application, if any, is called from ours.
+	pub fn install_header_callback(&self, library: &'static Library, handle: *mut CURL, url: Option<&UrlParts>) -> CURLcode {
+		if !self.header_log.is_enabled() && !self.capture.is_enabled() && !self.har.is_enabled() && !self.network.is_enabled() && !self.https.is_enabled() {
+			return curl_sys::CURLE_OK;
+		}
+
+		// Our own options may have turned on following redirects.
+		let follow = self.options.options_for(url)
+			.filter_map(|(_, option)| match (option.option.option, option.arg()) {
+				(curl_sys::CURLOPT_FOLLOWLOCATION, Arg::Long(x)) => Some(x != 0),
+				_ => None,
+			})
+			.last();
+		self.handles.with(handle, |state| {
+			state.library = Some(library);
+			state.response_headers.clear();
+			state.location = None;
+			state.follows_redirects = follow.unwrap_or(state.follow_location);
+		});
+		let code = self.setopt(library, handle, curl_sys::CURLOPT_HEADERFUNCTION, Arg::Function(Some(header_callback_fn())), "set CURLOPT_HEADERFUNCTION");
+		if code != curl_sys::CURLE_OK {
+			return code;
+		}
+		self.setopt(library, handle, curl_sys::CURLOPT_HEADERDATA, Arg::Pointer(handle.cast()), "set CURLOPT_HEADERDATA")
+	}
+
+	/// Add a line from the header callback to the response headers of a handle, and log the response when its headers are complete.
+	pub fn log_header(&self, handle: *mut CURL, line: &[u8]) {
+		if !self.header_log.is_enabled() {
+			return;
+		}
+		let line = self.header_log.format_line(line);
+		if line.is_empty() {
+			let mut response = self.handles.with(handle, |state| std::mem::take(&mut state.response_headers));
+			if !response.is_empty() {
+				response.push('\n');
+				if let Err(e) = self.header_log.write(&response) {
+					error_policy::report(e);
+				}
+			}
+			return;
+		}
+
+		// Start each response with the time, the process ID, and the method and URL of the request it answers.
+		let (library, first) = self.handles.with(handle, |state| (state.library, state.response_headers.is_empty()));
+		let request = library.filter(|_| first).map(|library| {
+			let method = library.info_string(handle, curl::CURLINFO_EFFECTIVE_METHOD).ok().flatten();
+			let method = method.map_or(String::from("-"), |x| x.to_string_lossy().into_owned());
+			let url = library.effective_url(handle).map_or(String::from("-"), |x| request_log::redact_url(&x.to_string_lossy()));
+			format!("# {} {} {} {}\n", Date::now().rfc3339(), std::process::id(), method, url)
+		});
+		self.handles.with(handle, |state| {
+			if let Some(request) = request.filter(|_| state.response_headers.is_empty()) {
+				state.response_headers.push_str(&request);
+			}
+			state.response_headers.push_str(&line);
+			state.response_headers.push('\n');
+		});
+	}
+}
+
+/// Our CURLOPT_HEADERFUNCTION callback, which logs the headers and forwards them to where libcurl would send them.
+///
+/// The user data pointer is the handle itself.
+extern "C" fn header_callback(buffer: *mut c_char, size: usize, nitems: usize, data: *mut c_void) -> usize {
+	let handle: *mut CURL = data.cast();
+	// SAFETY: libcurl passes a buffer of `size * nitems` bytes.
+	let line = unsafe { std::slice::from_raw_parts(buffer.cast::<u8>(), size * nitems) };
+	let allowed = with_state(|init| {
+		let Some(har) = init.handles.with_existing(handle, |state| state.har.clone()) else {
+			return true;
+		};
+		init.log_header(handle, line);
+		if let Some(har) = har {
+			har.response_header(line);
+		}
+		// Check redirects before libcurl follows them, returning 0 aborts the transfer.
+		if has_name(line, "Location") {
+			let location = CString::new(line["Location:".len()..].trim_ascii()).ok();
+			init.handles.with(handle, |state| state.location = location);
+		}
+		!matches!(line, b"\r\n" | b"\n") || init.check_redirect(handle)
+	});
+	if allowed == Some(false) {
+		return 0;
+	}
+
+	let application = with_state(|init| init.handles.with_existing(handle, |state| (state.header, state.header_data, state.write))).flatten();
+	match application {
+		// SAFETY: libcurl passes a valid buffer, and the application set the callbacks and user data.
+		Some((header, header_data, write)) => unsafe { forward_header(header, header_data, write, buffer, size, nitems) },
+		None => size * nitems,
+	}
+}
+
+/// Get our CURLOPT_HEADERFUNCTION callback as a generic function pointer.
+fn header_callback_fn() -> setopt::Function {
+	let callback: curl::CurlWriteCallback = header_callback;
+	// SAFETY: Function pointers all have the same representation, libcurl calls it with the right signature.
+	unsafe { std::mem::transmute::<curl::CurlWriteCallback, setopt::Function>(callback) }
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_format_line() {
+		let log = HeaderLog { file: None, redact: DEFAULT_REDACT.map(String::from).to_vec() };
+		assert_eq!(log.format_line(b"HTTP/1.1 301 Moved Permanently\r\n"), "HTTP/1.1 301 Moved Permanently");
+		assert_eq!(log.format_line(b"Location: /next\r\n"), "Location: /next");
+		assert_eq!(log.format_line(b"set-cookie: session=secret; HttpOnly\r\n"), "set-cookie: REDACTED");
+		assert_eq!(log.format_line(b"Set-Cookies: fine\n"), "Set-Cookies: fine");
+		assert_eq!(log.format_line(b"\r\n"), "");
+	}
+}
@@ -0,0 +1,75 @@
+use std::ffi::CString;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+
+use curl_inject_opt_shared::UrlParts;
+use curl_inject_opt_shared::headers::{HeaderRule, Placeholders};
+use curl_inject_opt_shared::reexports::curl_sys;
+
+use curl_sys::CURL;
+use curl_sys::CURLcode;
+
+use crate::CurlInjectOpt;
+use crate::curl::Library;
+use crate::date::Date;
+use crate::error_policy;
+use crate::random;
+use crate::setopt::{self, Arg, Slist};
+
+impl CurlInjectOpt {
+	/// Install a copy of the CURLOPT_HTTPHEADER list of the application with the header rules applied to it.
+	///
+	/// The `extra` rules, such as the trace context headers, are applied after the configured rules.
+	/// The list of the application itself is never modified.
+	/// If no header rules apply to this transfer but they did for the previous one, the list of the application is restored.
+	pub fn install_headers(&self, library: &Library, handle: *mut CURL, url: Option<&UrlParts>, extra: &[HeaderRule]) -> CURLcode {
+		if !self.options.has_headers() && extra.is_empty() {
+			return curl_sys::CURLE_OK;
+		}
+
+		let counter = self.transfers.fetch_add(1, Ordering::Relaxed) + 1;
+		let rules: Vec<_> = self.options.headers_for(url).chain(extra).collect();
+		let application = self.handles.with(handle, |state| state.httpheader) as *const curl_sys::curl_slist;
+		if rules.is_empty() {
+			if self.handles.with(handle, |state| state.headers.take()).is_none() {
+				return curl_sys::CURLE_OK;
+			}
+			if self.debug {
+				eprintln!("curl-inject-opt: restoring request headers");
+			}
+			return self.setopt(library, handle, curl_sys::CURLOPT_HTTPHEADER, Arg::Slist(application), "restore CURLOPT_HTTPHEADER");
+		}
+
+		// SAFETY: libcurl requires the list to stay valid until the transfer is done.
+		let mut headers: Vec<Vec<u8>> = unsafe { setopt::slist_items(application) }.map(|x| x.to_bytes().to_vec()).collect();
+		let placeholders = Placeholders {
+			uuid: random::uuid(),
+			time: Date::now().rfc3339(),
+			counter,
+			pid: std::process::id(),
+		};
+		for rule in rules {
+			match rule {
+				HeaderRule::Sign(name) => {
+					if let Err(e) = self.sign_request(handle, url, name, &mut headers) {
+						error_policy::report(e);
+					}
+				},
+				rule => rule.apply(&mut headers, &placeholders),
+			}
+		}
+		if self.debug {
+			for header in &headers {
+				eprintln!("curl-inject-opt: request header: {}", String::from_utf8_lossy(header));
+			}
+		}
+
+		// Header lines come from C strings and validated rules, so they never contain a null byte.
+		let headers = headers.into_iter().filter_map(|x| CString::new(x).ok()).collect();
+		let headers = Arc::new(Slist::new(headers));
+		let code = self.setopt(library, handle, curl_sys::CURLOPT_HTTPHEADER, Arg::Slist(headers.as_ptr()), "set CURLOPT_HTTPHEADER");
+		// Only drop the previous copy after libcurl stopped using it.
+		let _previous = self.handles.with(handle, |state| state.headers.replace(headers));
+		code
+	}
+}
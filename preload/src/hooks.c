@@ -0,0 +1,78 @@
+/* Hooks for curl_easy_setopt() and curl_easy_getinfo().
+ *
+ * curl_easy_setopt() and curl_easy_getinfo() are variadic, and stable Rust can not define variadic functions.
+ * The setopt hooks read the argument with va_arg() based on the argument type encoded in the option ID,
+ * exactly like libcurl does, and pass it on to curl_inject_opt_curl_easy_setopt_hook() in lib.rs.
+ * The argument of curl_easy_getinfo() is always a pointer, which is passed on to curl_inject_opt_curl_easy_getinfo_hook().
+ *
+ * When compiled with CURL_INJECT_OPT_SYMVER, the hooks are exported as curl_easy_setopt and curl_easy_getinfo with the symbol version of each flavour of libcurl.
+ * Unit tests link libcurl directly, so they use a build without symbol versions.
+ */
+#include <stdarg.h>
+#include <stdint.h>
+
+/* Must match setopt::RawArg. */
+union curl_inject_opt_arg {
+	long long_value;
+	int64_t off_t_value;
+	void * pointer;
+	void (*function)(void);
+};
+
+/* Indices in curl::LIBRARIES. */
+enum {
+	LIBRARY_OPENSSL_4 = 0,
+	LIBRARY_GNUTLS_3 = 1,
+	LIBRARY_UNVERSIONED = 2,
+};
+
+int curl_inject_opt_curl_easy_setopt_hook(int library, void * handle, int option, const union curl_inject_opt_arg * arg);
+int curl_inject_opt_curl_easy_getinfo_hook(int library, void * handle, int info, void * arg);
+
+static int forward(int library, void * handle, int option, va_list args) {
+	union curl_inject_opt_arg arg;
+	switch (option / 10000) {
+		case 0: arg.long_value  = va_arg(args, long);           break; /* CURLOPTTYPE_LONG */
+		case 1: arg.pointer     = va_arg(args, void *);         break; /* CURLOPTTYPE_OBJECTPOINT */
+		case 2: arg.function    = va_arg(args, void (*)(void)); break; /* CURLOPTTYPE_FUNCTIONPOINT */
+		case 3: arg.off_t_value = va_arg(args, int64_t);        break; /* CURLOPTTYPE_OFF_T */
+		case 4: arg.pointer     = va_arg(args, void *);         break; /* CURLOPTTYPE_BLOB */
+		default: return 48; /* CURLE_UNKNOWN_OPTION */
+	}
+	return curl_inject_opt_curl_easy_setopt_hook(library, handle, option, &arg);
+}
+
+#ifdef CURL_INJECT_OPT_SYMVER
+#define SYMVER(name, symbol, version) __asm__(".symver " #name ", " symbol version);
+#else
+#define SYMVER(name, symbol, version)
+#endif
+
+#define DEFINE_SETOPT(name, library, version) \
+	int name(void * handle, int option, ...) { \
+		va_list args; \
+		va_start(args, option); \
+		int code = forward(library, handle, option, args); \
+		va_end(args); \
+		return code; \
+	} \
+	SYMVER(name, "curl_easy_setopt", version)
+
+#define DEFINE_GETINFO(name, library, version) \
+	int name(void * handle, int info, ...) { \
+		va_list args; \
+		va_start(args, info); \
+		void * arg = va_arg(args, void *); \
+		va_end(args); \
+		return curl_inject_opt_curl_easy_getinfo_hook(library, handle, info, arg); \
+	} \
+	SYMVER(name, "curl_easy_getinfo", version)
+
+/* The unversioned hook gets a default symbol version, see the comment in lib.rs. */
+DEFINE_SETOPT(curl_inject_opt_curl_easy_setopt,           LIBRARY_UNVERSIONED, "@@CURL_INJECT_OPT")
+DEFINE_SETOPT(curl_inject_opt_openssl_4_curl_easy_setopt, LIBRARY_OPENSSL_4,   "@CURL_OPENSSL_4")
+DEFINE_SETOPT(curl_inject_opt_gnutls_3_curl_easy_setopt,  LIBRARY_GNUTLS_3,    "@CURL_GNUTLS_3")
+
+DEFINE_GETINFO(curl_inject_opt_curl_easy_getinfo,           LIBRARY_UNVERSIONED, "@@CURL_INJECT_OPT")
+DEFINE_GETINFO(curl_inject_opt_openssl_4_curl_easy_getinfo, LIBRARY_OPENSSL_4,   "@CURL_OPENSSL_4")
+DEFINE_GETINFO(curl_inject_opt_gnutls_3_curl_easy_getinfo,  LIBRARY_GNUTLS_3,    "@CURL_GNUTLS_3")
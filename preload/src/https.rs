@@ -0,0 +1,147 @@
+use curl_inject_opt_shared::{Rule, UrlParts};
+
+/// What to do with plaintext `http://` URLs.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HttpsMode {
+	/// Leave plaintext URLs alone.
+	Off,
+
+	/// Rewrite plaintext URLs to `https://`.
+	Upgrade,
+
+	/// Refuse transfers with plaintext URLs.
+	Require,
+}
+
+/// The policy for plaintext `http://` URLs.
+#[derive(Debug)]
+pub struct HttpsPolicy {
+	/// What to do with plaintext URLs.
+	pub mode: HttpsMode,
+
+	/// Hosts that may still be used with plaintext URLs.
+	pub exceptions: Vec<Rule>,
+}
+
+/// What the policy decided for a URL.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Verdict {
+	/// Use the URL as it is.
+	Allow,
+
+	/// Use the upgraded URL instead.
+	Upgrade(String),
+
+	/// Refuse the transfer.
+	Refuse,
+}
+
+impl HttpsMode {
+	/// Parse a mode from a string.
+	pub fn parse(value: &str) -> Result<Self, String> {
+		if value.eq_ignore_ascii_case("off") {
+			Ok(Self::Off)
+		} else if value.eq_ignore_ascii_case("upgrade") {
+			Ok(Self::Upgrade)
+		} else if value.eq_ignore_ascii_case("require") {
+			Ok(Self::Require)
+		} else {
+			Err(format!("invalid HTTPS mode: {}, expected off, upgrade or require", value))
+		}
+	}
+}
+
+impl HttpsPolicy {
+	/// Parse the policy from a mode and a comma separated list of host patterns.
+	pub fn parse(mode: &str, exceptions: &str) -> Result<Self, String> {
+		let mode = HttpsMode::parse(mode)?;
+		let exceptions = exceptions.split(',')
+			.filter(|x| !x.is_empty())
+			.map(Rule::parse)
+			.collect::<Result<_, _>>()?;
+		Ok(Self { mode, exceptions })
+	}
+
+	/// Get the policy from the CURL_INJECT_OPT_HTTPS and CURL_INJECT_OPT_HTTPS_EXCEPT environment variables.
+	pub fn from_env() -> Result<Self, String> {
+		let mode = std::env::var("CURL_INJECT_OPT_HTTPS").unwrap_or_else(|_| String::from("off"));
+		let exceptions = std::env::var("CURL_INJECT_OPT_HTTPS_EXCEPT").unwrap_or_default();
+		Self::parse(&mode, &exceptions)
+	}
+
+	/// Check if the policy does anything at all.
+	pub fn is_enabled(&self) -> bool {
+		self.mode != HttpsMode::Off
+	}
+
+	/// Decide what to do with a URL.
+	pub fn check(&self, url: &str, parts: &UrlParts) -> Verdict {
+		if self.mode == HttpsMode::Off || parts.scheme != "http" || self.exceptions.iter().any(|x| x.matches(parts)) {
+			return Verdict::Allow;
+		}
+		match self.mode {
+			HttpsMode::Upgrade => Verdict::Upgrade(upgrade_url(url)),
+			_                  => Verdict::Refuse,
+		}
+	}
+
+	/// Check if libcurl may follow a redirect to a URL.
+	///
+	/// A redirect can not be upgraded once libcurl follows it, so plaintext redirects are refused in both modes.
+	pub fn allows_redirect(&self, parts: &UrlParts) -> bool {
+		self.mode == HttpsMode::Off || parts.scheme != "http" || self.exceptions.iter().any(|x| x.matches(parts))
+	}
+}
+
+/// Change the scheme of a plaintext URL to `https://`.
+///
+/// An explicit port 80 is removed, so the default HTTPS port is used instead.
+fn upgrade_url(url: &str) -> String {
+	let rest = match url.find("://") {
+		Some(i) => &url[i + 3..],
+		None    => url,
+	};
+	let end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+	let (authority, rest) = rest.split_at(end);
+	let authority = authority.strip_suffix(":80").unwrap_or(authority);
+	format!("https://{}{}", authority, rest)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn check(policy: &HttpsPolicy, url: &str) -> Verdict {
+		policy.check(url, &UrlParts::parse(url).unwrap())
+	}
+
+	#[test]
+	fn test_upgrade() {
+		let policy = HttpsPolicy::parse("upgrade", "localhost,*.lan").unwrap();
+		assert!(check(&policy, "http://example.com/a?b#c") == Verdict::Upgrade("https://example.com/a?b#c".into()));
+		assert!(check(&policy, "HTTP://user@example.com:80") == Verdict::Upgrade("https://user@example.com".into()));
+		assert!(check(&policy, "example.com/a") == Verdict::Upgrade("https://example.com/a".into()));
+		assert!(check(&policy, "https://example.com/") == Verdict::Allow);
+		assert!(check(&policy, "ftp://example.com/") == Verdict::Allow);
+		assert!(check(&policy, "http://localhost:8080/") == Verdict::Allow);
+		assert!(check(&policy, "http://printer.lan/") == Verdict::Allow);
+	}
+
+	#[test]
+	fn test_require() {
+		let policy = HttpsPolicy::parse("require", "localhost").unwrap();
+		assert!(check(&policy, "http://example.com/") == Verdict::Refuse);
+		assert!(check(&policy, "https://example.com/") == Verdict::Allow);
+		assert!(check(&policy, "http://localhost/") == Verdict::Allow);
+		assert!(HttpsPolicy::parse("sometimes", "").is_err());
+	}
+
+	#[test]
+	fn test_redirect() {
+		let policy = HttpsPolicy::parse("upgrade", "localhost").unwrap();
+		assert!(!policy.allows_redirect(&UrlParts::parse("http://example.com/").unwrap()));
+		assert!(policy.allows_redirect(&UrlParts::parse("https://example.com/").unwrap()));
+		assert!(policy.allows_redirect(&UrlParts::parse("http://localhost/").unwrap()));
+		assert!(HttpsPolicy::parse("off", "").unwrap().allows_redirect(&UrlParts::parse("http://example.com/").unwrap()));
+	}
+}
@@ -0,0 +1,358 @@
+use std::fmt::Write;
+
+/// Append a string to a buffer as a JSON string literal.
+pub fn write_string(buffer: &mut String, value: &str) {
+	buffer.push('"');
+	for c in value.chars() {
+		match c {
+			'"'  => buffer.push_str("\\\""),
+			'\\' => buffer.push_str("\\\\"),
+			'\n' => buffer.push_str("\\n"),
+			'\r' => buffer.push_str("\\r"),
+			'\t' => buffer.push_str("\\t"),
+			c if c < ' ' => {
+				let _ = write!(buffer, "\\u{:04x}", c as u32);
+			},
+			c => buffer.push(c),
+		}
+	}
+	buffer.push('"');
+}
+
+/// A JSON object that is written one field at a time.
+#[derive(Default)]
+pub struct Object {
+	buffer: String,
+}
+
+impl Object {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	fn key(&mut self, key: &str) {
+		self.buffer.push(if self.buffer.is_empty() { '{' } else { ',' });
+		write_string(&mut self.buffer, key);
+		self.buffer.push(':');
+	}
+
+	/// Add a string field.
+	pub fn string(&mut self, key: &str, value: &str) -> &mut Self {
+		self.key(key);
+		write_string(&mut self.buffer, value);
+		self
+	}
+
+	/// Add a string field, or `null` if there is no value.
+	pub fn optional_string(&mut self, key: &str, value: Option<&str>) -> &mut Self {
+		match value {
+			Some(value) => self.string(key, value),
+			None        => self.raw(key, "null"),
+		}
+	}
+
+	/// Add a number field, or `null` if there is no value.
+	pub fn number(&mut self, key: &str, value: Option<impl std::fmt::Display>) -> &mut Self {
+		match value {
+			Some(value) => {
+				self.key(key);
+				let _ = write!(self.buffer, "{}", value);
+				self
+			},
+			None => self.raw(key, "null"),
+		}
+	}
+
+	/// Add a field with a value that is already encoded as JSON, such as a nested object.
+	pub fn raw(&mut self, key: &str, json: &str) -> &mut Self {
+		self.key(key);
+		self.buffer.push_str(json);
+		self
+	}
+
+	/// Finish the object and get the encoded JSON.
+	pub fn finish(&mut self) -> String {
+		let mut buffer = std::mem::take(&mut self.buffer);
+		if buffer.is_empty() {
+			buffer.push('{');
+		}
+		buffer.push('}');
+		buffer
+	}
+}
+
+/// Encode a list of values that are already encoded as JSON as an array.
+pub fn array(values: impl IntoIterator<Item = String>) -> String {
+	let values: Vec<_> = values.into_iter().collect();
+	format!("[{}]", values.join(","))
+}
+
+/// Format a duration in microseconds as seconds, without losing precision to floating point.
+pub fn seconds(microseconds: i64) -> String {
+	let sign = if microseconds < 0 { "-" } else { "" };
+	let microseconds = microseconds.unsigned_abs();
+	format!("{}{}.{:06}", sign, microseconds / 1_000_000, microseconds % 1_000_000)
+}
+
+/// A parsed JSON value.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+	Null,
+	Bool(bool),
+
+	/// A number, as it was written, so integers do not lose precision.
+	Number(String),
+	String(String),
+	Array(Vec<Value>),
+
+	/// An object, with the fields in the order they were written.
+	Object(Vec<(String, Value)>),
+}
+
+impl Value {
+	/// Get the first field of an object with the given key.
+	pub fn get(&self, key: &str) -> Option<&Value> {
+		match self {
+			Self::Object(fields) => fields.iter().find(|(x, _)| x == key).map(|(_, value)| value),
+			_                    => None,
+		}
+	}
+
+	pub fn as_str(&self) -> Option<&str> {
+		match self {
+			Self::String(x) => Some(x),
+			_               => None,
+		}
+	}
+
+	pub fn as_array(&self) -> Option<&[Value]> {
+		match self {
+			Self::Array(x) => Some(x),
+			_              => None,
+		}
+	}
+
+	pub fn as_object(&self) -> Option<&[(String, Value)]> {
+		match self {
+			Self::Object(x) => Some(x),
+			_               => None,
+		}
+	}
+
+	/// Get a number that is a non-negative integer.
+	pub fn as_u64(&self) -> Option<u64> {
+		match self {
+			Self::Number(x) => x.parse().ok(),
+			_               => None,
+		}
+	}
+}
+
+/// The maximum nesting depth of arrays and objects, so deeply nested input can not overflow the stack.
+const MAX_DEPTH: usize = 128;
+
+/// Parse a JSON document.
+pub fn parse(input: &str) -> Result<Value, String> {
+	let mut parser = Parser { input, position: 0 };
+	let value = parser.value(0)?;
+	parser.whitespace();
+	if parser.position < input.len() {
+		return Err(parser.error("unexpected data after the end"));
+	}
+	Ok(value)
+}
+
+struct Parser<'a> {
+	input: &'a str,
+	position: usize,
+}
+
+impl Parser<'_> {
+	fn error(&self, message: &str) -> String {
+		format!("invalid JSON at byte {}: {}", self.position, message)
+	}
+
+	fn peek(&self) -> Option<u8> {
+		self.input.as_bytes().get(self.position).copied()
+	}
+
+	fn whitespace(&mut self) {
+		while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+			self.position += 1;
+		}
+	}
+
+	/// Consume a byte if it is the next one after whitespace.
+	fn eat(&mut self, byte: u8) -> bool {
+		self.whitespace();
+		let found = self.peek() == Some(byte);
+		if found {
+			self.position += 1;
+		}
+		found
+	}
+
+	fn value(&mut self, depth: usize) -> Result<Value, String> {
+		if depth > MAX_DEPTH {
+			return Err(self.error("too deeply nested"));
+		}
+		self.whitespace();
+		match self.peek() {
+			Some(b'{') => self.object(depth),
+			Some(b'[') => self.array(depth),
+			Some(b'"') => self.string().map(Value::String),
+			Some(b'-' | b'0'..=b'9') => self.number(),
+			_ => {
+				for (word, value) in [("null", Value::Null), ("true", Value::Bool(true)), ("false", Value::Bool(false))] {
+					if self.input[self.position..].starts_with(word) {
+						self.position += word.len();
+						return Ok(value);
+					}
+				}
+				Err(self.error("expected a value"))
+			},
+		}
+	}
+
+	fn object(&mut self, depth: usize) -> Result<Value, String> {
+		self.position += 1;
+		let mut fields = Vec::new();
+		if self.eat(b'}') {
+			return Ok(Value::Object(fields));
+		}
+		loop {
+			self.whitespace();
+			if self.peek() != Some(b'"') {
+				return Err(self.error("expected a string as key"));
+			}
+			let key = self.string()?;
+			if !self.eat(b':') {
+				return Err(self.error("expected a colon"));
+			}
+			fields.push((key, self.value(depth + 1)?));
+			if self.eat(b'}') {
+				return Ok(Value::Object(fields));
+			}
+			if !self.eat(b',') {
+				return Err(self.error("expected a comma or the end of the object"));
+			}
+		}
+	}
+
+	fn array(&mut self, depth: usize) -> Result<Value, String> {
+		self.position += 1;
+		let mut items = Vec::new();
+		if self.eat(b']') {
+			return Ok(Value::Array(items));
+		}
+		loop {
+			items.push(self.value(depth + 1)?);
+			if self.eat(b']') {
+				return Ok(Value::Array(items));
+			}
+			if !self.eat(b',') {
+				return Err(self.error("expected a comma or the end of the array"));
+			}
+		}
+	}
+
+	fn number(&mut self) -> Result<Value, String> {
+		let start = self.position;
+		while matches!(self.peek(), Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9')) {
+			self.position += 1;
+		}
+		let number = &self.input[start..self.position];
+		if number.parse::<f64>().is_err() {
+			self.position = start;
+			return Err(self.error("invalid number"));
+		}
+		Ok(Value::Number(number.to_string()))
+	}
+
+	fn string(&mut self) -> Result<String, String> {
+		self.position += 1;
+		let mut value = String::new();
+		loop {
+			let rest = &self.input[self.position..];
+			let Some(end) = rest.find(['"', '\\']) else {
+				return Err(self.error("unterminated string"));
+			};
+			if rest[..end].contains(|c: char| c < ' ') {
+				return Err(self.error("control character in string"));
+			}
+			value.push_str(&rest[..end]);
+			self.position += end + 1;
+			if rest.as_bytes()[end] == b'"' {
+				return Ok(value);
+			}
+			let escaped = match self.peek() {
+				Some(b'"')  => '"',
+				Some(b'\\') => '\\',
+				Some(b'/')  => '/',
+				Some(b'b')  => '\u{8}',
+				Some(b'f')  => '\u{c}',
+				Some(b'n')  => '\n',
+				Some(b'r')  => '\r',
+				Some(b't')  => '\t',
+				Some(b'u')  => {
+					self.position += 1;
+					let high = self.hex4()?;
+					let code = match high {
+						0xd800..=0xdbff if self.input[self.position..].starts_with("\\u") => {
+							self.position += 2;
+							match self.hex4()? {
+								low @ 0xdc00..=0xdfff => 0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00),
+								_                     => u32::MAX,
+							}
+						},
+						_ => high,
+					};
+					value.push(char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER));
+					continue;
+				},
+				_ => return Err(self.error("invalid escape sequence")),
+			};
+			value.push(escaped);
+			self.position += 1;
+		}
+	}
+
+	fn hex4(&mut self) -> Result<u32, String> {
+		let digits = self.input.get(self.position..self.position + 4).filter(|x| x.bytes().all(|x| x.is_ascii_hexdigit()));
+		let Some(digits) = digits else {
+			return Err(self.error("invalid unicode escape"));
+		};
+		self.position += 4;
+		Ok(u32::from_str_radix(digits, 16).unwrap_or(0))
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_object() {
+		let json = Object::new()
+			.string("url", "http://example.com/\"quoted\"\\")
+			.optional_string("ip", None)
+			.number("code", Some(200))
+			.number("size", None::<i64>)
+			.raw("times", &Object::new().raw("total", &seconds(1_500_042)).finish())
+			.string("control", "a\nb\u{1}")
+			.finish();
+		assert_eq!(json, r#"{"url":"http://example.com/\"quoted\"\\","ip":null,"code":200,"size":null,"times":{"total":1.500042},"control":"a\nb\u0001"}"#);
+	}
+
+	#[test]
+	fn test_parse() {
+		let value = parse(r#" {"a": [1, -2.5e3, true, null], "b": "x\"\u00e9\ud83d\ude00\n", "a": {}} "#).unwrap();
+		assert_eq!(value.get("a").and_then(Value::as_array).map(|x| x.len()), Some(4));
+		assert_eq!(value.get("a").and_then(|x| x.as_array()?.first()?.as_u64()), Some(1));
+		assert_eq!(value.get("b").and_then(Value::as_str), Some("x\"\u{e9}\u{1f600}\n"));
+		assert_eq!(value.as_object().map(|x| x.len()), Some(3));
+		assert!(parse(r#"{"a": 1,}"#).is_err());
+		assert!(parse("[1] 2").is_err());
+		assert!(parse(&"[".repeat(1000)).is_err());
+	}
+}
@@ -1,58 +1,152 @@
-use std::ffi::CStr;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
 use std::os::unix::ffi::OsStrExt;
-use std::sync::LazyLock;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, LazyLock, Mutex, MutexGuard, PoisonError};
+use std::sync::atomic::AtomicU64;
 
+mod audit;
+mod base64;
+mod ca;
+mod capture;
+mod curl;
+mod date;
+mod error_policy;
+mod handles;
+mod har;
+mod header_log;
+mod headers;
+mod https;
+mod json;
+mod metrics;
+mod network;
+mod otel;
+mod random;
+mod replay;
+mod request_log;
+mod setopt;
+mod signing;
+mod summary;
+mod symbols;
+#[cfg(test)]
+mod testing;
+mod tls;
+mod trace;
+
+use curl_inject_opt_shared::OptionSet;
+use curl_inject_opt_shared::headers::has_name;
+use curl_inject_opt_shared::OPTIONS;
+use curl_inject_opt_shared::UrlParts;
 use curl_inject_opt_shared::SetOption;
 use curl_inject_opt_shared::Value;
+use curl_inject_opt_shared::merge_list;
 use curl_inject_opt_shared::parse_options;
 use curl_inject_opt_shared::reexports::curl_sys;
 
 use curl_sys::CURL;
 use curl_sys::CURLM;
 use curl_sys::CURLMcode;
+use curl_sys::CURLMsg;
 use curl_sys::CURLcode;
 use curl_sys::CURLoption;
 
-type CurlEasySetOpt  = extern "C" fn(handle: *mut CURL, option: CURLoption, ...) -> CURLcode;
-type CurlEasyPerform = extern "C" fn(handle: *mut CURL) -> CURLcode;
-type CurlMultiAddHandle = extern "C" fn(multi_handle: *mut CURLM, handle: *mut CURL) -> CURLMcode;
-
-macro_rules! load_next_fn {
-	( $name:ident : $type:ty ) => {{
-		let name = unsafe { CStr::from_bytes_with_nul_unchecked(concat!(stringify!($name), "\0").as_bytes()) };
-		unsafe {
-			// Clear dlerror() before calling dlsym().
-			libc::dlerror();
-
-			// Look-up the wanted symbol.
-			let symbol = libc::dlsym(libc::RTLD_NEXT, name.as_ptr());
+use audit::Audit;
+use ca::ExtraCa;
+use capture::Capture;
+use curl::{Library, LIBCURL, LIBRARIES};
+use handles::{HandleTable, PostFields, RequestKind};
+use har::Har;
+use header_log::HeaderLog;
+use https::HttpsPolicy;
+use metrics::Metrics;
+use network::NetworkPolicy;
+use otel::Tracer;
+use replay::{Replay, Replayed, Unmatched};
+use request_log::RequestLog;
+use summary::SummaryRecorder;
+use setopt::{Arg, PreparedOption, Saved, Slist};
+use signing::Signer;
+use symbols::NextFn;
+use tls::TlsPolicy;
+use trace::Trace;
 
-			// Check dlerror().
-			let error  = libc::dlerror();
-			if !error.is_null() {
-				// Convert to Err(String).
-				Err(format!("failed to look up symbol {}: {}", stringify!($name), CStr::from_ptr(error).to_string_lossy()))
-			} else {
-				// Convert to Ok(fn).
-				let symbol : $type = std::mem::transmute(symbol);
-				Ok(symbol)
-			}
-		}
-	}};
+/// Get a function from the next object, or report an error if it can not be found (yet).
+fn next_fn<T: Copy>(function: &NextFn<T>) -> Option<T> {
+	match function.get() {
+		Ok(x) => Some(x),
+		Err(e) => {
+			error_policy::report(e);
+			None
+		},
+	}
 }
 
 struct CurlInjectOpt {
-	/// The original curl_easy_perform function.
-	curl_easy_perform: CurlEasyPerform,
+	/// The options to set on handles, and the rules that decide when they apply.
+	options: OptionSet<PreparedOption>,
+
+	/// What to do with plaintext HTTP URLs.
+	https: HttpsPolicy,
+
+	/// Which hosts transfers may connect to.
+	network: NetworkPolicy,
+
+	/// What to do with insecure TLS settings of the application.
+	tls: TlsPolicy,
+
+	/// Insecure uses of libcurl, for the audit report.
+	audit: Audit,
+
+	/// Extra CA certificates to trust in addition to the CA bundle of the application.
+	extra_ca: ExtraCa,
+
+	/// The log of finished transfers.
+	request_log: RequestLog,
+
+	/// The log of response headers.
+	header_log: HeaderLog,
+
+	/// Finished transfers for the summary of the command.
+	summary: SummaryRecorder,
+
+	/// Metrics of finished transfers.
+	metrics: Metrics,
+
+	/// Spans of transfers, for distributed tracing.
+	tracer: Tracer,
+
+	/// The key to sign requests with, for `header-sign` rules.
+	signer: Signer,
+
+	/// Captures the request and response bodies of transfers.
+	capture: Capture,
+
+	/// Writes a trace of the transfers of each handle.
+	trace: Trace,
 
-	/// The original curl_east_setopt function.
-	curl_easy_setopt: CurlEasySetOpt,
+	/// Records all transfers in an HTTP Archive file.
+	har: Har,
 
-	/// The original curl_multi_add_handle function.
-	curl_multi_add_handle: CurlMultiAddHandle,
+	/// Recorded responses to answer transfers with instead of running them.
+	replay: Replay,
 
-	/// The options to set on all handles.
-	options: Vec<SetOption>,
+	/// The number of transfers started by the process, for the `%{counter}` placeholder in headers.
+	transfers: AtomicU64,
+
+	/// State for each known CURL handle.
+	handles: HandleTable,
+
+	/// If true, set the options only once per handle instead of before every transfer.
+	apply_once: bool,
+
+	/// If true, refuse to start a transfer when an option could not be set.
+	strict: bool,
+
+	/// The error code for curl_multi_add_handle() when an option could not be set in strict mode.
+	strict_multi_code: CURLMcode,
+
+	/// The first error while loading options or policies, which makes every transfer fail in strict mode.
+	init_error: Option<String>,
 
 	/// If true, run in debug mode, printing what we're doing.
 	debug: bool,
@@ -66,15 +160,77 @@ fn env_bool(name: &str) -> bool {
 	}
 }
 
+fn env_int<T: std::str::FromStr>(name: &str, default: T) -> T {
+	let Some(value) = std::env::var_os(name) else {
+		return default;
+	};
+	match value.to_str().and_then(|x| x.parse().ok()) {
+		Some(x) => x,
+		None    => {
+			error_policy::report(format_args!("invalid integer value for {}: {}", name, value.to_string_lossy()));
+			default
+		},
+	}
+}
+
+/// Get a feature from its environment variables, or `fallback` if they are invalid.
+///
+/// The error goes to `fail`, so in strict mode no transfer runs without a feature that the user enabled.
+fn load<T>(feature: Result<T, String>, fallback: T, fail: &mut impl FnMut(String)) -> T {
+	feature.unwrap_or_else(|e| {
+		fail(e);
+		fallback
+	})
+}
+
+/// Lock a mutex, even if a thread panicked while holding it.
+///
+/// A panic while holding one of our locks can at worst lose some recorded data, and hooks must keep working after it.
+pub(crate) fn lock_ignore_poison<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+	mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
 impl CurlInjectOpt {
-	fn init() -> Result<Self, String> {
-		let curl_easy_perform     = load_next_fn!(curl_easy_perform     : CurlEasyPerform);
-		let curl_easy_setopt      = load_next_fn!(curl_easy_setopt      : CurlEasySetOpt);
-		let curl_multi_add_handle = load_next_fn!(curl_multi_add_handle : CurlMultiAddHandle);
+	fn init() -> Self {
 		let debug                 = env_bool("CURL_INJECT_OPT_DEBUG");
+		let apply_once            = env_bool("CURL_INJECT_OPT_APPLY_ONCE");
+		let strict                = env_bool("CURL_INJECT_OPT_STRICT");
+		let strict_multi_code     = env_int("CURL_INJECT_OPT_STRICT_MULTI_CODE", curl_sys::CURLM_BAD_EASY_HANDLE);
 		let no_inherit            = std::env::var_os("CURL_INJECT_OPT_NO_INHERIT");
+
+		// Transfers that run without the options or policies that failed to load are refused in strict mode.
+		let mut init_error        = None;
+		let mut fail              = |e: String| {
+			error_policy::report(&e);
+			init_error.get_or_insert(e);
+		};
+
 		let options               = std::env::var_os("CURL_INJECT_OPT");
-		let options               = options.map(|x| parse_options(x.as_bytes()).expect("failed to parse CURL_INJECT_OPT")).unwrap_or_default();
+		let options               = options.map(|x| parse_options(x.as_bytes())).unwrap_or(Ok(OptionSet::default()));
+		let options               = load(options.map_err(|e| format!("failed to parse CURL_INJECT_OPT: {}", e)), OptionSet::default(), &mut fail);
+		let options               = options.filter_map(|option| {
+			PreparedOption::new(option).map_err(&mut fail).ok()
+		});
+
+		let https                 = load(HttpsPolicy::from_env(), HttpsPolicy { mode: https::HttpsMode::Off, exceptions: Vec::new() }, &mut fail);
+		let network               = load(NetworkPolicy::from_env(), NetworkPolicy::allow_all(), &mut fail);
+		let tls                   = load(TlsPolicy::from_env(), TlsPolicy::off(), &mut fail);
+		let extra_ca              = load(ExtraCa::from_env(), ExtraCa::default(), &mut fail);
+		let request_log           = load(RequestLog::from_env(), RequestLog::default(), &mut fail);
+		let header_log            = load(HeaderLog::from_env(), HeaderLog::default(), &mut fail);
+		let summary               = load(SummaryRecorder::from_env(), SummaryRecorder::default(), &mut fail);
+		let metrics               = load(Metrics::from_env(), Metrics::default(), &mut fail);
+		let tracer                = load(Tracer::from_env(), Tracer::default(), &mut fail);
+		let signer                = load(Signer::from_env(), Signer::default(), &mut fail);
+		let capture               = load(Capture::from_env(), Capture::default(), &mut fail);
+		let trace                 = load(Trace::from_env(), Trace::default(), &mut fail);
+		let har                   = load(Har::from_env(), Har::default(), &mut fail);
+		let replay                = load(Replay::from_env(), Replay::default(), &mut fail);
+		let audit                 = Audit::from_env();
+		if audit.is_enabled() || metrics.is_enabled() || tracer.is_enabled() {
+			// SAFETY: The function is safe to call at any time.
+			unsafe { libc::atexit(at_exit) };
+		}
 
 		if let Some(path) = no_inherit {
 			if let Some(preload) = std::env::var_os("LD_PRELOAD") {
@@ -84,53 +240,677 @@ impl CurlInjectOpt {
 
 		if debug {
 			eprintln!("curl-inject-opt: debug is on");
-			if let Some(err) = curl_easy_perform.as_ref().err() {
-				eprintln!("curl-inject-opt: {}", err);
-			}
-			if let Some(err) = curl_easy_setopt.as_ref().err() {
-				eprintln!("curl-inject-opt: {}", err);
-			}
-			if let Some(err) = curl_multi_add_handle.as_ref().err() {
-				eprintln!("curl-inject-opt: {}", err);
+			for symbol in LIBCURL.symbols() {
+				if let Err(e) = symbol.address() {
+					eprintln!("curl-inject-opt: {}", e);
+				}
 			}
 		}
 
-		let result = Self {
-			curl_easy_perform: curl_easy_perform?,
-			curl_easy_setopt: curl_easy_setopt?,
-			curl_multi_add_handle: curl_multi_add_handle?,
+		Self {
 			options,
+			https,
+			network,
+			tls,
+			audit,
+			extra_ca,
+			request_log,
+			header_log,
+			summary,
+			metrics,
+			tracer,
+			signer,
+			capture,
+			trace,
+			har,
+			replay,
+			transfers: AtomicU64::new(0),
+			handles: HandleTable::default(),
+			apply_once,
+			strict,
+			strict_multi_code,
+			init_error,
 			debug,
+		}
+	}
+
+	/// Check a value set by the application against the TLS policy.
+	///
+	/// Returns a replacement if the value must be fixed, or an error code if it must be rejected.
+	///
+	/// # Safety
+	/// The argument must be valid for the option.
+	unsafe fn check_tls(&self, option: CURLoption, arg: Arg) -> Result<Option<tls::Fixed>, CURLcode> {
+		if !self.tls.is_enabled() {
+			return Ok(None);
+		}
+		let Some(insecure) = self.tls.check(option, arg) else {
+			return Ok(None);
+		};
+		match self.tls.action(insecure.check) {
+			tls::Action::Off => Ok(None),
+			tls::Action::Warn => {
+				eprintln!("curl-inject-opt: warning: insecure TLS setting: {}", insecure.message);
+				Ok(None)
+			},
+			tls::Action::Fix => {
+				if self.debug {
+					eprintln!("curl-inject-opt: fixing insecure TLS setting: {}", insecure.message);
+				}
+				Ok(Some(insecure.fixed))
+			},
+			tls::Action::Reject => {
+				eprintln!("curl-inject-opt: rejecting insecure TLS setting: {}", insecure.message);
+				Err(curl_sys::CURLE_BAD_FUNCTION_ARGUMENT)
+			},
+		}
+	}
+
+	/// Remember insecure TLS settings of the application for the audit report.
+	///
+	/// # Safety
+	/// The argument must be valid for the option.
+	unsafe fn audit_setopt(&self, handle: *mut CURL, option: CURLoption, arg: Arg) {
+		if !self.audit.is_enabled() {
+			return;
+		}
+		let insecure = self.tls.check(option, arg);
+		self.handles.with(handle, |state| match insecure {
+			Some(insecure) => state.insecure.insert(option, (insecure.option, insecure.check)),
+			None           => state.insecure.remove(&option),
+		});
+	}
+
+	/// Record insecure uses of libcurl for a transfer that is about to start.
+	fn audit_transfer(&self, handle: *mut CURL, url: Option<&UrlParts>) {
+		if !self.audit.is_enabled() {
+			return;
+		}
+		let host = url.map(|x| x.host.as_str());
+		let plaintext = url.is_some_and(|x| x.scheme == "http");
+		let (insecure, credentials, httpheader, protocols_restricted) = self.handles.with(handle, |state| {
+			state.https_transfer = url.is_some_and(|x| x.scheme == "https");
+			(state.insecure.values().copied().collect::<Vec<_>>(), state.credentials.clone(), state.httpheader, state.protocols_restricted)
+		});
+
+		for (option, check) in insecure {
+			self.audit.record(host, option, check.description());
+		}
+		if plaintext {
+			for option in credentials {
+				self.audit.record(host, option, "credentials sent over plaintext HTTP");
+			}
+			// SAFETY: libcurl requires the list to stay valid until the transfer is done.
+			if unsafe { setopt::slist_items(httpheader as *const curl_sys::curl_slist) }.any(|x| has_name(x.to_bytes(), "authorization")) {
+				self.audit.record(host, "CURLOPT_HTTPHEADER", "Authorization header sent over plaintext HTTP");
+			}
+		}
+		if !protocols_restricted {
+			self.audit.record(host, "CURLOPT_PROTOCOLS", "all protocols allowed");
+		}
+	}
+
+	/// Remember what the application set on a handle.
+	fn record_setopt(&self, handle: *mut CURL, option: CURLoption, arg: Arg) {
+		match (option, arg) {
+			(curl_sys::CURLOPT_URL, Arg::Pointer(url)) => self.handles.with(handle, |state| {
+				// SAFETY: libcurl accepted the option, so the pointer is a valid string or null.
+				state.url = (!url.is_null()).then(|| unsafe { CStr::from_ptr(url.cast()) }.to_owned());
+			}),
+			(curl::CURLOPT_CURLU, Arg::Pointer(curlu)) => self.handles.with(handle, |state| state.curlu = curlu as usize),
+			(curl::CURLOPT_PREREQFUNCTION, Arg::Function(prereq)) => self.handles.with(handle, |state| state.prereq = prereq),
+			(curl::CURLOPT_PREREQDATA, Arg::Pointer(data)) => self.handles.with(handle, |state| state.prereq_data = data as usize),
+			(curl_sys::CURLOPT_WRITEFUNCTION, Arg::Function(write)) => self.handles.with(handle, |state| state.write = write),
+			(curl_sys::CURLOPT_WRITEDATA, Arg::Pointer(data)) => self.handles.with(handle, |state| state.write_data = data as usize),
+			(curl_sys::CURLOPT_READFUNCTION, Arg::Function(read)) => self.handles.with(handle, |state| state.read = read),
+			(curl_sys::CURLOPT_READDATA, Arg::Pointer(data)) => self.handles.with(handle, |state| state.read_data = data as usize),
+			(curl_sys::CURLOPT_HEADERFUNCTION, Arg::Function(header)) => self.handles.with(handle, |state| state.header = header),
+			(curl_sys::CURLOPT_HEADERDATA, Arg::Pointer(data)) => self.handles.with(handle, |state| state.header_data = data as usize),
+			(curl_sys::CURLOPT_DEBUGFUNCTION, Arg::Function(debug)) => self.handles.with(handle, |state| state.debug = debug),
+			(curl_sys::CURLOPT_VERBOSE, Arg::Long(verbose)) => self.handles.with(handle, |state| state.verbose = verbose != 0),
+			(curl_sys::CURLOPT_FOLLOWLOCATION, Arg::Long(follow)) => self.handles.with(handle, |state| state.follow_location = follow != 0),
+			(curl_sys::CURLOPT_STDERR, Arg::Pointer(file)) => self.handles.with(handle, |state| state.stderr = file as usize),
+			(curl_sys::CURLOPT_CAINFO, Arg::Pointer(path)) => self.handles.with(handle, |state| {
+				// SAFETY: libcurl accepted the option, so the pointer is a valid string or null.
+				state.cainfo = (!path.is_null()).then(|| unsafe { CStr::from_ptr(path.cast()) }.to_owned());
+			}),
+			(curl_sys::CURLOPT_CAINFO_BLOB, Arg::Blob(blob)) => self.handles.with(handle, |state| {
+				// SAFETY: libcurl accepted the option, so the pointer is a valid blob or null.
+				state.cainfo_blob = unsafe { blob.as_ref() }.map(|x| unsafe { std::slice::from_raw_parts(x.data.cast::<u8>(), x.len) }.to_vec());
+			}),
+			(curl_sys::CURLOPT_HTTPHEADER, Arg::Pointer(list)) => self.handles.with(handle, |state| state.httpheader = list as usize),
+			(curl_sys::CURLOPT_PROTOCOLS, Arg::Long(protocols)) => self.handles.with(handle, |state| {
+				state.protocols_restricted = protocols != !0;
+			}),
+			(curl::CURLOPT_PROTOCOLS_STR, Arg::Pointer(protocols)) => self.handles.with(handle, |state| {
+				// SAFETY: libcurl accepted the option, so the pointer is a valid string or null.
+				state.protocols_restricted = !protocols.is_null() && !unsafe { CStr::from_ptr(protocols.cast()) }.to_bytes().eq_ignore_ascii_case(b"all");
+			}),
+			(option, Arg::Pointer(value)) => {
+				if let Some((_, name)) = CREDENTIAL_OPTIONS.iter().find(|(x, _)| *x == option) {
+					self.handles.with(handle, |state| match value.is_null() {
+						true  => state.credentials.remove(name),
+						false => state.credentials.insert(name),
+					});
+				}
+			},
+			_ => (),
+		}
+
+		if self.signer.is_enabled() || self.capture.is_enabled() || self.har.is_enabled() || self.replay.is_enabled() {
+			self.record_request(handle, option, arg);
+		}
+
+		// Save values for options that rule blocks may override, so we can restore them when the rule does not match anymore.
+		// Also save values that our options are merged with.
+		let scoped = self.options.blocks.iter().flat_map(|x| &x.options).any(|x| x.option.option == option);
+		let merged = self.options.options.iter().any(|x| x.option.option == option && x.option.merge);
+		if let (true, Some(meta)) = (scoped || merged, OPTIONS.iter().find(|x| x.option == option)) {
+			// SAFETY: libcurl accepted the option, so the argument is valid for the option.
+			let saved = unsafe { Saved::new(meta.kind, arg) };
+			self.handles.with(handle, |state| state.saved.insert(option, saved));
+		}
+	}
+
+	/// Track the method and body of the requests of a handle, for signing, capturing, the HAR file and replay.
+	fn record_request(&self, handle: *mut CURL, option: CURLoption, arg: Arg) {
+		self.handles.with(handle, |state| {
+			let request = &mut state.request;
+			match (option, arg) {
+				(curl_sys::CURLOPT_HTTPGET, Arg::Long(1..)) => request.kind = RequestKind::Get,
+				(curl_sys::CURLOPT_POST, Arg::Long(post)) if post != 0 => request.kind = RequestKind::Post,
+				(curl_sys::CURLOPT_UPLOAD, Arg::Long(upload)) if upload != 0 => request.kind = RequestKind::Put,
+				// Turning off POST or upload does not turn off CURLOPT_NOBODY, which curl does for `curl -I`.
+				(curl_sys::CURLOPT_POST | curl_sys::CURLOPT_UPLOAD, Arg::Long(_)) if request.kind != RequestKind::Head => request.kind = RequestKind::Get,
+				(curl_sys::CURLOPT_NOBODY, Arg::Long(nobody)) => {
+					if nobody != 0 {
+						request.kind = RequestKind::Head;
+					} else if request.kind == RequestKind::Head {
+						request.kind = RequestKind::Get;
+					}
+				},
+				(curl_sys::CURLOPT_HTTPPOST | curl::CURLOPT_MIMEPOST, Arg::Pointer(form)) => {
+					request.kind = if form.is_null() { RequestKind::Get } else { RequestKind::PostForm };
+				},
+				(curl_sys::CURLOPT_CUSTOMREQUEST, Arg::Pointer(method)) => {
+					// SAFETY: libcurl accepted the option, so the pointer is a valid string or null.
+					request.custom_method = (!method.is_null()).then(|| unsafe { CStr::from_ptr(method.cast()) }.to_owned());
+				},
+				(curl_sys::CURLOPT_POSTFIELDSIZE, Arg::Long(size)) => request.fields_size = usize::try_from(size).ok(),
+				(curl_sys::CURLOPT_POSTFIELDSIZE_LARGE, Arg::OffT(size)) => request.fields_size = usize::try_from(size).ok(),
+				(curl_sys::CURLOPT_POSTFIELDS, Arg::Pointer(data)) => {
+					request.kind = RequestKind::Post;
+					request.fields = (!data.is_null()).then_some(PostFields::Pointer(data as usize));
+				},
+				(curl_sys::CURLOPT_COPYPOSTFIELDS, Arg::Pointer(data)) => {
+					request.kind = RequestKind::Post;
+					// SAFETY: libcurl accepted the option, so the pointer is valid for the size that libcurl copied too.
+					request.fields = (!data.is_null()).then(|| PostFields::Copy(Arc::new(unsafe { post_fields(data as usize, request.fields_size) }.to_vec())));
+				},
+				_ => (),
+			}
+		});
+	}
+
+	/// Call the real `curl_easy_setopt()`, reporting errors.
+	fn setopt(&self, library: &Library, handle: *mut CURL, option: CURLoption, arg: Arg, what: impl std::fmt::Display) -> CURLcode {
+		let Some(curl_easy_setopt) = next_fn(&library.curl_easy_setopt) else {
+			return curl_sys::CURLE_FAILED_INIT;
+		};
+		let code = setopt::call(curl_easy_setopt, handle, option, arg);
+		if code != curl_sys::CURLE_OK {
+			error_policy::report(format_args!("failed to {}: error {}", what, code));
+		}
+		code
+	}
+
+	/// Get the URL for the next transfer of a handle, and apply the rewrite rules and the HTTPS policy to it.
+	///
+	/// A rewritten URL is set with CURLOPT_URL, and the URL of the application is restored if a later transfer is not rewritten.
+	/// Returns the URL of the transfer, if it is needed for rules, and the first error that occurred.
+	/// Returns an error if the transfer must be refused, regardless of strict mode.
+	fn prepare_url(&self, library: &Library, handle: *mut CURL) -> Result<(Option<UrlParts>, CURLcode), CURLcode> {
+		let needs_url = !self.options.blocks.is_empty() || self.options.has_rewrites() || self.https.is_enabled() || self.network.is_enabled() || self.options.has_headers() || self.audit.is_enabled();
+		if !needs_url {
+			return Ok((None, curl_sys::CURLE_OK));
+		}
+
+		let (url, curlu, rewritten) = self.handles.with(handle, |state| (state.url.clone(), state.curlu, state.rewritten));
+		let url = match curlu {
+			0     => url,
+			curlu => library.url_from_curlu(curlu as *mut curl::CURLU).map_err(error_policy::report).ok(),
+		};
+		let Some(url) = url else {
+			return Ok((None, curl_sys::CURLE_OK));
 		};
+		let url = url.to_string_lossy();
 
-		Ok(result)
+		let mut new_url = self.options.rewrite_url(&url);
+		let mut parts = UrlParts::parse(new_url.as_deref().unwrap_or(&url)).map_err(error_policy::report).ok();
+		if let Some(current) = &parts {
+			match self.https.check(new_url.as_deref().unwrap_or(&url), current) {
+				https::Verdict::Allow => (),
+				https::Verdict::Upgrade(upgraded) => {
+					parts = UrlParts::parse(&upgraded).map_err(error_policy::report).ok();
+					new_url = Some(upgraded);
+				},
+				https::Verdict::Refuse => {
+					error_policy::report(format_args!("refusing plaintext transfer to {}", new_url.as_deref().unwrap_or(&url)));
+					return Err(curl_sys::CURLE_UNSUPPORTED_PROTOCOL);
+				},
+			}
+		}
+
+		if let Some(current) = &parts {
+			if !self.network.allows(current) {
+				error_policy::report(format_args!("denied transfer to {} by network policy", new_url.as_deref().unwrap_or(&url)));
+				self.handles.with(handle, |state| state.denied = Some(self.network.code));
+				return Err(self.network.code);
+			}
+		}
+
+		let mut code = curl_sys::CURLE_OK;
+		let new_url = new_url.and_then(|new_url| {
+			CString::new(new_url).map_err(|_| error_policy::report(format_args!("rewritten URL for {} contains a null byte", url))).ok()
+		});
+		if let Some(new_url) = &new_url {
+			if self.debug {
+				eprintln!("curl-inject-opt: rewriting URL {} to {}", url, new_url.to_string_lossy());
+			}
+			// CURLOPT_CURLU takes precedence over CURLOPT_URL, so clear it.
+			if curlu != 0 {
+				code = self.setopt(library, handle, curl::CURLOPT_CURLU, Arg::Pointer(std::ptr::null_mut()), "clear CURLOPT_CURLU");
+			}
+			if code == curl_sys::CURLE_OK {
+				code = self.setopt(library, handle, curl_sys::CURLOPT_URL, Arg::String(new_url), "set rewritten URL");
+			}
+		} else if rewritten {
+			if self.debug {
+				eprintln!("curl-inject-opt: restoring URL {}", url);
+			}
+			code = match curlu {
+				0     => self.setopt(library, handle, curl_sys::CURLOPT_URL, Arg::String(&CString::new(url.as_bytes()).unwrap_or_default()), "restore URL"),
+				curlu => self.setopt(library, handle, curl::CURLOPT_CURLU, Arg::Pointer(curlu as *mut c_void), "restore CURLOPT_CURLU"),
+			};
+		}
+		self.handles.with(handle, |state| state.rewritten = new_url.is_some());
+
+		Ok((parts, code))
 	}
 
-	fn set_option(&self, handle: *mut CURL, option: &SetOption) -> CURLcode {
+	/// Restore the value set by the application for an option, or the default value if it never set one.
+	fn restore_option(&self, library: &Library, handle: *mut CURL, option: CURLoption) {
+		let Some(saved) = self.handles.with(handle, |state| state.saved.get(&option).cloned()).or_else(|| Saved::default_for(option)) else {
+			return;
+		};
+		let name = OPTIONS.iter().find(|x| x.option == option).map_or("unknown", |x| x.name);
 		if self.debug {
-			eprintln!("curl-inject-opt: setting option {}: {}", option.name, option.value);
+			eprintln!("curl-inject-opt: restoring option {}", name);
 		}
-		let code = match &option.value {
-			Value::CString(x) => (self.curl_easy_setopt)(handle, option.option, x.as_ref() as *const CStr),
-			Value::CLong(x)   => (self.curl_easy_setopt)(handle, option.option, *x),
+		self.setopt(library, handle, option, saved.arg(), format_args!("restore option {}", name));
+	}
+
+	fn set_option(&self, library: &Library, handle: *mut CURL, option: &PreparedOption) -> CURLcode {
+		if option.option.merge {
+			return self.set_merged_option(library, handle, option);
+		}
+		if self.debug {
+			eprintln!("curl-inject-opt: setting option {}: {}", option.option.name, option.option.value);
+		}
+		let Some(curl_easy_setopt) = next_fn(&library.curl_easy_setopt) else {
+			return curl_sys::CURLE_FAILED_INIT;
 		};
+		let code = option.set(curl_easy_setopt, handle);
 		if code != curl_sys::CURLE_OK {
-			eprintln!("curl-inject-opt: failed to set option {}: error {}", option.name, code);
+			error_policy::report(format_args!("failed to set option {}: error {}", option.option.name, code));
 		}
 		code
 	}
 
-	fn set_options(&self, handle: *mut CURL) {
-		for option in &self.options {
-			self.set_option(handle, option);
+	/// Set an option to the value set by the application merged with our value, without duplicates.
+	fn set_merged_option(&self, library: &Library, handle: *mut CURL, option: &PreparedOption) -> CURLcode {
+		let SetOption { name, option, ref value, .. } = option.option;
+		let saved = self.handles.with(handle, |state| state.saved.get(&option).cloned());
+		match value {
+			Value::CString(ours) => {
+				let separator = OPTIONS.iter().find(|x| x.option == option).and_then(|x| x.list_separator()).unwrap_or(b',');
+				let application = match &saved {
+					Some(Saved::String(x)) => x.as_bytes(),
+					_                      => b"",
+				};
+				// Neither list contains a null byte, so the merged list does not either.
+				let merged = CString::new(merge_list(separator, application, ours.as_bytes())).unwrap_or_default();
+				if self.debug {
+					eprintln!("curl-inject-opt: setting option {}: {}", name, merged.to_string_lossy());
+				}
+				self.setopt(library, handle, option, Arg::String(&merged), format_args!("set option {}", name))
+			},
+			Value::CSlist(ours) => {
+				let application = match saved {
+					Some(Saved::Pointer(x)) => x as *const curl_sys::curl_slist,
+					_                       => std::ptr::null(),
+				};
+				// SAFETY: the application must keep its list alive for as long as libcurl may use it.
+				let mut items: Vec<CString> = unsafe { setopt::slist_items(application) }.map(CStr::to_owned).collect();
+				for item in ours {
+					if !items.contains(item) {
+						items.push(item.clone());
+					}
+				}
+				if self.debug {
+					eprintln!("curl-inject-opt: setting option {}: {}", name, Value::CSlist(items.clone()));
+				}
+				let merged = Arc::new(Slist::new(items));
+				let code = self.setopt(library, handle, option, Arg::Slist(merged.as_ptr()), format_args!("set option {}", name));
+				// Only drop the previous list after libcurl stopped using it.
+				let _previous = self.handles.with(handle, |state| state.merged.insert(option, merged));
+				code
+			},
+			_ => {
+				error_policy::report(format_args!("option {} can not be merged", name));
+				curl_sys::CURLE_BAD_FUNCTION_ARGUMENT
+			},
+		}
+	}
+
+	/// Answer a transfer of `curl_easy_perform()` with a recorded response, if replay is enabled.
+	///
+	/// The status line and headers go to the header callback of the application, and the body to its write callback.
+	/// The body is also captured and recorded in the HAR file, like the body of a transfer that runs.
+	fn replay_transfer(&self, library: &Library, handle: *mut CURL) -> Perform {
+		if !self.replay.is_enabled() {
+			return Perform::Run;
+		}
+		self.handles.with(handle, |state| state.replayed = None);
+		let (kind, method, url, body) = self.replay_request(library, handle);
+		let normalize = |url: &str| normalize_url(library, url);
+		let body = body.as_deref();
+
+		let Some(response) = self.replay.find(&method, &url, body, normalize) else {
+			if self.replay.unmatched == Unmatched::Pass {
+				return Perform::Run;
+			}
+			error_policy::report(format_args!("no recorded response for {} {}", method, request_log::redact_url(&url)));
+			return Perform::Refused(curl_sys::CURLE_COULDNT_CONNECT);
+		};
+		if self.debug {
+			eprintln!("curl-inject-opt: replaying response {} for {} {}", response.status, method, url);
+		}
+
+		// Set the result first, so the callbacks can already ask for the response code.
+		// libcurl reports the normalized URL, like `http://host/` for `http://host`.
+		let head = kind == RequestKind::Head;
+		let url = normalize(&url);
+		let replayed = Replayed {
+			scheme: CString::new(url.split_once("://").map_or("", |(scheme, _)| scheme)).unwrap_or_default(),
+			url: CString::new(url).unwrap_or_default(),
+			method: CString::new(method).unwrap_or_default(),
+			content_type: response.header("Content-Type").and_then(|x| CString::new(x).ok()),
+			bytes_down: if head { 0 } else { response.body.len() },
+			response: response.clone(),
+		};
+		let captured = self.capture.start(handle).map(Arc::new);
+		let har = self.har.start().map(Arc::new);
+		if let Some(body) = body.filter(|x| !x.is_empty()) {
+			captured.iter().for_each(|x| x.request(body));
+			har.iter().for_each(|x| x.request(body));
+		}
+		let (header, header_data, write, write_data) = self.handles.with(handle, |state| {
+			state.replayed = Some(Arc::new(replayed));
+			state.captured = captured.clone();
+			state.har = har.clone();
+			(state.header, state.header_data, state.write, state.write_data)
+		});
+
+		for mut line in response.header_lines() {
+			har.iter().for_each(|x| x.response_header(&line));
+			// SAFETY: The buffer is valid for its length, and the application set the callbacks and user data.
+			if unsafe { forward_header(header, header_data, write, line.as_mut_ptr().cast(), 1, line.len()) } != line.len() {
+				return Perform::Replayed(curl_sys::CURLE_WRITE_ERROR);
+			}
+		}
+		if !head {
+			for chunk in response.body.chunks(curl::CURL_MAX_WRITE_SIZE) {
+				captured.iter().for_each(|x| x.response(chunk));
+				har.iter().for_each(|x| x.response(chunk));
+				let mut chunk = chunk.to_vec();
+				// SAFETY: The buffer is valid for its length, and the application set the callback and user data.
+				if unsafe { forward_write(write, chunk.as_mut_ptr().cast(), 1, chunk.len(), write_data) } != chunk.len() {
+					return Perform::Replayed(curl_sys::CURLE_WRITE_ERROR);
+				}
+			}
+		}
+		Perform::Replayed(curl_sys::CURLE_OK)
+	}
+
+	/// Get the request of a handle with its method, URL and body, to find its recorded response.
+	///
+	/// The URL is rewritten like it would be for the transfer.
+	/// The body is `None` if it is not known before the transfer runs.
+	fn replay_request(&self, library: &Library, handle: *mut CURL) -> (RequestKind, String, String, Option<Vec<u8>>) {
+		let (url, curlu, request) = self.handles.with(handle, |state| (state.url.clone(), state.curlu, state.request.clone()));
+		let url = match curlu {
+			0     => url,
+			curlu => library.url_from_curlu(curlu as *mut curl::CURLU).map_err(error_policy::report).ok(),
+		};
+		let url = url.map_or_else(String::new, |x| x.to_string_lossy().into_owned());
+		let url = self.options.rewrite_url(&url).unwrap_or(url);
+		let body = match (request.kind, &request.fields) {
+			(RequestKind::Get | RequestKind::Head, _)            => Some(Vec::new()),
+			(RequestKind::Post, Some(PostFields::Copy(data)))    => Some(data.to_vec()),
+			// SAFETY: libcurl requires the data of CURLOPT_POSTFIELDS to stay valid until the transfer is done.
+			(RequestKind::Post, Some(PostFields::Pointer(data))) => Some(unsafe { post_fields(*data, request.fields_size) }.to_vec()),
+			_                                                    => None,
+		};
+		(request.kind, request.method(), url, body)
+	}
+
+	/// Set all options on a handle.
+	///
+	/// In strict mode, returns the error code of the first option that could not be set.
+	fn set_options(&self, library: &'static Library, handle: *mut CURL) -> Result<(), CURLcode> {
+		if let Some(e) = self.init_error.as_ref().filter(|_| self.strict) {
+			error_policy::report(format_args!("refusing to start transfer because the configuration could not be loaded: {}", e));
+			return Err(curl_sys::CURLE_FAILED_INIT);
+		}
+
+		// URLs are rewritten and checked, and rules are evaluated for every transfer, even with apply-once.
+		self.handles.with(handle, |state| state.denied = None);
+		let (url, url_code) = self.prepare_url(library, handle)?;
+		let mut result = match url_code {
+			curl_sys::CURLE_OK => Ok(()),
+			code               => Err(code),
+		};
+
+		self.audit_transfer(handle, url.as_ref());
+
+		if self.network.is_enabled() || self.https.is_enabled() || self.audit.is_enabled() {
+			let code = self.install_prereq(library, handle);
+			if code != curl_sys::CURLE_OK && result.is_ok() {
+				result = Err(code);
+			}
+		}
+
+		// Every transfer gets its own span, even with apply-once.
+		let trace_headers = match self.tracer.is_enabled() {
+			true  => {
+				let span = self.tracer.start_span();
+				let rules = self.tracer.header_rules(&span);
+				self.handles.with(handle, |state| state.span = Some(span));
+				rules
+			},
+			false => Vec::new(),
+		};
+
+		// With apply-once, the options are only set for the first transfer, but our callbacks are installed for every transfer.
+		let applied = self.apply_once && self.handles.mark_applied(handle);
+		if applied {
+			if self.debug {
+				eprintln!("curl-inject-opt: options already set on handle");
+			}
+		} else {
+			if self.debug && !self.options.blocks.is_empty() {
+				match &url {
+					Some(url) => eprintln!("curl-inject-opt: matching rules against {}://{}{}", url.scheme, url.host, url.path),
+					None      => eprintln!("curl-inject-opt: URL of transfer is unknown, skipping rules"),
+				}
+			}
+			let options: Vec<_> = self.options.options_for(url.as_ref()).collect();
+
+			// Restore options that a rule set for the previous transfer, but not for this one.
+			let previous = self.handles.with(handle, |state| std::mem::take(&mut state.scoped));
+			for option in previous {
+				if !options.iter().any(|(_, x)| x.option.option == option) {
+					self.restore_option(library, handle, option);
+				}
+			}
+
+			let mut scoped = Vec::new();
+			for (block, option) in options {
+				if block.is_some() {
+					scoped.push(option.option.option);
+				}
+				let code = self.set_option(library, handle, option);
+				if code != curl_sys::CURLE_OK && result.is_ok() {
+					result = Err(code);
+				}
+			}
+			self.handles.with(handle, |state| state.scoped = scoped);
+		}
+
+		// The merged CA bundle must be set after the options, so it overrides an injected CURLOPT_CAINFO_BLOB.
+		let installed = [
+			self.install_headers(library, handle, url.as_ref(), &trace_headers),
+			self.install_ca(library, handle, url.as_ref()),
+			self.install_body_callbacks(library, handle),
+			self.install_header_callback(library, handle, url.as_ref()),
+			self.install_debug_callback(library, handle, url.as_ref()),
+		];
+		for code in installed {
+			if code != curl_sys::CURLE_OK && result.is_ok() {
+				result = Err(code);
+			}
+		}
+
+		if !self.strict {
+			return Ok(());
+		}
+
+		if result.is_err() && !applied {
+			// Try again on the next transfer.
+			self.handles.with(handle, |state| state.applied = false);
+			error_policy::report("refusing to start transfer because not all options could be set");
+		}
+		result
+	}
+
+	/// Called when a transfer finished, either from `curl_easy_perform()` or from a message of `curl_multi_info_read()`.
+	fn transfer_done(&self, library: &Library, handle: *mut CURL, result: CURLcode) {
+		let (span, captured, har, response_headers, replayed) = self.handles.with(handle, |state| {
+			(state.span.take(), state.captured.take(), state.har.take(), std::mem::take(&mut state.response_headers), state.replayed.clone())
+		});
+		// Headers without a terminating empty line, such as the responses of protocols other than HTTP, are logged when the transfer is done.
+		if !response_headers.is_empty() {
+			if let Err(e) = self.header_log.write(&(response_headers + "\n")) {
+				error_policy::report(e);
+			}
+		}
+		if !self.request_log.is_enabled() && !self.summary.is_enabled() && !self.metrics.is_enabled() && span.is_none() && captured.is_none() && har.is_none() {
+			return;
+		}
+		let transfer = match replayed {
+			Some(replayed) => replayed.transfer(result),
+			None           => request_log::Transfer::from_handle(library, handle, result),
+		};
+		if let Err(e) = self.request_log.write(&transfer) {
+			error_policy::report(e);
+		}
+		if let Err(e) = self.summary.record(&transfer) {
+			error_policy::report(e);
+		}
+		self.metrics.record(&transfer);
+		if let Some(span) = span {
+			if let Err(e) = self.tracer.finish(&span, &transfer) {
+				error_policy::report(e);
+			}
+		}
+		if let Some(captured) = captured {
+			if let Err(e) = captured.finish(&transfer) {
+				error_policy::report(e);
+			}
+		}
+		if let Some(har) = har {
+			if let Err(e) = self.har.record(&har, &transfer) {
+				error_policy::report(e);
+			}
 		}
 	}
 }
 
-static INIT : LazyLock<Result<CurlInjectOpt, String>> = LazyLock::new(CurlInjectOpt::init);
+/// Normalize a URL like libcurl does, or keep it as it is if libcurl can not parse it.
+fn normalize_url(library: &Library, url: &str) -> String {
+	let normalized = CString::new(url).map_err(|e| e.to_string()).and_then(|x| library.normalize_url(&x));
+	normalized.map_or_else(|_| url.to_string(), |x| x.to_string_lossy().into_owned())
+}
+
+/// Get the data of CURLOPT_POSTFIELDS, with the size from CURLOPT_POSTFIELDSIZE, or up to the null terminator if no size was set.
+///
+/// # Safety
+/// The address must point to valid data of the given size, or to a null-terminated string if there is no size.
+unsafe fn post_fields<'a>(address: usize, size: Option<usize>) -> &'a [u8] {
+	match size {
+		Some(size) => std::slice::from_raw_parts(address as *const u8, size),
+		None       => CStr::from_ptr(address as *const c_char).to_bytes(),
+	}
+}
+
+/// How `curl_easy_perform()` handles a transfer.
+enum Perform {
+	/// Run the transfer with libcurl.
+	Run,
+
+	/// The transfer was answered with a recorded response, with this result.
+	Replayed(CURLcode),
+
+	/// The transfer is refused with this error code, without running it.
+	Refused(CURLcode),
+}
+
+static INIT : LazyLock<CurlInjectOpt> = LazyLock::new(CurlInjectOpt::init);
+
+/// Options that hold credentials, with their names.
+const CREDENTIAL_OPTIONS: [(CURLoption, &str); 4] = [
+	(curl_sys::CURLOPT_USERPWD,    "CURLOPT_USERPWD"),
+	(curl_sys::CURLOPT_USERNAME,   "CURLOPT_USERNAME"),
+	(curl_sys::CURLOPT_PASSWORD,   "CURLOPT_PASSWORD"),
+	(curl::CURLOPT_XOAUTH2_BEARER, "CURLOPT_XOAUTH2_BEARER"),
+];
+
+/// Run the part of a hook that needs the global state.
+///
+/// Panics are caught and reported according to the error policy, so they never unwind into the C caller.
+fn with_state<R>(fun: impl FnOnce(&CurlInjectOpt) -> R) -> Option<R> {
+	match std::panic::catch_unwind(AssertUnwindSafe(|| fun(&INIT))) {
+		Ok(x)  => Some(x),
+		Err(_) => {
+			error_policy::report("internal error while injecting options");
+			None
+		},
+	}
+}
+
+/// Write the results that are kept until the process exits, registered with `atexit()`.
+///
+/// This writes the audit report and the final metrics, and waits for the queued spans to be sent to the OTLP collector.
+extern "C" fn at_exit() {
+	with_state(|init| {
+		let results = [init.audit.write_report(), init.metrics.write_textfile(), init.tracer.flush()];
+		results.into_iter().filter_map(Result::err).for_each(error_policy::report);
+	});
+}
 
 extern "C" fn initialize() {
-	let _ = &*INIT;
+	with_state(|_| ());
 }
 
 #[used]
@@ -140,34 +920,556 @@ extern "C" fn initialize() {
 #[cfg_attr(target_os = "windows", link_section = ".CRT$XCU")]
 pub static init_curl_inject_opt: extern "C" fn() = initialize;
 
-#[no_mangle]
-pub extern "C" fn curl_easy_perform(handle: *mut CURL) -> CURLcode {
-	let init = match &*INIT {
-		Err(string) => panic!("{}", string),
-		Ok(init)    => init,
+fn curl_easy_init(library: &Library) -> *mut CURL {
+	let Some(next) = next_fn(&library.curl_easy_init) else {
+		return std::ptr::null_mut();
+	};
+
+	let handle = next();
+	with_state(|init| init.handles.insert(handle));
+	handle
+}
+
+fn curl_easy_setopt(library: &Library, handle: *mut CURL, option: CURLoption, arg: Arg) -> CURLcode {
+	let Some(next) = next_fn(&library.curl_easy_setopt) else {
+		return curl_sys::CURLE_FAILED_INIT;
+	};
+
+	// Insecure TLS settings are refused or replaced before libcurl sees them.
+	// SAFETY: The argument comes straight from the application, so it is as valid as libcurl requires.
+	let fixed = with_state(|init| unsafe {
+		init.audit_setopt(handle, option, arg);
+		init.check_tls(option, arg)
+	});
+	let fixed = match fixed {
+		Some(Err(code)) => return code,
+		Some(Ok(fixed)) => fixed,
+		None            => None,
+	};
+	let arg = match &fixed {
+		Some(tls::Fixed::Long(value))   => Arg::Long(*value),
+		Some(tls::Fixed::String(value)) => Arg::Pointer(value.as_ref().map_or(std::ptr::null_mut(), |x| x.as_ptr().cast_mut().cast())),
+		None                            => arg,
 	};
 
-	if init.debug {
-		eprintln!("curl-inject-opt: curl_easy_perform() called");
+	let code = setopt::call(next, handle, option, arg);
+	if code == curl_sys::CURLE_OK {
+		with_state(|init| init.record_setopt(handle, option, arg));
 	}
+	code
+}
 
-	// Set options, then delegate to the real handler.
-	init.set_options(handle);
-	(init.curl_easy_perform)(handle)
+/// Called by the hooks for `curl_easy_setopt()` in `hooks.c`.
+///
+/// # Safety
+/// The argument must have been read with `va_arg()` using the argument type encoded in the option ID.
+#[no_mangle]
+pub unsafe extern "C" fn curl_inject_opt_curl_easy_setopt_hook(library: c_int, handle: *mut CURL, option: CURLoption, arg: *const setopt::RawArg) -> CURLcode {
+	let Some(library) = usize::try_from(library).ok().and_then(|x| LIBRARIES.get(x)) else {
+		return curl_sys::CURLE_FAILED_INIT;
+	};
+	let Some(arg) = Arg::from_raw(option, &*arg) else {
+		return curl_sys::CURLE_UNKNOWN_OPTION;
+	};
+	curl_easy_setopt(library, handle, option, arg)
 }
 
+fn curl_easy_getinfo(library: &Library, handle: *mut CURL, info: curl_sys::CURLINFO, arg: *mut c_void) -> CURLcode {
+	let Some(next) = next_fn(&library.curl_easy_getinfo) else {
+		return curl_sys::CURLE_FAILED_INIT;
+	};
+
+	// Replayed transfers never ran, so libcurl does not know their response.
+	let replayed = with_state(|init| {
+		init.replay.is_enabled().then(|| init.handles.with(handle, |state| state.replayed.clone())).flatten()
+	});
+	// SAFETY: The application passes a pointer of the type that libcurl uses for the info.
+	if let Some(code) = replayed.flatten().and_then(|x| unsafe { x.getinfo(info, arg) }) {
+		return code;
+	}
+	next(handle, info, arg)
+}
+
+/// Called by the hooks for `curl_easy_getinfo()` in `hooks.c`.
+///
+/// # Safety
+/// The argument must point to a value of the type that libcurl uses for the info.
 #[no_mangle]
-pub extern "C" fn curl_multi_add_handle(multi_handle: *mut CURLM, handle: *mut CURL) -> CURLMcode {
-	let init = match &*INIT {
-		Err(string) => panic!("{}", string),
-		Ok(init)    => init,
+pub unsafe extern "C" fn curl_inject_opt_curl_easy_getinfo_hook(library: c_int, handle: *mut CURL, info: curl_sys::CURLINFO, arg: *mut c_void) -> CURLcode {
+	let Some(library) = usize::try_from(library).ok().and_then(|x| LIBRARIES.get(x)) else {
+		return curl_sys::CURLE_FAILED_INIT;
+	};
+	curl_easy_getinfo(library, handle, info, arg)
+}
+
+fn curl_easy_reset(library: &Library, handle: *mut CURL) {
+	let Some(next) = next_fn(&library.curl_easy_reset) else {
+		return;
+	};
+
+	// Resetting a handle clears all options, so they need to be set again.
+	next(handle);
+	with_state(|init| {
+		if init.debug {
+			eprintln!("curl-inject-opt: curl_easy_reset() called");
+		}
+		init.handles.reset(handle);
+	});
+}
+
+fn curl_easy_duphandle(library: &Library, handle: *mut CURL) -> *mut CURL {
+	let Some(next) = next_fn(&library.curl_easy_duphandle) else {
+		return std::ptr::null_mut();
+	};
+
+	// The duplicate inherits all options of the original, including the ones we set.
+	let duplicate = next(handle);
+	with_state(|init| {
+		if init.debug {
+			eprintln!("curl-inject-opt: curl_easy_duphandle() called");
+		}
+		init.handles.duplicate(handle, duplicate);
+
+		// The duplicate gets its own copy of the CURLU handle, so remember the URL instead.
+		let curlu = init.handles.with(handle, |state| state.curlu);
+		if curlu != 0 && !duplicate.is_null() {
+			let url = library.url_from_curlu(curlu as *mut curl::CURLU).map_err(error_policy::report).ok();
+			init.handles.with(duplicate, |state| {
+				state.url = url;
+				state.curlu = 0;
+			});
+		}
+	});
+	duplicate
+}
+
+fn curl_easy_cleanup(library: &Library, handle: *mut CURL) {
+	let Some(next) = next_fn(&library.curl_easy_cleanup) else {
+		return;
+	};
+
+	// libcurl still calls the callbacks while it closes the connections of the handle, so they need its state.
+	next(handle);
+	with_state(|init| init.handles.remove(handle));
+}
+
+extern "C" {
+	static stdin: *mut libc::FILE;
+	static stdout: *mut libc::FILE;
+	static stderr: *mut libc::FILE;
+}
+
+/// Call a write callback of the application, or `fwrite()` if it did not set one, like libcurl does.
+///
+/// # Safety
+/// The buffer must be valid for `size * nitems` bytes, and the callback and user data must have been set by the application.
+unsafe fn forward_write(write: Option<setopt::Function>, buffer: *mut c_char, size: usize, nitems: usize, data: usize) -> usize {
+	match write {
+		Some(write) => {
+			let write = std::mem::transmute::<setopt::Function, curl::CurlWriteCallback>(write);
+			write(buffer, size, nitems, data as *mut c_void)
+		},
+		None => {
+			let file = if data == 0 { stdout } else { data as *mut libc::FILE };
+			libc::fwrite(buffer.cast(), size, nitems, file)
+		},
+	}
+}
+
+/// Call the header callback of the application, or pass the header on to where libcurl sends it without one.
+///
+/// Without a header callback, libcurl passes headers to the write callback with CURLOPT_HEADERDATA, if it is set.
+///
+/// # Safety
+/// The buffer must be valid for `size * nitems` bytes, and the callbacks and user data must have been set by the application.
+unsafe fn forward_header(header: Option<setopt::Function>, header_data: usize, write: Option<setopt::Function>, buffer: *mut c_char, size: usize, nitems: usize) -> usize {
+	match header {
+		Some(header) => {
+			let header = std::mem::transmute::<setopt::Function, curl::CurlWriteCallback>(header);
+			header(buffer, size, nitems, header_data as *mut c_void)
+		},
+		None if header_data != 0 => forward_write(write, buffer, size, nitems, header_data),
+		None => size * nitems,
+	}
+}
+
+fn curl_easy_perform(library: &'static Library, handle: *mut CURL) -> CURLcode {
+	let Some(next) = next_fn(&library.curl_easy_perform) else {
+		return curl_sys::CURLE_FAILED_INIT;
 	};
 
-	if init.debug {
-		eprintln!("curl-inject-opt: curl_multi_add_handle() called");
+	// Answer the transfer with a recorded response, or set options and delegate to the real handler.
+	let perform = with_state(|init| {
+		if init.debug {
+			eprintln!("curl-inject-opt: curl_easy_perform() called");
+		}
+		match init.replay_transfer(library, handle) {
+			Perform::Run => match init.set_options(library, handle) {
+				Ok(())    => Perform::Run,
+				Err(code) => Perform::Refused(code),
+			},
+			perform => perform,
+		}
+	});
+
+	match perform {
+		Some(Perform::Refused(code)) => return code,
+		Some(Perform::Replayed(code)) => {
+			with_state(|init| init.transfer_done(library, handle, code));
+			return code;
+		},
+		Some(Perform::Run) | None => (),
 	}
+	with_state(|init| init.handles.with(handle, |state| state.performing = true));
+	let code = next(handle);
+	with_state(|init| init.handles.with(handle, |state| state.performing = false));
+
+	// Report transfers that our callbacks aborted with the error code of the policy that denied them.
+	let mut code = code;
+	if code != curl_sys::CURLE_OK {
+		if let Some(Some(denied)) = with_state(|init| init.handles.with(handle, |state| state.denied.take())) {
+			code = denied;
+		}
+	}
+
+	with_state(|init| init.transfer_done(library, handle, code));
+	code
+}
+
+fn curl_multi_add_handle(library: &'static Library, multi_handle: *mut CURLM, handle: *mut CURL) -> CURLMcode {
+	let Some(next) = next_fn(&library.curl_multi_add_handle) else {
+		return curl_sys::CURLM_INTERNAL_ERROR;
+	};
 
 	// Set options, then delegate to the real handler.
-	init.set_options(handle);
-	(init.curl_multi_add_handle)(multi_handle, handle)
+	// Options were already set if `curl_easy_perform()` adds the handle to its internal multi handle.
+	let result = with_state(|init| {
+		if init.debug {
+			eprintln!("curl-inject-opt: curl_multi_add_handle() called");
+		}
+		if init.handles.with(handle, |state| state.performing) {
+			return Ok(());
+		}
+		// Transfers of the multi interface are not replayed, so only those without recorded response may run, if unmatched transfers pass.
+		if init.replay.is_enabled() {
+			init.handles.with(handle, |state| state.replayed = None);
+			let (_, method, url, body) = init.replay_request(library, handle);
+			if init.replay.unmatched == Unmatched::Fail || init.replay.has_response(&method, &url, body.as_deref(), |x| normalize_url(library, x)) {
+				error_policy::report(format_args!("refusing transfer {} {}: replay is not supported for the multi interface", method, request_log::redact_url(&url)));
+				return Err(init.strict_multi_code);
+			}
+		}
+		match init.set_options(library, handle) {
+			Ok(()) => Ok(()),
+			// Denied transfers still run, so they can fail with the error code of the policy.
+			Err(_) if init.handles.with(handle, |state| state.denied.is_some()) => match init.fail_denied(library, handle) {
+				curl_sys::CURLE_OK => Ok(()),
+				_ => Err(init.strict_multi_code),
+			},
+			Err(_) => Err(init.strict_multi_code),
+		}
+	});
+
+	match result {
+		Some(Err(code)) => code,
+		_ => next(multi_handle, handle),
+	}
+}
+
+fn curl_multi_info_read(library: &Library, multi_handle: *mut CURLM, msgs_in_queue: *mut c_int) -> *mut CURLMsg {
+	let Some(next) = next_fn(&library.curl_multi_info_read) else {
+		return std::ptr::null_mut();
+	};
+
+	// Each finished transfer is reported exactly once through a CURLMSG_DONE message.
+	let message = next(multi_handle, msgs_in_queue);
+	// SAFETY: libcurl returned a valid message or null, and the message stays valid until the next call.
+	if let Some(done) = unsafe { message.as_mut() }.filter(|x| x.msg == curl_sys::CURLMSG_DONE) {
+		// SAFETY: For CURLMSG_DONE messages, the data is a union that holds the result code.
+		let result = unsafe { &mut *std::ptr::addr_of_mut!(done.data).cast::<CURLcode>() };
+		with_state(|init| {
+			// Transfers of curl_easy_perform() are reported when it returns.
+			let (performing, denied) = init.handles.with(done.easy_handle, |state| match state.performing {
+				true  => (true, None),
+				false => (false, state.denied.take()),
+			});
+			// Report transfers that a policy denied with its error code.
+			if let Some(denied) = denied.filter(|_| *result != curl_sys::CURLE_OK) {
+				*result = denied;
+			}
+			if !performing {
+				init.transfer_done(library, done.easy_handle, *result);
+			}
+		});
+	}
+	message
+}
+
+/// Export the hooks for one flavour of libcurl.
+///
+/// The exported functions are given the name of the original function with the given symbol version.
+/// The `.symver` directives must end up in the same object file as the functions,
+/// so each invocation should be in its own module.
+macro_rules! export_hooks {
+	(
+		$library:path => $version:literal {
+			curl_easy_init: $curl_easy_init:ident,
+			curl_easy_setopt: $curl_easy_setopt:ident,
+			curl_easy_getinfo: $curl_easy_getinfo:ident,
+			curl_easy_perform: $curl_easy_perform:ident,
+			curl_easy_reset: $curl_easy_reset:ident,
+			curl_easy_duphandle: $curl_easy_duphandle:ident,
+			curl_easy_cleanup: $curl_easy_cleanup:ident,
+			curl_multi_add_handle: $curl_multi_add_handle:ident,
+			curl_multi_info_read: $curl_multi_info_read:ident $(,)?
+		}
+	) => {
+		use super::*;
+
+		// Defined in hooks.c, since they are variadic.
+		extern "C" {
+			fn $curl_easy_setopt(handle: *mut CURL, option: curl_sys::CURLoption, ...) -> CURLcode;
+			fn $curl_easy_getinfo(handle: *mut CURL, info: curl_sys::CURLINFO, ...) -> CURLcode;
+		}
+
+		// Nothing calls the hooks from hooks.c, so refer to them to make the linker include them.
+		#[used]
+		static HOOKS_C: (
+			unsafe extern "C" fn(*mut CURL, curl_sys::CURLoption, ...) -> CURLcode,
+			unsafe extern "C" fn(*mut CURL, curl_sys::CURLINFO, ...) -> CURLcode,
+		) = ($curl_easy_setopt, $curl_easy_getinfo);
+
+		#[no_mangle]
+		pub extern "C" fn $curl_easy_init() -> *mut CURL {
+			super::curl_easy_init(&$library)
+		}
+
+		#[no_mangle]
+		pub extern "C" fn $curl_easy_perform(handle: *mut CURL) -> CURLcode {
+			super::curl_easy_perform(&$library, handle)
+		}
+
+		#[no_mangle]
+		pub extern "C" fn $curl_easy_reset(handle: *mut CURL) {
+			super::curl_easy_reset(&$library, handle)
+		}
+
+		#[no_mangle]
+		pub extern "C" fn $curl_easy_duphandle(handle: *mut CURL) -> *mut CURL {
+			super::curl_easy_duphandle(&$library, handle)
+		}
+
+		#[no_mangle]
+		pub extern "C" fn $curl_easy_cleanup(handle: *mut CURL) {
+			super::curl_easy_cleanup(&$library, handle)
+		}
+
+		#[no_mangle]
+		pub extern "C" fn $curl_multi_add_handle(multi_handle: *mut CURLM, handle: *mut CURL) -> CURLMcode {
+			super::curl_multi_add_handle(&$library, multi_handle, handle)
+		}
+
+		#[no_mangle]
+		pub extern "C" fn $curl_multi_info_read(multi_handle: *mut CURLM, msgs_in_queue: *mut c_int) -> *mut CURLMsg {
+			super::curl_multi_info_read(&$library, multi_handle, msgs_in_queue)
+		}
+
+		// Unit tests link libcurl directly, so the hooks must not take over its symbols there.
+		#[cfg(not(test))]
+		std::arch::global_asm!(
+			concat!(".symver ", stringify!($curl_easy_init),        ", curl_easy_init",        $version),
+			concat!(".symver ", stringify!($curl_easy_perform),     ", curl_easy_perform",     $version),
+			concat!(".symver ", stringify!($curl_easy_reset),       ", curl_easy_reset",       $version),
+			concat!(".symver ", stringify!($curl_easy_duphandle),   ", curl_easy_duphandle",   $version),
+			concat!(".symver ", stringify!($curl_easy_cleanup),     ", curl_easy_cleanup",     $version),
+			concat!(".symver ", stringify!($curl_multi_add_handle), ", curl_multi_add_handle", $version),
+			concat!(".symver ", stringify!($curl_multi_info_read),  ", curl_multi_info_read",  $version),
+		);
+	};
+}
+
+// The hooks for curl_easy_setopt() and curl_easy_getinfo() from hooks.c, see the comment there.
+#[cfg_attr(not(test), link(name = "curl_inject_opt_hooks", kind = "static"))]
+#[cfg_attr(test, link(name = "curl_inject_opt_hooks_unversioned", kind = "static"))]
+extern "C" {}
+
+// The unversioned hooks must get a default symbol version too.
+// Otherwise the dynamic linker also accepts them for references to a specific version.
+mod unversioned {
+	export_hooks!(curl::LIBCURL => "@@CURL_INJECT_OPT" {
+		curl_easy_init: curl_inject_opt_curl_easy_init,
+		curl_easy_setopt: curl_inject_opt_curl_easy_setopt,
+		curl_easy_getinfo: curl_inject_opt_curl_easy_getinfo,
+		curl_easy_perform: curl_inject_opt_curl_easy_perform,
+		curl_easy_reset: curl_inject_opt_curl_easy_reset,
+		curl_easy_duphandle: curl_inject_opt_curl_easy_duphandle,
+		curl_easy_cleanup: curl_inject_opt_curl_easy_cleanup,
+		curl_multi_add_handle: curl_inject_opt_curl_multi_add_handle,
+		curl_multi_info_read: curl_inject_opt_curl_multi_info_read,
+	});
+}
+
+mod openssl_4 {
+	export_hooks!(curl::LIBCURL_OPENSSL_4 => "@CURL_OPENSSL_4" {
+		curl_easy_init: curl_inject_opt_openssl_4_curl_easy_init,
+		curl_easy_setopt: curl_inject_opt_openssl_4_curl_easy_setopt,
+		curl_easy_getinfo: curl_inject_opt_openssl_4_curl_easy_getinfo,
+		curl_easy_perform: curl_inject_opt_openssl_4_curl_easy_perform,
+		curl_easy_reset: curl_inject_opt_openssl_4_curl_easy_reset,
+		curl_easy_duphandle: curl_inject_opt_openssl_4_curl_easy_duphandle,
+		curl_easy_cleanup: curl_inject_opt_openssl_4_curl_easy_cleanup,
+		curl_multi_add_handle: curl_inject_opt_openssl_4_curl_multi_add_handle,
+		curl_multi_info_read: curl_inject_opt_openssl_4_curl_multi_info_read,
+	});
+}
+
+mod gnutls_3 {
+	export_hooks!(curl::LIBCURL_GNUTLS_3 => "@CURL_GNUTLS_3" {
+		curl_easy_init: curl_inject_opt_gnutls_3_curl_easy_init,
+		curl_easy_setopt: curl_inject_opt_gnutls_3_curl_easy_setopt,
+		curl_easy_getinfo: curl_inject_opt_gnutls_3_curl_easy_getinfo,
+		curl_easy_perform: curl_inject_opt_gnutls_3_curl_easy_perform,
+		curl_easy_reset: curl_inject_opt_gnutls_3_curl_easy_reset,
+		curl_easy_duphandle: curl_inject_opt_gnutls_3_curl_easy_duphandle,
+		curl_easy_cleanup: curl_inject_opt_gnutls_3_curl_easy_cleanup,
+		curl_multi_add_handle: curl_inject_opt_gnutls_3_curl_multi_add_handle,
+		curl_multi_info_read: curl_inject_opt_gnutls_3_curl_multi_info_read,
+	});
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use curl_inject_opt_shared::Rewrite;
+	use crate::testing::{TempPath, fake_handle};
+
+	#[test]
+	fn test_strict_refuses_transfers_after_init_error() {
+		let mut init = CurlInjectOpt::init();
+		init.strict = true;
+		init.init_error = HttpsPolicy::parse("sometimes", "").err();
+
+		// The transfer is refused before the handle is used.
+		assert!(init.init_error.is_some());
+		assert!(init.set_options(&curl::LIBCURL, fake_handle(1)) == Err(curl_sys::CURLE_FAILED_INIT));
+	}
+
+	/// Serve a redirect to `/landed` on a local port, and record the paths that were requested.
+	fn serve_redirect() -> (u16, Arc<Mutex<Vec<String>>>) {
+		use std::io::{BufRead, Write};
+		let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let port = listener.local_addr().unwrap().port();
+		let requested = Arc::new(Mutex::new(Vec::new()));
+		let paths = requested.clone();
+		std::thread::spawn(move || {
+			for stream in listener.incoming() {
+				let mut stream = std::io::BufReader::new(stream.unwrap());
+				let mut line = String::new();
+				if stream.read_line(&mut line).unwrap_or(0) == 0 {
+					continue;
+				}
+				paths.lock().unwrap().push(line.split(' ').nth(1).unwrap_or_default().to_string());
+				let location = format!("http://127.0.0.1:{}/landed", port);
+				let response = format!("HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", location);
+				stream.get_mut().write_all(response.as_bytes()).ok();
+			}
+		});
+		(port, requested)
+	}
+
+	/// Perform a transfer with our CURLOPT_PREREQFUNCTION check, but without any other hooks.
+	fn perform_checked(init: &CurlInjectOpt, url: &str) -> (CURLcode, Option<CURLcode>) {
+		extern "C" fn prereq(data: *mut c_void, primary_ip: *mut c_char, _: *mut c_char, _: c_int, _: c_int) -> c_int {
+			// SAFETY: The user data points to the state and the handle on the stack of perform_checked().
+			let (init, handle) = unsafe { *data.cast::<(&CurlInjectOpt, *mut CURL)>() };
+			// SAFETY: libcurl passes the address as a null-terminated string.
+			let address = unsafe { CStr::from_ptr(primary_ip) }.to_string_lossy();
+			match init.check_prereq(handle, &address) {
+				true  => curl::CURL_PREREQFUNC_OK,
+				false => curl::CURL_PREREQFUNC_ABORT,
+			}
+		}
+		let prereq: curl::CurlPrereqCallback = prereq;
+		// SAFETY: Function pointers all have the same representation.
+		let prereq = unsafe { std::mem::transmute::<curl::CurlPrereqCallback, setopt::Function>(prereq) };
+
+		// The functions of libcurl are looked up with RTLD_NEXT, which only finds libraries loaded after the test binary.
+		// SAFETY: Loading libcurl runs no code that depends on us.
+		assert!(!unsafe { libc::dlopen(c"libcurl.so.4".as_ptr(), libc::RTLD_NOW | libc::RTLD_GLOBAL) }.is_null());
+		let library = &curl::LIBCURL;
+		let handle = library.curl_easy_init.get().unwrap()();
+		init.handles.insert(handle);
+		let mut data = (init, handle);
+		let url = CString::new(url).unwrap();
+		assert!(init.install_prereq(library, handle) == curl_sys::CURLE_OK);
+		assert!(init.setopt(library, handle, curl::CURLOPT_PREREQFUNCTION, Arg::Function(Some(prereq)), "test") == curl_sys::CURLE_OK);
+		assert!(init.setopt(library, handle, curl::CURLOPT_PREREQDATA, Arg::Pointer((&mut data as *mut (&CurlInjectOpt, *mut CURL)).cast()), "test") == curl_sys::CURLE_OK);
+		assert!(init.setopt(library, handle, curl_sys::CURLOPT_URL, Arg::String(&url), "test") == curl_sys::CURLE_OK);
+		assert!(init.setopt(library, handle, curl_sys::CURLOPT_FOLLOWLOCATION, Arg::Long(1), "test") == curl_sys::CURLE_OK);
+		let code = library.curl_easy_perform.get().unwrap()(handle);
+		let denied = init.handles.remove(handle).and_then(|x| x.denied);
+		library.curl_easy_cleanup.get().unwrap()(handle);
+		(code, denied)
+	}
+
+	#[test]
+	fn test_require_https_refuses_plaintext_redirects() {
+		let (port, requested) = serve_redirect();
+		let mut init = CurlInjectOpt::init();
+		init.https = HttpsPolicy::parse("require", &format!("127.0.0.1:{}/to", port)).unwrap();
+		let (code, denied) = perform_checked(&init, &format!("http://127.0.0.1:{}/to", port));
+		assert!(code == curl_sys::CURLE_ABORTED_BY_CALLBACK);
+		assert!(denied == Some(curl_sys::CURLE_UNSUPPORTED_PROTOCOL));
+		assert!(*requested.lock().unwrap() == ["/to"]);
+	}
+
+	#[test]
+	fn test_replay_matches_recorded_transfers() {
+		extern "C" fn write(buffer: *mut c_char, size: usize, nitems: usize, data: *mut c_void) -> usize {
+			// SAFETY: The user data points to the vector on the stack of the test, and libcurl passes a valid buffer.
+			unsafe { (*data.cast::<Vec<u8>>()).extend_from_slice(std::slice::from_raw_parts(buffer.cast(), size * nitems)) };
+			size * nitems
+		}
+		let write: curl::CurlWriteCallback = write;
+		// SAFETY: Function pointers all have the same representation.
+		let write = unsafe { std::mem::transmute::<curl::CurlWriteCallback, setopt::Function>(write) };
+
+		// Record a transfer like --har does, with the URL that libcurl reports after the transfer.
+		let path = TempPath::new("replay");
+		let har = Har::new(path.to_path_buf(), 1024);
+		let recording = har.start().unwrap();
+		recording.response_header(b"HTTP/1.1 200 OK\r\n");
+		recording.response_header(b"Content-Type: text/plain\r\n");
+		recording.response_header(b"\r\n");
+		recording.response(b"recorded");
+		let transfer = request_log::Transfer {
+			url: Some(String::from("http://127.0.0.1:18080/")),
+			method: Some(String::from("GET")),
+			response_code: Some(200),
+			..request_log::Transfer::default()
+		};
+		har.record(&recording, &transfer).unwrap();
+
+		// SAFETY: Loading libcurl runs no code that depends on us.
+		assert!(!unsafe { libc::dlopen(c"libcurl.so.4".as_ptr(), libc::RTLD_NOW | libc::RTLD_GLOBAL) }.is_null());
+		let library = &curl::LIBCURL;
+		let mut init = CurlInjectOpt::init();
+		init.replay = Replay::new(path.to_path_buf(), Unmatched::Fail);
+		init.options.rewrites.push(Rewrite::prefix("http://staging.invalid:8080", "http://127.0.0.1:18080"));
+
+		// The program sets a URL without path, and one that only matches after the rewrite.
+		// The effective URL is normalized like libcurl does, which keeps the fragment.
+		for (url, effective) in [(c"http://127.0.0.1:18080", "http://127.0.0.1:18080/"), (c"http://staging.invalid:8080/#top", "http://127.0.0.1:18080/#top")] {
+			let handle = fake_handle(1);
+			let mut body = Vec::<u8>::new();
+			init.handles.insert(handle);
+			init.record_setopt(handle, curl_sys::CURLOPT_URL, Arg::Pointer(url.as_ptr().cast_mut().cast()));
+			init.record_setopt(handle, curl_sys::CURLOPT_WRITEFUNCTION, Arg::Function(Some(write)));
+			init.record_setopt(handle, curl_sys::CURLOPT_WRITEDATA, Arg::Pointer((&mut body as *mut Vec<u8>).cast()));
+			assert!(matches!(init.replay_transfer(library, handle), Perform::Replayed(curl_sys::CURLE_OK)));
+			assert_eq!(body, b"recorded");
+			let transfer = init.handles.remove(handle).and_then(|x| x.replayed).unwrap().transfer(curl_sys::CURLE_OK);
+			assert_eq!((transfer.url.as_deref(), transfer.response_code, transfer.bytes_down), (Some(effective), Some(200), Some(8)));
+		}
+	}
 }
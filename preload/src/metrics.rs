@@ -0,0 +1,387 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use curl_inject_opt_shared::reexports::curl_sys;
+
+use curl_sys::CURLcode;
+
+use crate::lock_ignore_poison;
+use crate::request_log::Transfer;
+
+/// The upper bounds of the buckets of the transfer duration histogram, in seconds.
+const DURATION_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// The upper bounds of the buckets of the download size histogram, in bytes.
+const SIZE_BUCKETS: [f64; 8] = [1024.0, 16384.0, 65536.0, 262144.0, 1048576.0, 4194304.0, 16777216.0, 67108864.0];
+
+/// The default interval for rewriting the Prometheus textfile.
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Exports metrics of finished transfers as a Prometheus textfile, over StatsD, or both.
+#[derive(Default)]
+pub struct Metrics {
+	/// The path of the Prometheus textfile, where `%p` is replaced by the process ID.
+	textfile: Option<String>,
+
+	/// How often to rewrite the textfile while the process runs, or `None` to write it only at exit.
+	interval: Option<Duration>,
+
+	/// The StatsD server.
+	statsd: Option<Statsd>,
+
+	/// The executable of the process, for the `exe` label.
+	executable: String,
+
+	/// The metrics collected by this process.
+	state: Arc<Mutex<State>>,
+}
+
+/// A StatsD server, connected on the first transfer.
+///
+/// Resolving the address can block, so processes that never run a transfer must not do it.
+struct Statsd {
+	/// The address of the server, as `host:port`.
+	address: String,
+
+	/// A UDP socket connected to the server, or `None` if connecting failed.
+	socket: OnceLock<Option<UdpSocket>>,
+}
+
+/// The metrics collected by one process.
+#[derive(Default)]
+struct State {
+	/// The process that collected the metrics, so a forked child starts from scratch.
+	pid: u32,
+
+	/// If true, the thread that rewrites the textfile is running in this process.
+	writer: bool,
+
+	/// The metrics per host.
+	hosts: BTreeMap<String, HostMetrics>,
+}
+
+/// The metrics of all transfers to one host.
+#[derive(Clone, Debug, Default)]
+struct HostMetrics {
+	/// The number of transfers per `CURLcode`.
+	results: BTreeMap<CURLcode, u64>,
+
+	/// The total number of bytes uploaded.
+	bytes_up: u64,
+
+	/// The total number of bytes downloaded.
+	bytes_down: u64,
+
+	/// The duration of transfers in seconds.
+	duration: Histogram,
+
+	/// The number of bytes downloaded per transfer.
+	download_size: Histogram,
+}
+
+/// A Prometheus histogram.
+#[derive(Clone, Debug, Default)]
+struct Histogram {
+	/// The number of observations per bucket, not cumulative, with one extra bucket for `+Inf`.
+	buckets: Vec<u64>,
+
+	/// The sum of all observations.
+	sum: f64,
+
+	/// The number of observations.
+	count: u64,
+}
+
+impl Metrics {
+	/// Get the configuration from the CURL_INJECT_OPT_METRICS_TEXTFILE, CURL_INJECT_OPT_METRICS_INTERVAL and CURL_INJECT_OPT_STATSD environment variables.
+	pub fn from_env() -> Result<Self, String> {
+		let textfile = std::env::var_os("CURL_INJECT_OPT_METRICS_TEXTFILE").filter(|x| !x.is_empty());
+		let textfile = textfile.map(|x| x.into_string().map_err(|x| format!("metrics textfile path is not valid UTF-8: {}", x.to_string_lossy()))).transpose()?;
+
+		let interval = match std::env::var("CURL_INJECT_OPT_METRICS_INTERVAL") {
+			Ok(x)  => match x.parse::<u64>() {
+				Ok(0)  => None,
+				Ok(x)  => Some(Duration::from_secs(x)),
+				Err(_) => return Err(format!("invalid value for CURL_INJECT_OPT_METRICS_INTERVAL: {}", x)),
+			},
+			Err(_) => Some(DEFAULT_INTERVAL),
+		};
+
+		let statsd = std::env::var("CURL_INJECT_OPT_STATSD").ok().filter(|x| !x.is_empty());
+		let statsd = statsd.map(|address| Statsd { address, socket: OnceLock::new() });
+
+		Ok(Self {
+			textfile,
+			interval,
+			statsd,
+			executable: std::env::current_exe().map_or_else(|_| String::from("-"), |x| x.display().to_string()),
+			state: Arc::default(),
+		})
+	}
+
+	/// Check if metrics are exported.
+	pub fn is_enabled(&self) -> bool {
+		self.textfile.is_some() || self.statsd.is_some()
+	}
+
+	/// Record a finished transfer.
+	pub fn record(&self, transfer: &Transfer) {
+		let host = transfer.host();
+		if let Some(socket) = self.statsd.as_ref().and_then(Statsd::socket) {
+			// StatsD is fire and forget: a server that is down must not affect the program.
+			let _ = socket.send(statsd_packet(&self.executable, &host, transfer).as_bytes());
+		}
+
+		if self.textfile.is_none() {
+			return;
+		}
+		let mut state = lock_ignore_poison(&self.state);
+		state.reset_after_fork();
+		let metrics = state.hosts.entry(host).or_default();
+		*metrics.results.entry(transfer.result).or_default() += 1;
+		metrics.bytes_up += transfer.bytes_up.unwrap_or(0).max(0) as u64;
+		metrics.bytes_down += transfer.bytes_down.unwrap_or(0).max(0) as u64;
+		if let Some(total) = transfer.total {
+			metrics.duration.observe(&DURATION_BUCKETS, total as f64 / 1e6);
+		}
+		if let Some(bytes_down) = transfer.bytes_down {
+			metrics.download_size.observe(&SIZE_BUCKETS, bytes_down as f64);
+		}
+
+		if !state.writer {
+			if let Some(interval) = self.interval {
+				state.writer = self.spawn_writer(interval);
+			}
+		}
+	}
+
+	/// Write the Prometheus textfile, if this process finished any transfers.
+	///
+	/// The file is written to a temporary file first and then renamed, so the collector never reads a partial file.
+	pub fn write_textfile(&self) -> Result<(), String> {
+		let Some(textfile) = &self.textfile else {
+			return Ok(());
+		};
+		write_textfile(textfile, &self.executable, &self.state)
+	}
+
+	/// Start a thread that rewrites the textfile periodically.
+	fn spawn_writer(&self, interval: Duration) -> bool {
+		let Some(textfile) = self.textfile.clone() else {
+			return false;
+		};
+		let executable = self.executable.clone();
+		let state = self.state.clone();
+		let thread = std::thread::Builder::new()
+			.name(String::from("curl-inject-opt-metrics"))
+			.spawn(move || loop {
+				std::thread::sleep(interval);
+				if let Err(e) = write_textfile(&textfile, &executable, &state) {
+					crate::error_policy::report(e);
+				}
+			});
+		thread.is_ok()
+	}
+}
+
+impl Statsd {
+	/// Get the socket, connecting to the server on first use.
+	fn socket(&self) -> Option<&UdpSocket> {
+		self.socket.get_or_init(|| {
+			connect_statsd(&self.address)
+				.map_err(|e| crate::error_policy::report(format_args!("failed to connect to StatsD server {}: {}", self.address, e)))
+				.ok()
+		}).as_ref()
+	}
+}
+
+impl State {
+	/// Forget the metrics of the parent process in a forked child.
+	///
+	/// The thread that rewrites the textfile does not survive a fork either.
+	fn reset_after_fork(&mut self) {
+		let pid = std::process::id();
+		if self.pid != pid {
+			*self = Self { pid, ..Self::default() };
+		}
+	}
+
+	/// Encode the metrics in the Prometheus text format.
+	fn to_textfile(&self, executable: &str) -> String {
+		let mut output = String::new();
+		let labels = |host: &str| format!("exe=\"{}\",host=\"{}\"", escape_label(executable), escape_label(host));
+
+		output += "# HELP curl_inject_opt_transfers_total Finished libcurl transfers by CURLcode result.\n";
+		output += "# TYPE curl_inject_opt_transfers_total counter\n";
+		for (host, metrics) in &self.hosts {
+			for (result, count) in &metrics.results {
+				let _ = writeln!(output, "curl_inject_opt_transfers_total{{{},result=\"{}\"}} {}", labels(host), result, count);
+			}
+		}
+
+		output += "# HELP curl_inject_opt_uploaded_bytes_total Bytes uploaded by libcurl transfers.\n";
+		output += "# TYPE curl_inject_opt_uploaded_bytes_total counter\n";
+		for (host, metrics) in &self.hosts {
+			let _ = writeln!(output, "curl_inject_opt_uploaded_bytes_total{{{}}} {}", labels(host), metrics.bytes_up);
+		}
+
+		output += "# HELP curl_inject_opt_downloaded_bytes_total Bytes downloaded by libcurl transfers.\n";
+		output += "# TYPE curl_inject_opt_downloaded_bytes_total counter\n";
+		for (host, metrics) in &self.hosts {
+			let _ = writeln!(output, "curl_inject_opt_downloaded_bytes_total{{{}}} {}", labels(host), metrics.bytes_down);
+		}
+
+		output += "# HELP curl_inject_opt_transfer_duration_seconds Total time of libcurl transfers.\n";
+		output += "# TYPE curl_inject_opt_transfer_duration_seconds histogram\n";
+		for (host, metrics) in &self.hosts {
+			metrics.duration.write(&mut output, "curl_inject_opt_transfer_duration_seconds", &labels(host), &DURATION_BUCKETS);
+		}
+
+		output += "# HELP curl_inject_opt_download_size_bytes Bytes downloaded per libcurl transfer.\n";
+		output += "# TYPE curl_inject_opt_download_size_bytes histogram\n";
+		for (host, metrics) in &self.hosts {
+			metrics.download_size.write(&mut output, "curl_inject_opt_download_size_bytes", &labels(host), &SIZE_BUCKETS);
+		}
+
+		output
+	}
+}
+
+impl Histogram {
+	fn observe(&mut self, bounds: &[f64], value: f64) {
+		self.buckets.resize(bounds.len() + 1, 0);
+		let bucket = bounds.iter().position(|&bound| value <= bound).unwrap_or(bounds.len());
+		self.buckets[bucket] += 1;
+		self.sum += value;
+		self.count += 1;
+	}
+
+	fn write(&self, output: &mut String, name: &str, labels: &str, bounds: &[f64]) {
+		if self.count == 0 {
+			return;
+		}
+		let mut cumulative = 0;
+		for (bound, count) in bounds.iter().zip(&self.buckets) {
+			cumulative += count;
+			let _ = writeln!(output, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, bound, cumulative);
+		}
+		let _ = writeln!(output, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, self.count);
+		let _ = writeln!(output, "{}_sum{{{}}} {}", name, labels, self.sum);
+		let _ = writeln!(output, "{}_count{{{}}} {}", name, labels, self.count);
+	}
+}
+
+fn write_textfile(textfile: &str, executable: &str, state: &Mutex<State>) -> Result<(), String> {
+	let pid = std::process::id();
+	let output = {
+		let state = lock_ignore_poison(state);
+		// A forked child that did not finish a transfer yet has only the metrics of its parent.
+		if state.pid != pid || state.hosts.is_empty() {
+			return Ok(());
+		}
+		state.to_textfile(executable)
+	};
+
+	let path = PathBuf::from(textfile.replace("%p", &pid.to_string()));
+	let mut temporary = path.clone().into_os_string();
+	temporary.push(format!(".{}.tmp", pid));
+	std::fs::write(&temporary, output).map_err(|e| format!("failed to write metrics to {}: {}", temporary.to_string_lossy(), e))?;
+	std::fs::rename(&temporary, &path).map_err(|e| {
+		let _ = std::fs::remove_file(&temporary);
+		format!("failed to write metrics to {}: {}", path.display(), e)
+	})
+}
+
+fn connect_statsd(address: &str) -> std::io::Result<UdpSocket> {
+	let address = address.to_socket_addrs()?.next().ok_or_else(|| std::io::Error::other("no addresses found"))?;
+	let socket = match address {
+		std::net::SocketAddr::V4(_) => UdpSocket::bind(("0.0.0.0", 0))?,
+		std::net::SocketAddr::V6(_) => UdpSocket::bind(("::", 0))?,
+	};
+	socket.set_nonblocking(true)?;
+	socket.connect(address)?;
+	Ok(socket)
+}
+
+/// Encode the metrics of a transfer as StatsD lines with DogStatsD tags.
+fn statsd_packet(executable: &str, host: &str, transfer: &Transfer) -> String {
+	let tags = format!("exe:{},host:{}", escape_tag(executable), escape_tag(host));
+	let mut packet = format!("curl_inject_opt.transfers:1|c|#{},result:{}\n", tags, transfer.result);
+	if let Some(bytes_up) = transfer.bytes_up {
+		let _ = writeln!(packet, "curl_inject_opt.uploaded_bytes:{}|c|#{}", bytes_up, tags);
+	}
+	if let Some(bytes_down) = transfer.bytes_down {
+		let _ = writeln!(packet, "curl_inject_opt.downloaded_bytes:{}|c|#{}", bytes_down, tags);
+		let _ = writeln!(packet, "curl_inject_opt.download_size:{}|h|#{}", bytes_down, tags);
+	}
+	if let Some(total) = transfer.total {
+		let _ = writeln!(packet, "curl_inject_opt.transfer_duration:{}|ms|#{}", total as f64 / 1e3, tags);
+	}
+	packet.pop();
+	packet
+}
+
+/// Escape a Prometheus label value.
+fn escape_label(value: &str) -> String {
+	value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Replace the characters that have a meaning in a DogStatsD tag.
+fn escape_tag(value: &str) -> String {
+	value.replace([',', '|', '#', '\n'], "_")
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_textfile() {
+		let transfer = |result, bytes_down, total| Transfer {
+			url: Some(String::from("https://example.com/")),
+			result,
+			bytes_up: Some(10),
+			bytes_down: Some(bytes_down),
+			total: Some(total),
+			..Transfer::default()
+		};
+		let metrics = Metrics { textfile: Some(String::from("/nonexistent/%p.prom")), ..Metrics::default() };
+		metrics.record(&transfer(0, 2000, 30_000));
+		metrics.record(&transfer(28, 0, 10_000_000));
+		metrics.record(&transfer(0, 100, 20_000));
+
+		let output = lock_ignore_poison(&metrics.state).to_textfile("/usr/bin/\"agent\"");
+		let labels = r#"exe="/usr/bin/\"agent\"",host="example.com""#;
+		assert!(output.contains(&format!("curl_inject_opt_transfers_total{{{},result=\"0\"}} 2\n", labels)));
+		assert!(output.contains(&format!("curl_inject_opt_transfers_total{{{},result=\"28\"}} 1\n", labels)));
+		assert!(output.contains(&format!("curl_inject_opt_uploaded_bytes_total{{{}}} 30\n", labels)));
+		assert!(output.contains(&format!("curl_inject_opt_downloaded_bytes_total{{{}}} 2100\n", labels)));
+		assert!(output.contains(&format!("curl_inject_opt_transfer_duration_seconds_bucket{{{},le=\"0.025\"}} 1\n", labels)));
+		assert!(output.contains(&format!("curl_inject_opt_transfer_duration_seconds_bucket{{{},le=\"0.05\"}} 2\n", labels)));
+		assert!(output.contains(&format!("curl_inject_opt_transfer_duration_seconds_bucket{{{},le=\"10\"}} 3\n", labels)));
+		assert!(output.contains(&format!("curl_inject_opt_transfer_duration_seconds_count{{{}}} 3\n", labels)));
+		assert!(output.contains(&format!("curl_inject_opt_download_size_bytes_bucket{{{},le=\"1024\"}} 2\n", labels)));
+		assert!(output.contains(&format!("curl_inject_opt_download_size_bytes_sum{{{}}} 2100\n", labels)));
+	}
+
+	#[test]
+	fn test_statsd_packet() {
+		let transfer = Transfer {
+			url: Some(String::from("http://127.0.0.1:8080/")),
+			result: 7,
+			bytes_down: Some(0),
+			total: Some(1500),
+			..Transfer::default()
+		};
+		assert_eq!(statsd_packet("/bin/a,b", &transfer.host(), &transfer), [
+			"curl_inject_opt.transfers:1|c|#exe:/bin/a_b,host:127.0.0.1,result:7",
+			"curl_inject_opt.downloaded_bytes:0|c|#exe:/bin/a_b,host:127.0.0.1",
+			"curl_inject_opt.download_size:0|h|#exe:/bin/a_b,host:127.0.0.1",
+			"curl_inject_opt.transfer_duration:1.5|ms|#exe:/bin/a_b,host:127.0.0.1",
+		].join("\n"));
+	}
+}
@@ -0,0 +1,296 @@
+use std::ffi::{CStr, CString};
+use std::net::IpAddr;
+use std::os::raw::{c_char, c_int, c_void};
+
+use curl_inject_opt_shared::{Rule, UrlParts};
+use curl_inject_opt_shared::reexports::curl_sys;
+
+use curl_sys::CURL;
+use curl_sys::CURLcode;
+
+use crate::{with_state, CurlInjectOpt};
+use crate::curl::{self, Library};
+use crate::error_policy;
+use crate::setopt::{self, Arg};
+
+/// The policy that decides which hosts transfers may connect to.
+#[derive(Debug)]
+pub struct NetworkPolicy {
+	/// Only allow loopback hosts and `file://` URLs, in addition to the `allow` patterns.
+	pub offline: bool,
+
+	/// If not empty, only URLs matching one of these patterns are allowed.
+	pub allow: Vec<Rule>,
+
+	/// URLs matching one of these patterns are denied, even if they are also allowed.
+	pub deny: Vec<Rule>,
+
+	/// The error code for denied transfers.
+	pub code: CURLcode,
+}
+
+impl NetworkPolicy {
+	/// A policy that allows everything.
+	pub fn allow_all() -> Self {
+		Self {
+			offline: false,
+			allow: Vec::new(),
+			deny: Vec::new(),
+			code: curl_sys::CURLE_COULDNT_RESOLVE_HOST,
+		}
+	}
+
+	/// Parse the policy from comma separated lists of URL patterns and the name of the error code.
+	pub fn parse(offline: bool, allow: &str, deny: &str, code: &str) -> Result<Self, String> {
+		let code = if code.eq_ignore_ascii_case("resolve") {
+			curl_sys::CURLE_COULDNT_RESOLVE_HOST
+		} else if code.eq_ignore_ascii_case("timeout") {
+			curl_sys::CURLE_OPERATION_TIMEDOUT
+		} else {
+			return Err(format!("invalid error for denied transfers: {}, expected resolve or timeout", code));
+		};
+		Ok(Self {
+			offline,
+			allow: parse_patterns(allow)?,
+			deny: parse_patterns(deny)?,
+			code,
+		})
+	}
+
+	/// Get the policy from the CURL_INJECT_OPT_OFFLINE, CURL_INJECT_OPT_ALLOW_HOSTS, CURL_INJECT_OPT_DENY_HOSTS and CURL_INJECT_OPT_DENY_CODE environment variables.
+	pub fn from_env() -> Result<Self, String> {
+		let offline = crate::env_bool("CURL_INJECT_OPT_OFFLINE");
+		let allow = std::env::var("CURL_INJECT_OPT_ALLOW_HOSTS").unwrap_or_default();
+		let deny  = std::env::var("CURL_INJECT_OPT_DENY_HOSTS").unwrap_or_default();
+		let code  = std::env::var("CURL_INJECT_OPT_DENY_CODE").unwrap_or_else(|_| String::from("resolve"));
+		Self::parse(offline, &allow, &deny, &code)
+	}
+
+	/// Check if the policy does anything at all.
+	pub fn is_enabled(&self) -> bool {
+		self.offline || !self.allow.is_empty() || !self.deny.is_empty()
+	}
+
+	/// Check if a transfer to a URL is allowed.
+	pub fn allows(&self, url: &UrlParts) -> bool {
+		if self.deny.iter().any(|x| x.matches(url)) {
+			false
+		} else if self.offline && (url.scheme == "file" || is_loopback(&url.host)) {
+			true
+		} else if self.offline || !self.allow.is_empty() {
+			self.allow.iter().any(|x| x.matches(url))
+		} else {
+			true
+		}
+	}
+
+	/// Check if a transfer to an allowed URL may use the address that libcurl connected to.
+	///
+	/// In offline mode, a URL that is only allowed because its host is a loopback host must also be connected to over loopback,
+	/// so that CURLOPT_RESOLVE, CURLOPT_CONNECT_TO or a proxy can not send it elsewhere.
+	pub fn allows_address(&self, url: &UrlParts, address: &str) -> bool {
+		if !self.offline || url.scheme == "file" || self.allow.iter().any(|x| x.matches(url)) {
+			return true;
+		}
+		address.parse::<IpAddr>().is_ok_and(|x| x.to_canonical().is_loopback())
+	}
+}
+
+fn parse_patterns(patterns: &str) -> Result<Vec<Rule>, String> {
+	patterns.split(',')
+		.filter(|x| !x.is_empty())
+		.map(Rule::parse)
+		.collect()
+}
+
+/// Check if a host name or address always refers to the local machine.
+fn is_loopback(host: &str) -> bool {
+	let host = host.strip_suffix('.').unwrap_or(host).to_ascii_lowercase();
+	if host == "localhost" || host.ends_with(".localhost") {
+		return true;
+	}
+	host.parse::<IpAddr>().is_ok_and(|x| x.is_loopback())
+}
+
+impl CurlInjectOpt {
+	/// Install our CURLOPT_PREREQFUNCTION callback to check the network and HTTPS policies and audit redirects.
+	///
+	/// The callback of the application, if any, is called from ours.
+	pub fn install_prereq(&self, library: &'static Library, handle: *mut CURL) -> CURLcode {
+		self.handles.with(handle, |state| state.library = Some(library));
+		let code = self.setopt(library, handle, curl::CURLOPT_PREREQFUNCTION, Arg::Function(Some(prereq_callback_fn())), "set CURLOPT_PREREQFUNCTION");
+		if code != curl_sys::CURLE_OK {
+			return code;
+		}
+		self.setopt(library, handle, curl::CURLOPT_PREREQDATA, Arg::Pointer(handle.cast()), "set CURLOPT_PREREQDATA")
+	}
+
+	/// Check the network and HTTPS policies for the URL that a handle is about to send a request to, and audit redirects.
+	///
+	/// The URL is checked again because it changes when libcurl follows redirects,
+	/// and the address that libcurl connected to is checked against the offline mode.
+	pub fn check_prereq(&self, handle: *mut CURL, primary_ip: &str) -> bool {
+		let library = self.handles.with(handle, |state| state.library);
+		let url = library.ok_or_else(|| String::from("unknown handle")).and_then(|library| library.effective_url(handle));
+		if let Some(parts) = url.as_ref().ok().and_then(|x| UrlParts::parse(&x.to_string_lossy()).ok()) {
+			if parts.scheme == "http" && self.handles.with(handle, |state| state.https_transfer) {
+				self.audit.record(Some(&parts.host), "CURLOPT_FOLLOWLOCATION", "redirect from HTTPS to plaintext HTTP");
+			}
+		}
+		self.check_url(handle, url, Some(primary_ip))
+	}
+
+	/// Check the target of a redirect against the network and HTTPS policies before libcurl follows it.
+	///
+	/// Called at the end of the headers of every response, so a denied redirect never opens a connection.
+	/// The Location header is resolved against the current URL, like libcurl does when it follows it.
+	pub fn check_redirect(&self, handle: *mut CURL) -> bool {
+		let (library, follows, location) = self.handles.with(handle, |state| (state.library, state.follows_redirects, state.location.take()));
+		let (Some(library), true, Some(location)) = (library, follows, location) else {
+			return true;
+		};
+		if !library.info_long(handle, curl_sys::CURLINFO_RESPONSE_CODE).is_ok_and(|x| (300..400).contains(&x)) {
+			return true;
+		}
+		let url = library.effective_url(handle).and_then(|base| library.join_url(&base, &location));
+		self.check_url(handle, url, None)
+	}
+
+	/// Check a URL that a handle connects to against the network and HTTPS policies, and remember the error code if it is denied.
+	///
+	/// If the URL can not be determined, the transfer is denied, unless only the audit report looks at it.
+	pub fn check_url(&self, handle: *mut CURL, url: Result<CString, String>, primary_ip: Option<&str>) -> bool {
+		let url = url.map(|x| x.to_string_lossy().into_owned());
+		let parts = url.as_ref().map_err(String::clone).and_then(|x| UrlParts::parse(x));
+		let denied = match (&url, &parts) {
+			(Err(e), _) | (_, Err(e)) => {
+				if !self.network.is_enabled() && !self.https.is_enabled() {
+					error_policy::report(e);
+					return true;
+				}
+				error_policy::report(format_args!("denying transfer: {}", e));
+				match self.network.is_enabled() {
+					true  => self.network.code,
+					false => curl_sys::CURLE_UNSUPPORTED_PROTOCOL,
+				}
+			},
+			(Ok(url), Ok(parts)) => if !self.https.allows_redirect(parts) {
+				error_policy::report(format_args!("refusing plaintext transfer to {}", url));
+				curl_sys::CURLE_UNSUPPORTED_PROTOCOL
+			} else if !self.network.allows(parts) {
+				error_policy::report(format_args!("denied transfer to {} by network policy", url));
+				self.network.code
+			} else if primary_ip.is_some_and(|x| !self.network.allows_address(parts, x)) {
+				error_policy::report(format_args!("denied transfer to {} over {} by network policy", url, primary_ip.unwrap_or_default()));
+				self.network.code
+			} else {
+				return true;
+			},
+		};
+		self.handles.with(handle, |state| state.denied = Some(denied));
+		false
+	}
+
+	/// Make a transfer of the multi interface that a policy denied fail with the error code of the policy.
+	///
+	/// `curl_multi_add_handle()` can not return that code, so the URL is replaced by one with an unsupported scheme,
+	/// which fails before anything is resolved or connected, and `curl_multi_info_read()` reports the code of the policy instead.
+	/// The URL of the application is restored for the next transfer, like a rewritten URL.
+	pub fn fail_denied(&self, library: &Library, handle: *mut CURL) -> CURLcode {
+		let curlu = self.handles.with(handle, |state| state.curlu);
+		if curlu != 0 {
+			let code = self.setopt(library, handle, curl::CURLOPT_CURLU, Arg::Pointer(std::ptr::null_mut()), "clear CURLOPT_CURLU");
+			if code != curl_sys::CURLE_OK {
+				return code;
+			}
+		}
+		self.handles.with(handle, |state| state.rewritten = true);
+		self.setopt(library, handle, curl_sys::CURLOPT_URL, Arg::String(c"curl-inject-opt-denied://"), "set URL of denied transfer")
+	}
+}
+
+/// Our CURLOPT_PREREQFUNCTION callback, called before every request including redirects.
+///
+/// The user data pointer is the handle itself.
+extern "C" fn prereq_callback(data: *mut c_void, primary_ip: *mut c_char, local_ip: *mut c_char, primary_port: c_int, local_port: c_int) -> c_int {
+	let handle: *mut CURL = data.cast();
+	// SAFETY: libcurl passes the address as a null-terminated string.
+	let address = unsafe { CStr::from_ptr(primary_ip) }.to_string_lossy();
+	let allowed = with_state(|init| init.check_prereq(handle, &address)).unwrap_or(false);
+	if !allowed {
+		return curl::CURL_PREREQFUNC_ABORT;
+	}
+
+	let (prereq, prereq_data) = with_state(|init| init.handles.with_existing(handle, |state| (state.prereq, state.prereq_data))).flatten().unwrap_or((None, 0));
+	match prereq {
+		// SAFETY: The application set this function as CURLOPT_PREREQFUNCTION, so it has this signature.
+		Some(prereq) => unsafe {
+			let prereq = std::mem::transmute::<setopt::Function, curl::CurlPrereqCallback>(prereq);
+			prereq(prereq_data as *mut c_void, primary_ip, local_ip, primary_port, local_port)
+		},
+		None => curl::CURL_PREREQFUNC_OK,
+	}
+}
+
+/// Get our CURLOPT_PREREQFUNCTION callback as a generic function pointer.
+fn prereq_callback_fn() -> setopt::Function {
+	let callback: curl::CurlPrereqCallback = prereq_callback;
+	// SAFETY: Function pointers all have the same representation, libcurl calls it with the right signature.
+	unsafe { std::mem::transmute::<curl::CurlPrereqCallback, setopt::Function>(callback) }
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn allows(policy: &NetworkPolicy, url: &str) -> bool {
+		policy.allows(&UrlParts::parse(url).unwrap())
+	}
+
+	#[test]
+	fn test_allow_deny() {
+		let policy = NetworkPolicy::parse(false, "", "*.example.com,https://evil.org", "resolve").unwrap();
+		assert!(allows(&policy, "https://example.org/"));
+		assert!(!allows(&policy, "https://www.example.com/"));
+		assert!(!allows(&policy, "https://evil.org/"));
+		assert!(allows(&policy, "http://evil.org/"));
+
+		let policy = NetworkPolicy::parse(false, "*.corp,mirror.example.com", "secret.corp", "timeout").unwrap();
+		assert!(policy.code == curl_sys::CURLE_OPERATION_TIMEDOUT);
+		assert!(allows(&policy, "https://git.corp/"));
+		assert!(allows(&policy, "https://mirror.example.com/"));
+		assert!(!allows(&policy, "https://example.com/"));
+		assert!(!allows(&policy, "https://secret.corp/"));
+		assert!(!allows(&policy, "file:///etc/hosts"));
+		assert!(NetworkPolicy::parse(false, "", "", "never").is_err());
+	}
+
+	#[test]
+	fn test_offline() {
+		let policy = NetworkPolicy::parse(true, "", "", "resolve").unwrap();
+		assert!(allows(&policy, "file:///etc/hosts"));
+		assert!(allows(&policy, "http://localhost:8080/"));
+		assert!(allows(&policy, "http://api.localhost/"));
+		assert!(allows(&policy, "http://127.0.0.53/"));
+		assert!(allows(&policy, "http://[::1]:8080/"));
+		assert!(!allows(&policy, "http://127.example.com/"));
+		assert!(!allows(&policy, "https://crates.io/"));
+		assert!(!allows(&policy, "http://[::2]/"));
+
+		let policy = NetworkPolicy::parse(true, "cache.lan", "", "resolve").unwrap();
+		assert!(allows(&policy, "http://cache.lan/"));
+		assert!(!allows(&policy, "https://crates.io/"));
+	}
+
+	#[test]
+	fn test_offline_address() {
+		let policy = NetworkPolicy::parse(true, "cache.lan", "", "resolve").unwrap();
+		let localhost = UrlParts::parse("http://localhost/").unwrap();
+		assert!(policy.allows_address(&localhost, "127.0.0.1"));
+		assert!(policy.allows_address(&localhost, "::ffff:127.0.0.1"));
+		assert!(!policy.allows_address(&localhost, "93.184.215.14"));
+		assert!(!policy.allows_address(&localhost, ""));
+		assert!(policy.allows_address(&UrlParts::parse("http://cache.lan/").unwrap(), "192.168.1.2"));
+		assert!(NetworkPolicy::parse(false, "", "", "resolve").unwrap().allows_address(&localhost, "93.184.215.14"));
+	}
+}
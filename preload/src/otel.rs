@@ -0,0 +1,520 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::time::{Duration, SystemTime};
+
+use curl_inject_opt_shared::UrlParts;
+use curl_inject_opt_shared::headers::HeaderRule;
+
+use crate::json;
+use crate::lock_ignore_poison;
+use crate::random;
+use crate::request_log::Transfer;
+
+/// The timeout for connecting to and talking with the OTLP/HTTP collector.
+const ENDPOINT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The maximum size of a response of the OTLP/HTTP collector that we read.
+const MAX_RESPONSE_SIZE: u64 = 64 * 1024;
+
+/// The maximum number of spans waiting to be sent to the OTLP/HTTP collector.
+const MAX_QUEUED_SPANS: usize = 1024;
+
+/// How long to wait for the queued spans to be sent when the process exits.
+const EXIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The OTLP span kind of a client span.
+const SPAN_KIND_CLIENT: i32 = 3;
+
+/// The OTLP status code of a failed span.
+const STATUS_CODE_ERROR: i32 = 2;
+
+/// Creates a span for every transfer, propagates it with a W3C `traceparent` header, and exports it as OTLP-JSON.
+#[derive(Default)]
+pub struct Tracer {
+	/// The file to append spans to, one OTLP-JSON request per line.
+	file: Option<File>,
+
+	/// The OTLP/HTTP collector to send spans to.
+	endpoint: Option<Endpoint>,
+
+	/// The spans waiting to be sent to the collector by the exporter thread.
+	queue: Arc<(Mutex<Queue>, Condvar)>,
+
+	/// The parent of all spans, from the TRACEPARENT environment variable.
+	parent: Option<TraceParent>,
+
+	/// The vendor specific trace state, from the TRACESTATE environment variable.
+	tracestate: Option<String>,
+
+	/// The OTLP resource that describes this process, encoded as JSON.
+	resource: String,
+}
+
+/// An OTLP/HTTP collector endpoint.
+#[derive(Clone)]
+struct Endpoint {
+	host: String,
+	port: u16,
+	path: String,
+}
+
+/// The spans of one process that are waiting to be sent to the collector.
+#[derive(Default)]
+struct Queue {
+	/// The process that queued the spans, so a forked child starts from scratch.
+	pid: u32,
+
+	/// If true, the thread that sends the spans is running in this process.
+	exporter: bool,
+
+	/// If true, the exporter thread is sending a span that is no longer in the queue.
+	sending: bool,
+
+	/// The OTLP-JSON requests to send.
+	requests: VecDeque<String>,
+}
+
+/// A parsed W3C `traceparent` header.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct TraceParent {
+	trace_id: String,
+	span_id: String,
+	flags: u8,
+}
+
+/// The span of a transfer that is running.
+#[derive(Clone, Debug)]
+pub struct Span {
+	trace_id: String,
+	span_id: String,
+	parent_span_id: Option<String>,
+
+	/// The trace flags, where bit 0 means that the span is sampled.
+	flags: u8,
+
+	/// The start time in nanoseconds since the Unix epoch.
+	start: u128,
+}
+
+impl Tracer {
+	/// Get the configuration from the CURL_INJECT_OPT_OTLP_FILE, CURL_INJECT_OPT_OTLP_ENDPOINT, TRACEPARENT and TRACESTATE environment variables.
+	pub fn from_env() -> Result<Self, String> {
+		let file = match std::env::var_os("CURL_INJECT_OPT_OTLP_FILE").filter(|x| !x.is_empty()) {
+			None       => None,
+			Some(path) => Some(std::fs::OpenOptions::new()
+				.create(true)
+				.append(true)
+				.open(&path)
+				.map_err(|e| format!("failed to open OTLP file {}: {}", path.to_string_lossy(), e))?),
+		};
+		let endpoint = match std::env::var("CURL_INJECT_OPT_OTLP_ENDPOINT").ok().filter(|x| !x.is_empty()) {
+			None      => None,
+			Some(url) => Some(Endpoint::parse(&url)?),
+		};
+		if file.is_none() && endpoint.is_none() {
+			return Ok(Self::default());
+		}
+
+		let parent = match std::env::var("TRACEPARENT").ok().filter(|x| !x.is_empty()) {
+			None        => None,
+			Some(value) => Some(TraceParent::parse(&value)?),
+		};
+		let tracestate = std::env::var("TRACESTATE").ok()
+			.filter(|x| parent.is_some() && !x.is_empty() && !x.contains(|c: char| c.is_ascii_control()));
+
+		Ok(Self {
+			file,
+			endpoint,
+			queue: Arc::default(),
+			parent,
+			tracestate,
+			resource: resource(),
+		})
+	}
+
+	/// Check if tracing is enabled.
+	pub fn is_enabled(&self) -> bool {
+		self.file.is_some() || self.endpoint.is_some()
+	}
+
+	/// Start the span of a transfer, as a child of the parent from the environment, or in a new trace.
+	pub fn start_span(&self) -> Span {
+		let (trace_id, parent_span_id, flags) = match &self.parent {
+			Some(parent) => (parent.trace_id.clone(), Some(parent.span_id.clone()), parent.flags),
+			None         => (random::hex(&random::bytes::<16>()), None, 1),
+		};
+		Span {
+			trace_id,
+			span_id: random::hex(&random::bytes::<8>()),
+			parent_span_id,
+			flags,
+			start: now(),
+		}
+	}
+
+	/// Get the header rules that propagate a span to the server.
+	pub fn header_rules(&self, span: &Span) -> Vec<HeaderRule> {
+		let mut rules = vec![HeaderRule::Set(format!("traceparent: 00-{}-{}-{:02x}", span.trace_id, span.span_id, span.flags))];
+		if let Some(tracestate) = &self.tracestate {
+			rules.push(HeaderRule::Set(format!("tracestate: {}", tracestate)));
+		}
+		rules
+	}
+
+	/// Finish the span of a transfer and export it, unless the parent asked not to sample it.
+	pub fn finish(&self, span: &Span, transfer: &Transfer) -> Result<(), String> {
+		if span.flags & 1 == 0 {
+			return Ok(());
+		}
+		let request = self.to_json(span, transfer, now());
+		if let Some(mut file) = self.file.as_ref() {
+			file.write_all(format!("{}\n", request).as_bytes()).map_err(|e| format!("failed to write OTLP file: {}", e))?;
+		}
+		if let Some(endpoint) = &self.endpoint {
+			self.send(endpoint, request)?;
+		}
+		Ok(())
+	}
+
+	/// Queue a request for the exporter thread, so the program does not wait for the collector.
+	fn send(&self, endpoint: &Endpoint, request: String) -> Result<(), String> {
+		let (queue, wakeup) = &*self.queue;
+		let mut queue = lock_ignore_poison(queue);
+		queue.reset_after_fork();
+		if queue.requests.len() >= MAX_QUEUED_SPANS {
+			return Err(format!("failed to send span to OTLP endpoint {}: too many spans waiting", endpoint));
+		}
+		if !queue.exporter {
+			queue.exporter = spawn_exporter(endpoint.clone(), self.queue.clone());
+			if !queue.exporter {
+				return Err(format!("failed to send span to OTLP endpoint {}: failed to start the exporter thread", endpoint));
+			}
+		}
+		queue.requests.push_back(request);
+		wakeup.notify_all();
+		Ok(())
+	}
+
+	/// Wait until the queued spans are sent, or the exit timeout expires.
+	pub fn flush(&self) -> Result<(), String> {
+		let (queue, wakeup) = &*self.queue;
+		let queue = lock_ignore_poison(queue);
+		if queue.pid != std::process::id() || !queue.exporter {
+			return Ok(());
+		}
+		let (queue, _) = wakeup.wait_timeout_while(queue, EXIT_TIMEOUT, |x| x.sending || !x.requests.is_empty()).unwrap_or_else(PoisonError::into_inner);
+		match queue.requests.len() + queue.sending as usize {
+			0     => Ok(()),
+			count => Err(format!("gave up sending {} spans to OTLP endpoint {}", count, self.endpoint.as_ref().map(ToString::to_string).unwrap_or_default())),
+		}
+	}
+
+	/// Encode a finished span as an OTLP-JSON `ExportTraceServiceRequest`.
+	fn to_json(&self, span: &Span, transfer: &Transfer, now: u128) -> String {
+		let nanos = |microseconds: i64| span.start + microseconds.max(0) as u128 * 1000;
+		let end = transfer.total.map_or(now, nanos);
+		let url = transfer.url.as_deref().and_then(|x| UrlParts::parse(x).ok());
+
+		let mut attributes = Vec::new();
+		attributes.extend(transfer.method.as_deref().map(|x| attribute_string("http.request.method", x)));
+		attributes.extend(transfer.url.as_deref().map(|x| attribute_string("url.full", x)));
+		attributes.extend(url.as_ref().map(|x| attribute_string("server.address", &x.host)));
+		attributes.extend(url.as_ref().and_then(|x| x.port).map(|x| attribute_int("server.port", x)));
+		attributes.extend(transfer.remote_ip.as_deref().map(|x| attribute_string("network.peer.address", x)));
+		attributes.extend(transfer.response_code.filter(|&x| x > 0).map(|x| attribute_int("http.response.status_code", x)));
+		attributes.extend(transfer.bytes_up.map(|x| attribute_int("http.request.body.size", x)));
+		attributes.extend(transfer.bytes_down.map(|x| attribute_int("http.response.body.size", x)));
+		attributes.push(attribute_int("curl.result", transfer.result));
+		let error = match (transfer.result, transfer.response_code.unwrap_or(0)) {
+			(0, code @ 400..) => Some(code.to_string()),
+			(0, _)            => None,
+			(result, _)       => Some(format!("CURLcode {}", result)),
+		};
+		attributes.extend(error.as_deref().map(|x| attribute_string("error.type", x)));
+
+		// Phases that did not happen, like the TLS handshake of a plaintext transfer, are reported as 0 by libcurl.
+		let phases = [
+			("name_lookup", transfer.name_lookup),
+			("connect", transfer.connect),
+			("app_connect", transfer.app_connect),
+			("start_transfer", transfer.start_transfer),
+		];
+		let events = phases.into_iter().filter_map(|(name, time)| {
+			let time = time.filter(|&x| x > 0)?;
+			Some(json::Object::new().string("timeUnixNano", &nanos(time).to_string()).string("name", name).finish())
+		});
+
+		let mut object = json::Object::new();
+		object
+			.string("traceId", &span.trace_id)
+			.string("spanId", &span.span_id);
+		if let Some(parent_span_id) = &span.parent_span_id {
+			object.string("parentSpanId", parent_span_id);
+		}
+		if let Some(tracestate) = &self.tracestate {
+			object.string("traceState", tracestate);
+		}
+		object
+			.string("name", transfer.method.as_deref().unwrap_or("HTTP"))
+			.number("kind", Some(SPAN_KIND_CLIENT))
+			.string("startTimeUnixNano", &span.start.to_string())
+			.string("endTimeUnixNano", &end.to_string())
+			.raw("attributes", &json::array(attributes))
+			.raw("events", &json::array(events));
+		if error.is_some() {
+			object.raw("status", &json::Object::new().number("code", Some(STATUS_CODE_ERROR)).finish());
+		}
+		let span = object.finish();
+
+		let scope = json::Object::new()
+			.string("name", "curl-inject-opt")
+			.string("version", env!("CARGO_PKG_VERSION"))
+			.finish();
+		let scope_spans = json::Object::new()
+			.raw("scope", &scope)
+			.raw("spans", &json::array([span]))
+			.finish();
+		let resource_spans = json::Object::new()
+			.raw("resource", &self.resource)
+			.raw("scopeSpans", &json::array([scope_spans]))
+			.finish();
+		json::Object::new()
+			.raw("resourceSpans", &json::array([resource_spans]))
+			.finish()
+	}
+}
+
+impl Endpoint {
+	/// Parse the URL of an OTLP/HTTP collector.
+	///
+	/// If the URL has no path, spans are sent to the default path `/v1/traces`.
+	fn parse(url: &str) -> Result<Self, String> {
+		let parts = UrlParts::parse(url)?;
+		if parts.scheme != "http" || !url.contains("://") {
+			return Err(format!("unsupported OTLP endpoint {}: only http:// URLs are supported", url));
+		}
+		let path = match parts.path.as_str() {
+			"/" => String::from("/v1/traces"),
+			_   => parts.path,
+		};
+		Ok(Self {
+			port: parts.port.unwrap_or(80),
+			host: parts.host,
+			path,
+		})
+	}
+
+	/// Send an OTLP-JSON request to the collector.
+	///
+	/// This can take several seconds, so it is only called from the exporter thread.
+	fn post(&self, body: &str) -> Result<(), String> {
+		let address = (self.host.as_str(), self.port).to_socket_addrs()
+			.map_err(|e| e.to_string())?
+			.next()
+			.ok_or("no addresses found")?;
+		let mut stream = TcpStream::connect_timeout(&address, ENDPOINT_TIMEOUT).map_err(|e| e.to_string())?;
+		stream.set_read_timeout(Some(ENDPOINT_TIMEOUT)).map_err(|e| e.to_string())?;
+		stream.set_write_timeout(Some(ENDPOINT_TIMEOUT)).map_err(|e| e.to_string())?;
+
+		let host = match self.host.contains(':') {
+			true  => format!("[{}]", self.host),
+			false => self.host.clone(),
+		};
+		let request = format!(
+			"POST {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+			self.path, host, self.port, body.len(), body,
+		);
+		stream.write_all(request.as_bytes()).map_err(|e| e.to_string())?;
+
+		// Read the whole response, so the connection is closed cleanly.
+		let mut response = Vec::new();
+		stream.take(MAX_RESPONSE_SIZE).read_to_end(&mut response).map_err(|e| e.to_string())?;
+		let status = response.split(|&c| c == b'\n').next().unwrap_or_default();
+		match status {
+			[b'H', b'T', b'T', b'P', b'/', _, _, _, b' ', b'2', ..] => Ok(()),
+			_ => Err(format!("unexpected response: {}", String::from_utf8_lossy(status).trim_end())),
+		}
+	}
+}
+
+impl std::fmt::Display for Endpoint {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self.host.contains(':') {
+			true  => write!(f, "http://[{}]:{}{}", self.host, self.port, self.path),
+			false => write!(f, "http://{}:{}{}", self.host, self.port, self.path),
+		}
+	}
+}
+
+impl Queue {
+	/// Forget the spans of the parent process in a forked child.
+	///
+	/// The exporter thread does not survive a fork either.
+	fn reset_after_fork(&mut self) {
+		let pid = std::process::id();
+		if self.pid != pid {
+			*self = Self { pid, ..Self::default() };
+		}
+	}
+}
+
+/// Start a thread that sends the queued spans to the collector.
+fn spawn_exporter(endpoint: Endpoint, queue: Arc<(Mutex<Queue>, Condvar)>) -> bool {
+	let thread = std::thread::Builder::new()
+		.name(String::from("curl-inject-opt-otlp"))
+		.spawn(move || loop {
+			let (lock, wakeup) = &*queue;
+			let request = {
+				let guard = lock_ignore_poison(lock);
+				let mut guard = wakeup.wait_while(guard, |x| x.requests.is_empty()).unwrap_or_else(PoisonError::into_inner);
+				guard.sending = true;
+				guard.requests.pop_front()
+			};
+			if let Some(request) = request {
+				if let Err(e) = endpoint.post(&request) {
+					crate::error_policy::report(format!("failed to send span to OTLP endpoint {}: {}", endpoint, e));
+				}
+			}
+			let mut guard = lock_ignore_poison(lock);
+			guard.sending = false;
+			wakeup.notify_all();
+		});
+	thread.is_ok()
+}
+
+impl TraceParent {
+	/// Parse a W3C `traceparent` header value.
+	fn parse(value: &str) -> Result<Self, String> {
+		let invalid = || format!("invalid TRACEPARENT: {}", value);
+		let fields: Vec<_> = value.trim().split('-').collect();
+		let is_hex = |x: &str, len: usize| x.len() == len && x.bytes().all(|c| matches!(c, b'0'..=b'9' | b'a'..=b'f'));
+		let is_zero = |x: &str| x.bytes().all(|c| c == b'0');
+		let (version, trace_id, span_id, flags) = match fields.as_slice() {
+			[version, trace_id, span_id, flags] => (*version, *trace_id, *span_id, *flags),
+			// Later versions may add fields, but must keep the first four.
+			[version, trace_id, span_id, flags, ..] if *version != "00" => (*version, *trace_id, *span_id, *flags),
+			_ => return Err(invalid()),
+		};
+		if !is_hex(version, 2) || version == "ff" || !is_hex(trace_id, 32) || is_zero(trace_id) || !is_hex(span_id, 16) || is_zero(span_id) || !is_hex(flags, 2) {
+			return Err(invalid());
+		}
+		Ok(Self {
+			trace_id: trace_id.to_string(),
+			span_id: span_id.to_string(),
+			flags: u8::from_str_radix(flags, 16).map_err(|_| invalid())?,
+		})
+	}
+}
+
+/// Describe this process as an OTLP resource.
+fn resource() -> String {
+	let executable = std::env::current_exe().ok();
+	let service_name = std::env::var("OTEL_SERVICE_NAME").ok().filter(|x| !x.is_empty())
+		.or_else(|| executable.as_ref().and_then(|x| x.file_name()).map(|x| x.to_string_lossy().into_owned()))
+		.unwrap_or_else(|| String::from("unknown_service"));
+
+	let mut attributes = vec![
+		attribute_string("service.name", &service_name),
+		attribute_int("process.pid", std::process::id()),
+	];
+	attributes.extend(executable.map(|x| attribute_string("process.executable.path", &x.to_string_lossy())));
+	json::Object::new().raw("attributes", &json::array(attributes)).finish()
+}
+
+fn attribute_string(key: &str, value: &str) -> String {
+	let value = json::Object::new().string("stringValue", value).finish();
+	json::Object::new().string("key", key).raw("value", &value).finish()
+}
+
+fn attribute_int(key: &str, value: impl std::fmt::Display) -> String {
+	// OTLP-JSON encodes 64 bit integers as strings.
+	let value = json::Object::new().string("intValue", &value.to_string()).finish();
+	json::Object::new().string("key", key).raw("value", &value).finish()
+}
+
+/// Get the current time in nanoseconds since the Unix epoch.
+fn now() -> u128 {
+	SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_nanos()
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_traceparent() {
+		let parent = TraceParent::parse("00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01");
+		assert_eq!(parent, Ok(TraceParent { trace_id: "0af7651916cd43dd8448eb211c80319c".into(), span_id: "b7ad6b7169203331".into(), flags: 1 }));
+		assert!(TraceParent::parse("01-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-00-extra").is_ok());
+		assert!(TraceParent::parse("00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01-extra").is_err());
+		assert!(TraceParent::parse("00-00000000000000000000000000000000-b7ad6b7169203331-01").is_err());
+		assert!(TraceParent::parse("00-0AF7651916CD43DD8448EB211C80319C-b7ad6b7169203331-01").is_err());
+		assert!(TraceParent::parse("ff-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01").is_err());
+	}
+
+	#[test]
+	fn test_span() {
+		let tracer = Tracer {
+			parent: TraceParent::parse("00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01").ok(),
+			tracestate: Some(String::from("vendor=value")),
+			resource: String::from("{}"),
+			..Tracer::default()
+		};
+		let span = Span { start: 1_000_000_000, ..tracer.start_span() };
+		assert_eq!(span.trace_id, "0af7651916cd43dd8448eb211c80319c");
+		assert_eq!(span.span_id.len(), 16);
+
+		let headers: Vec<_> = tracer.header_rules(&span).into_iter().map(|x| match x {
+			HeaderRule::Set(line) => line,
+			_ => panic!("expected a set rule"),
+		}).collect();
+		assert_eq!(headers, [format!("traceparent: 00-0af7651916cd43dd8448eb211c80319c-{}-01", span.span_id), String::from("tracestate: vendor=value")]);
+
+		let transfer = Transfer {
+			url: Some(String::from("https://example.com/")),
+			method: Some(String::from("GET")),
+			response_code: Some(503),
+			connect: Some(2000),
+			app_connect: Some(0),
+			total: Some(5000),
+			..Transfer::default()
+		};
+		let json = tracer.to_json(&span, &transfer, 0);
+		assert!(json.starts_with(r#"{"resourceSpans":[{"resource":{},"scopeSpans":[{"scope":{"name":"curl-inject-opt","#));
+		assert!(json.contains(r#""parentSpanId":"b7ad6b7169203331","traceState":"vendor=value","name":"GET","kind":3,"startTimeUnixNano":"1000000000","endTimeUnixNano":"1005000000""#));
+		assert!(json.contains(r#"{"key":"server.port","value":{"intValue":"443"}}"#));
+		assert!(json.contains(r#"{"key":"error.type","value":{"stringValue":"503"}}"#));
+		assert!(json.contains(r#""events":[{"timeUnixNano":"1002000000","name":"connect"}],"status":{"code":2}}"#));
+	}
+
+	#[test]
+	fn test_endpoint_queue() {
+		let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let port = listener.local_addr().unwrap().port();
+		let server = std::thread::spawn(move || {
+			let (mut stream, _) = listener.accept().unwrap();
+			let mut request = Vec::new();
+			let mut buffer = [0; 4096];
+			while !request.ends_with(b"{}") {
+				let count = stream.read(&mut buffer).unwrap();
+				request.extend_from_slice(&buffer[..count]);
+			}
+			stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").unwrap();
+			String::from_utf8(request).unwrap()
+		});
+
+		let tracer = Tracer {
+			endpoint: Some(Endpoint::parse(&format!("http://127.0.0.1:{}", port)).unwrap()),
+			..Tracer::default()
+		};
+		assert_eq!(tracer.flush(), Ok(()));
+		assert_eq!(tracer.send(tracer.endpoint.as_ref().unwrap(), String::from("{}")), Ok(()));
+		assert_eq!(tracer.flush(), Ok(()));
+		let request = server.join().unwrap();
+		assert!(request.starts_with("POST /v1/traces HTTP/1.1\r\n"));
+		assert!(request.ends_with("\r\n\r\n{}"));
+	}
+}
@@ -0,0 +1,37 @@
+/// Get random bytes from the kernel.
+pub fn bytes<const N: usize>() -> [u8; N] {
+	let mut buffer = [0; N];
+	let mut filled = 0;
+	while filled < N {
+		// SAFETY: The pointer and length describe the unfilled part of the buffer.
+		let result = unsafe { libc::getrandom(buffer[filled..].as_mut_ptr().cast(), N - filled, 0) };
+		if result < 0 {
+			if std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted {
+				continue;
+			}
+			// Fall back to the randomly seeded hasher of the standard library, so callers always get random bytes.
+			use std::hash::{BuildHasher, Hasher};
+			for chunk in buffer.chunks_mut(8) {
+				let random = std::collections::hash_map::RandomState::new().build_hasher().finish().to_ne_bytes();
+				chunk.copy_from_slice(&random[..chunk.len()]);
+			}
+			break;
+		}
+		filled += result as usize;
+	}
+	buffer
+}
+
+/// Encode bytes as lowercase hexadecimal.
+pub fn hex(bytes: &[u8]) -> String {
+	bytes.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+/// Generate a random version 4 UUID.
+pub fn uuid() -> String {
+	let mut bytes = bytes::<16>();
+	bytes[6] = (bytes[6] & 0x0f) | 0x40;
+	bytes[8] = (bytes[8] & 0x3f) | 0x80;
+	let hex = hex(&bytes);
+	format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32])
+}
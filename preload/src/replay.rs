@@ -0,0 +1,413 @@
+use std::ffi::CString;
+use std::os::raw::{c_char, c_double, c_long, c_void};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use curl_inject_opt_shared::reexports::curl_sys;
+
+use curl_sys::CURLINFO;
+use curl_sys::CURLcode;
+use curl_sys::curl_off_t;
+
+use crate::base64;
+use crate::curl;
+use crate::json::{self, Value};
+use crate::lock_ignore_poison;
+use crate::random::hex;
+use crate::request_log::{self, Transfer};
+
+/// What to do with transfers that have no recorded response.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Unmatched {
+	/// Fail the transfer without touching the network.
+	Fail,
+
+	/// Run the transfer as usual.
+	Pass,
+}
+
+/// Answers transfers with responses recorded in a HAR file or a JSON fixture file, without running them.
+pub struct Replay {
+	/// The file with the recorded responses, or `None` if replay is disabled.
+	path: Option<PathBuf>,
+
+	/// The recorded responses, loaded on the first transfer so that processes that never run one do not read the file.
+	fixtures: OnceLock<Vec<Fixture>>,
+
+	/// For each fixture, true if it was used already.
+	used: Mutex<Vec<bool>>,
+
+	/// What to do with transfers that have no recorded response.
+	pub unmatched: Unmatched,
+}
+
+impl Default for Replay {
+	fn default() -> Self {
+		Self { path: None, fixtures: OnceLock::new(), used: Mutex::new(Vec::new()), unmatched: Unmatched::Fail }
+	}
+}
+
+/// A recorded response, with the request that it answers.
+struct Fixture {
+	/// The request method.
+	method: String,
+
+	/// The URL of the request, without fragment.
+	url: String,
+
+	/// The hex encoded SHA-256 of the request body, if the body must match too.
+	body_sha256: Option<String>,
+
+	/// The response to give.
+	response: Arc<Response>,
+}
+
+/// A recorded response.
+#[derive(Debug, PartialEq)]
+pub struct Response {
+	/// The HTTP version of the status line, like `HTTP/1.1`.
+	pub http_version: String,
+
+	/// The status code.
+	pub status: u16,
+
+	/// The reason phrase of the status line.
+	pub status_text: String,
+
+	/// The headers, in order.
+	pub headers: Vec<(String, String)>,
+
+	/// The body.
+	pub body: Vec<u8>,
+}
+
+impl Replay {
+	/// Get the file with the recorded responses from CURL_INJECT_OPT_REPLAY.
+	///
+	/// CURL_INJECT_OPT_REPLAY_UNMATCHED decides what happens to transfers without recorded response: `fail` (the default) or `pass`.
+	pub fn from_env() -> Result<Self, String> {
+		let Some(path) = std::env::var_os("CURL_INJECT_OPT_REPLAY").filter(|x| !x.is_empty()) else {
+			return Ok(Self::default());
+		};
+		let unmatched = match std::env::var("CURL_INJECT_OPT_REPLAY_UNMATCHED").as_deref() {
+			Err(_) | Ok("") | Ok("fail") => Unmatched::Fail,
+			Ok("pass")                   => Unmatched::Pass,
+			Ok(x)                        => return Err(format!("invalid action for unmatched transfers: {}, expected fail or pass", x)),
+		};
+		Ok(Self::new(PathBuf::from(path), unmatched))
+	}
+
+	/// Replay the responses recorded in a HAR file or a JSON fixture file.
+	///
+	/// The file is loaded on the first transfer.
+	pub fn new(path: PathBuf, unmatched: Unmatched) -> Self {
+		Self { path: Some(path), unmatched, ..Self::default() }
+	}
+
+	/// Check if transfers are replayed.
+	pub fn is_enabled(&self) -> bool {
+		self.path.is_some()
+	}
+
+	/// Get the recorded responses, loading them on first use.
+	///
+	/// If the file can not be loaded, the error is reported once and no transfer matches.
+	fn fixtures(&self) -> &[Fixture] {
+		let Some(path) = &self.path else {
+			return &[];
+		};
+		self.fixtures.get_or_init(|| {
+			let data = std::fs::read_to_string(path).map_err(|e| format!("failed to read replay file {}: {}", path.display(), e));
+			let fixtures = data.and_then(|x| parse_fixtures(&x).map_err(|e| format!("invalid replay file {}: {}", path.display(), e)));
+			fixtures.unwrap_or_else(|e| {
+				crate::error_policy::report(e);
+				Vec::new()
+			})
+		})
+	}
+
+	/// Find the recorded response for a request.
+	///
+	/// Responses recorded for the same request are given in order, and the last one is repeated once they are all used.
+	/// The body is `None` if it is not known before the transfer runs, so it only matches fixtures without a body hash.
+	/// The URLs are compared after `normalize`, so URLs that libcurl treats the same match, like `http://host` and `http://host/`.
+	pub fn find(&self, method: &str, url: &str, body: Option<&[u8]>, normalize: impl Fn(&str) -> String) -> Option<Arc<Response>> {
+		let fixtures = self.fixtures();
+		let matching = self.matching(method, url, body, normalize);
+		let mut used = lock_ignore_poison(&self.used);
+		used.resize(fixtures.len(), false);
+		let index = matching.iter().copied().find(|&i| !used[i]).or(matching.last().copied())?;
+		used[index] = true;
+		Some(fixtures[index].response.clone())
+	}
+
+	/// Check if there is a recorded response for a request, without using it up.
+	pub fn has_response(&self, method: &str, url: &str, body: Option<&[u8]>, normalize: impl Fn(&str) -> String) -> bool {
+		!self.matching(method, url, body, normalize).is_empty()
+	}
+
+	/// Get the indices of the fixtures that match a request, in order.
+	fn matching(&self, method: &str, url: &str, body: Option<&[u8]>, normalize: impl Fn(&str) -> String) -> Vec<usize> {
+		let fixtures = self.fixtures();
+		let url = normalize(url.split('#').next().unwrap_or(url));
+		let body_sha256 = body.map(|x| hex(&hmac_sha256::Hash::hash(x)));
+		let matches = |fixture: &Fixture| {
+			fixture.method.eq_ignore_ascii_case(method)
+				&& normalize(&fixture.url) == url
+				&& fixture.body_sha256.as_ref().is_none_or(|x| body_sha256.as_ref().is_some_and(|y| x.eq_ignore_ascii_case(y)))
+		};
+		fixtures.iter().enumerate().filter(|(_, x)| matches(x)).map(|(i, _)| i).collect()
+	}
+}
+
+impl Response {
+	/// Get the status line and header lines like libcurl passes them to the header callback, ending with an empty line.
+	pub fn header_lines(&self) -> Vec<Vec<u8>> {
+		let mut lines = Vec::with_capacity(self.headers.len() + 2);
+		lines.push(format!("{} {} {}\r\n", self.http_version, self.status, self.status_text).into_bytes());
+		lines.extend(self.headers.iter().map(|(name, value)| format!("{}: {}\r\n", name, value).into_bytes()));
+		lines.push(b"\r\n".to_vec());
+		lines
+	}
+
+	/// Get the CURL_HTTP_VERSION_* value of the HTTP version.
+	fn curl_http_version(&self) -> c_long {
+		let version = match self.http_version.as_str() {
+			"HTTP/1.0"            => curl_sys::CURL_HTTP_VERSION_1_0,
+			"HTTP/1.1"            => curl_sys::CURL_HTTP_VERSION_1_1,
+			"HTTP/2" | "HTTP/2.0" => curl_sys::CURL_HTTP_VERSION_2_0,
+			"HTTP/3"              => curl_sys::CURL_HTTP_VERSION_3,
+			_                     => curl_sys::CURL_HTTP_VERSION_NONE,
+		};
+		c_long::from(version)
+	}
+
+	/// Get the value of the first header with the given name.
+	pub fn header(&self, name: &str) -> Option<&str> {
+		self.headers.iter().find(|(x, _)| x.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+	}
+}
+
+/// A replayed transfer, which answers `curl_easy_getinfo()` for the handle until the next transfer.
+pub struct Replayed {
+	/// The response that was given.
+	pub response: Arc<Response>,
+
+	/// The URL of the request.
+	pub url: CString,
+
+	/// The scheme of the URL.
+	pub scheme: CString,
+
+	/// The method of the request.
+	pub method: CString,
+
+	/// The `Content-Type` of the response.
+	pub content_type: Option<CString>,
+
+	/// The number of body bytes given to the application.
+	pub bytes_down: usize,
+}
+
+impl Replayed {
+	/// Answer a `curl_easy_getinfo()` call, or return `None` to let libcurl answer it.
+	///
+	/// # Safety
+	/// The argument must point to a value of the type that libcurl uses for the info.
+	pub unsafe fn getinfo(&self, info: CURLINFO, arg: *mut c_void) -> Option<CURLcode> {
+		match info {
+			curl_sys::CURLINFO_RESPONSE_CODE    => *arg.cast::<c_long>() = c_long::from(self.response.status),
+			curl_sys::CURLINFO_REDIRECT_COUNT   => *arg.cast::<c_long>() = 0,
+			curl_sys::CURLINFO_EFFECTIVE_URL    => *arg.cast::<*const c_char>() = self.url.as_ptr(),
+			curl::CURLINFO_SCHEME               => *arg.cast::<*const c_char>() = self.scheme.as_ptr(),
+			curl::CURLINFO_PROTOCOL             => *arg.cast::<c_long>() = self.protocol(),
+			curl::CURLINFO_HTTP_VERSION         => *arg.cast::<c_long>() = self.response.curl_http_version(),
+			curl::CURLINFO_EFFECTIVE_METHOD     => *arg.cast::<*const c_char>() = self.method.as_ptr(),
+			curl_sys::CURLINFO_CONTENT_TYPE     => *arg.cast::<*const c_char>() = self.content_type.as_ref().map_or(std::ptr::null(), |x| x.as_ptr()),
+			curl_sys::CURLINFO_SIZE_DOWNLOAD    => *arg.cast::<c_double>() = self.bytes_down as c_double,
+			curl::CURLINFO_SIZE_DOWNLOAD_T      => *arg.cast::<curl_off_t>() = self.bytes_down as curl_off_t,
+			curl_sys::CURLINFO_SIZE_UPLOAD      => *arg.cast::<c_double>() = 0.0,
+			curl::CURLINFO_SIZE_UPLOAD_T        => *arg.cast::<curl_off_t>() = 0,
+			curl_sys::CURLINFO_TOTAL_TIME       => *arg.cast::<c_double>() = 0.0,
+			curl::CURLINFO_TOTAL_TIME_T         => *arg.cast::<curl_off_t>() = 0,
+			_                                   => return None,
+		}
+		Some(curl_sys::CURLE_OK)
+	}
+
+	/// Describe the replayed transfer for the request log, the summary, metrics, captures and the HAR file.
+	pub fn transfer(&self, result: CURLcode) -> Transfer {
+		Transfer {
+			url: Some(request_log::redact_url(&self.url.to_string_lossy())),
+			method: Some(self.method.to_string_lossy().into_owned()),
+			response_code: Some(c_long::from(self.response.status)),
+			result,
+			bytes_up: Some(0),
+			bytes_down: Some(self.bytes_down as curl_off_t),
+			total: Some(0),
+			content_type: self.content_type.as_ref().map(|x| x.to_string_lossy().into_owned()),
+			..Transfer::default()
+		}
+	}
+
+	/// Get the CURLPROTO_* value of the scheme, which programs use to check if the headers are HTTP headers.
+	fn protocol(&self) -> c_long {
+		match self.scheme.to_bytes().to_ascii_lowercase().as_slice() {
+			b"http"  => c_long::from(curl_sys::CURLPROTO_HTTP),
+			b"https" => c_long::from(curl_sys::CURLPROTO_HTTPS),
+			_        => 0,
+		}
+	}
+}
+
+/// Parse the fixtures from a HAR file, or from a JSON array of fixtures.
+fn parse_fixtures(data: &str) -> Result<Vec<Fixture>, String> {
+	let document = json::parse(data)?;
+	if let Some(entries) = document.get("log").and_then(|x| x.get("entries")) {
+		let entries = entries.as_array().ok_or("entries of HAR file is not an array")?;
+		return entries.iter().enumerate().map(|(i, x)| parse_har_entry(x).map_err(|e| format!("entry {}: {}", i, e))).collect();
+	}
+	let fixtures = document.as_array().ok_or("expected a HAR file or an array of fixtures")?;
+	fixtures.iter().enumerate().map(|(i, x)| parse_fixture(x).map_err(|e| format!("fixture {}: {}", i, e))).collect()
+}
+
+/// Parse an entry of a HAR file.
+fn parse_har_entry(entry: &Value) -> Result<Fixture, String> {
+	let request = entry.get("request").ok_or("missing request")?;
+	let response = entry.get("response").ok_or("missing response")?;
+	let method = request.get("method").and_then(Value::as_str).ok_or("missing request method")?;
+	let url = request.get("url").and_then(Value::as_str).ok_or("missing request URL")?;
+	let http_version = response.get("httpVersion").and_then(Value::as_str).filter(|x| x.starts_with("HTTP/")).unwrap_or("HTTP/1.1");
+	let headers = response.get("headers").and_then(Value::as_array).unwrap_or_default();
+	let headers = headers.iter()
+		.map(|x| Some((x.get("name")?.as_str()?.to_string(), x.get("value")?.as_str()?.to_string())))
+		.collect::<Option<Vec<_>>>()
+		.ok_or("invalid response header")?;
+	let content = response.get("content");
+	let text = content.and_then(|x| x.get("text")).and_then(Value::as_str).unwrap_or("");
+	let body = match content.and_then(|x| x.get("encoding")).and_then(Value::as_str) {
+		Some("base64") => base64::decode(text)?,
+		_              => text.as_bytes().to_vec(),
+	};
+	if content.and_then(|x| x.get("comment")).and_then(Value::as_str).is_some_and(|x| x.starts_with("truncated")) {
+		return Err(String::from("response body was truncated when it was recorded, record it with a larger --har-max-body"));
+	}
+
+	// The recorded body is decoded, so the headers that describe how it was sent would contradict it.
+	let mut headers = headers;
+	if !method.eq_ignore_ascii_case("HEAD") {
+		let length = ["Content-Length", "Transfer-Encoding"].iter().any(|x| headers.iter().any(|(name, _)| name.eq_ignore_ascii_case(x)));
+		headers.retain(|(name, _)| !["Content-Length", "Transfer-Encoding", "Content-Encoding"].iter().any(|x| name.eq_ignore_ascii_case(x)));
+		if length {
+			headers.push((String::from("Content-Length"), body.len().to_string()));
+		}
+	}
+	Ok(Fixture {
+		method: method.to_string(),
+		url: url.split('#').next().unwrap_or(url).to_string(),
+		body_sha256: None,
+		response: Arc::new(Response {
+			http_version: http_version.to_string(),
+			status: status(response.get("status"))?,
+			status_text: response.get("statusText").and_then(Value::as_str).unwrap_or("").to_string(),
+			headers,
+			body,
+		}),
+	})
+}
+
+/// Parse a fixture of the simple format.
+fn parse_fixture(fixture: &Value) -> Result<Fixture, String> {
+	let url = fixture.get("url").and_then(Value::as_str).ok_or("missing url")?;
+	let headers = match fixture.get("headers") {
+		None          => Vec::new(),
+		Some(headers) => headers.as_object()
+			.ok_or("headers is not an object")?
+			.iter()
+			.map(|(name, value)| Some((name.clone(), value.as_str()?.to_string())))
+			.collect::<Option<Vec<_>>>()
+			.ok_or("header value is not a string")?,
+	};
+	let body = match (fixture.get("body"), fixture.get("body_base64")) {
+		(Some(_), Some(_)) => return Err(String::from("body and body_base64 can not both be set")),
+		(Some(body), None) => body.as_str().ok_or("body is not a string")?.as_bytes().to_vec(),
+		(None, Some(body)) => base64::decode(body.as_str().ok_or("body_base64 is not a string")?)?,
+		(None, None)       => Vec::new(),
+	};
+	let body_sha256 = match fixture.get("body_sha256") {
+		None       => None,
+		Some(hash) => Some(hash.as_str().filter(|x| x.len() == 64 && x.bytes().all(|x| x.is_ascii_hexdigit())).ok_or("body_sha256 is not a SHA-256 in hex")?.to_string()),
+	};
+	Ok(Fixture {
+		method: fixture.get("method").and_then(Value::as_str).unwrap_or("GET").to_string(),
+		url: url.split('#').next().unwrap_or(url).to_string(),
+		body_sha256,
+		response: Arc::new(Response {
+			http_version: String::from("HTTP/1.1"),
+			status: fixture.get("status").map_or(Ok(200), |x| status(Some(x)))?,
+			status_text: fixture.get("status_text").and_then(Value::as_str).unwrap_or("").to_string(),
+			headers,
+			body,
+		}),
+	})
+}
+
+fn status(value: Option<&Value>) -> Result<u16, String> {
+	value.and_then(Value::as_u64).and_then(|x| u16::try_from(x).ok()).ok_or_else(|| String::from("missing or invalid status"))
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn replay(data: &str) -> Replay {
+		let replay = Replay::new(PathBuf::from("test.har"), Unmatched::Fail);
+		assert!(replay.fixtures.set(parse_fixtures(data).unwrap()).is_ok());
+		replay
+	}
+
+	#[test]
+	fn test_fixtures() {
+		let replay = replay(r#"[
+			{"url": "http://example.com/poll", "body": "pending"},
+			{"url": "http://example.com/poll", "status": 201, "status_text": "Created", "headers": {"Content-Type": "text/plain"}, "body_base64": "ZG9uZQ=="},
+			{"method": "POST", "url": "http://example.com/items", "body_sha256": "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824", "status": 409}
+		]"#);
+		let first = replay.find("GET", "http://example.com/poll#top", None, str::to_string).unwrap();
+		assert_eq!(first.body, b"pending");
+		assert_eq!(first.header_lines(), [b"HTTP/1.1 200 \r\n".to_vec(), b"\r\n".to_vec()]);
+		let second = replay.find("get", "http://example.com/poll", None, str::to_string).unwrap();
+		assert_eq!((second.status, second.header("content-type"), second.body.as_slice()), (201, Some("text/plain"), &b"done"[..]));
+		assert_eq!(replay.find("GET", "http://example.com/poll", None, str::to_string), Some(second));
+
+		assert!(replay.has_response("GET", "http://example.com/poll", None, str::to_string));
+		assert!(!replay.has_response("POST", "http://example.com/items", None, str::to_string));
+		assert_eq!(replay.find("POST", "http://example.com/items", None, str::to_string), None);
+		assert_eq!(replay.find("POST", "http://example.com/items", Some(b"hi"), str::to_string), None);
+		assert_eq!(replay.find("POST", "http://example.com/items", Some(b"hello"), str::to_string).map(|x| x.status), Some(409));
+	}
+
+	#[test]
+	fn test_har() {
+		let recorded = replay(r#"{"log": {"version": "1.2", "entries": [{
+			"request": {"method": "GET", "url": "https://example.com/a?b=c", "headers": []},
+			"response": {"status": 404, "statusText": "Not Found", "httpVersion": "HTTP/2", "headers": [{"name": "X-A", "value": "1"}], "content": {"size": 2, "text": "no"}}
+		}]}}"#);
+		let response = recorded.find("GET", "https://example.com/a?b=c", None, str::to_string).unwrap();
+		assert_eq!(response.header_lines(), [b"HTTP/2 404 Not Found\r\n".to_vec(), b"X-A: 1\r\n".to_vec(), b"\r\n".to_vec()]);
+		assert_eq!(response.body, b"no");
+		assert!(parse_fixtures(r#"[{"method": "GET"}]"#).is_err());
+
+		// A body recorded by --har is decoded, so the headers must describe the body as it is replayed.
+		let encoded = replay(r#"{"log": {"entries": [{
+			"request": {"method": "GET", "url": "https://example.com/gzip"},
+			"response": {"status": 200, "headers": [{"name": "content-encoding", "value": "gzip"}, {"name": "Transfer-Encoding", "value": "chunked"}, {"name": "X-A", "value": "1"}], "content": {"text": "decoded"}}
+		}]}}"#);
+		let response = encoded.find("GET", "https://example.com/gzip", None, str::to_string).unwrap();
+		assert_eq!(response.headers, [(String::from("X-A"), String::from("1")), (String::from("Content-Length"), String::from("7"))]);
+		assert!(parse_fixtures(r#"{"log": {"entries": [{
+			"request": {"method": "GET", "url": "https://example.com/"},
+			"response": {"status": 200, "content": {"size": 5, "text": "ab", "comment": "truncated to 2 of 5 bytes"}}
+		}]}}"#).is_err());
+	}
+}
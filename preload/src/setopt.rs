@@ -0,0 +1,509 @@
+use std::ffi::{CStr, CString, OsStr};
+use std::os::unix::ffi::OsStrExt;
+use std::os::raw::{c_long, c_void};
+
+use curl_inject_opt_shared::Kind;
+use curl_inject_opt_shared::SetOption;
+use curl_inject_opt_shared::Value;
+use curl_inject_opt_shared::reexports::curl_sys;
+
+use curl_sys::CURL;
+use curl_sys::CURLcode;
+use curl_sys::CURLoption;
+use curl_sys::curl_blob;
+use curl_sys::curl_off_t;
+use curl_sys::curl_slist;
+
+use crate::curl::CurlEasySetOpt;
+
+/// A generic C function pointer, as passed to `curl_easy_setopt()` for callback options.
+pub type Function = unsafe extern "C" fn();
+
+/// An argument for `curl_easy_setopt()`.
+///
+/// `curl_easy_setopt()` is variadic, so libcurl reads the argument with `va_arg()` using the type it expects for the option.
+/// Each variant holds exactly that C type, so the argument is passed the same way a C program would pass it.
+#[derive(Clone, Copy)]
+pub enum Arg<'a> {
+	/// A `long`, for CURLOPTTYPE_LONG options.
+	Long(c_long),
+
+	/// A `curl_off_t`, for CURLOPTTYPE_OFF_T options.
+	OffT(curl_off_t),
+
+	/// A `char *`, for string options.
+	String(&'a CStr),
+
+	/// A `struct curl_slist *`, for list options.
+	Slist(*const curl_slist),
+
+	/// A `struct curl_blob *`, for CURLOPTTYPE_BLOB options.
+	Blob(*const curl_blob),
+
+	/// A `FILE *`, for options like CURLOPT_STDERR.
+	File(*mut libc::FILE),
+
+	/// Any other pointer, for CURLOPTTYPE_OBJECTPOINT options.
+	Pointer(*mut c_void),
+
+	/// A function pointer, for CURLOPTTYPE_FUNCTIONPOINT options.
+	Function(Option<Function>),
+}
+
+/// An argument for `curl_easy_setopt()` as read with `va_arg()` by the hooks in `hooks.c`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub union RawArg {
+	long: c_long,
+	off_t: curl_off_t,
+	pointer: *mut c_void,
+	function: Option<Function>,
+}
+
+/// The argument type of an option, as encoded in the option ID by libcurl.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ArgType {
+	Long,
+	ObjectPoint,
+	FunctionPoint,
+	OffT,
+	Blob,
+}
+
+impl ArgType {
+	/// Get the argument type of an option from the option ID.
+	pub fn of(option: CURLoption) -> Option<Self> {
+		match option / 10_000 * 10_000 {
+			curl_sys::CURLOPTTYPE_LONG          => Some(Self::Long),
+			curl_sys::CURLOPTTYPE_OBJECTPOINT   => Some(Self::ObjectPoint),
+			curl_sys::CURLOPTTYPE_FUNCTIONPOINT => Some(Self::FunctionPoint),
+			curl_sys::CURLOPTTYPE_OFF_T         => Some(Self::OffT),
+			curl_sys::CURLOPTTYPE_BLOB          => Some(Self::Blob),
+			_ => None,
+		}
+	}
+}
+
+impl Arg<'_> {
+	/// Interpret a raw argument according to the argument type encoded in the option ID.
+	///
+	/// # Safety
+	/// The raw argument must have been read with `va_arg()` using the same argument type.
+	pub unsafe fn from_raw(option: CURLoption, raw: &RawArg) -> Option<Self> {
+		match ArgType::of(option)? {
+			ArgType::Long          => Some(Self::Long(raw.long)),
+			ArgType::OffT          => Some(Self::OffT(raw.off_t)),
+			ArgType::ObjectPoint   => Some(Self::Pointer(raw.pointer)),
+			ArgType::Blob          => Some(Self::Blob(raw.pointer.cast())),
+			ArgType::FunctionPoint => Some(Self::Function(raw.function)),
+		}
+	}
+
+	/// The argument type that libcurl reads for this argument.
+	pub fn arg_type(&self) -> ArgType {
+		match self {
+			Self::Long(_)     => ArgType::Long,
+			Self::OffT(_)     => ArgType::OffT,
+			Self::String(_)   => ArgType::ObjectPoint,
+			Self::Slist(_)    => ArgType::ObjectPoint,
+			Self::Blob(_)     => ArgType::Blob,
+			Self::File(_)     => ArgType::ObjectPoint,
+			Self::Pointer(_)  => ArgType::ObjectPoint,
+			Self::Function(_) => ArgType::FunctionPoint,
+		}
+	}
+}
+
+/// Call `curl_easy_setopt()` with an argument of the correct C type.
+///
+/// If the argument does not match the type encoded in the option ID, libcurl is not called at all
+/// and CURLE_BAD_FUNCTION_ARGUMENT is returned, since libcurl would read the argument as the wrong type.
+pub fn call(curl_easy_setopt: CurlEasySetOpt, handle: *mut CURL, option: CURLoption, arg: Arg) -> CURLcode {
+	if ArgType::of(option) != Some(arg.arg_type()) {
+		return curl_sys::CURLE_BAD_FUNCTION_ARGUMENT;
+	}
+
+	match arg {
+		Arg::Long(x)     => curl_easy_setopt(handle, option, x),
+		Arg::OffT(x)     => curl_easy_setopt(handle, option, x),
+		Arg::String(x)   => curl_easy_setopt(handle, option, x.as_ptr()),
+		Arg::Slist(x)    => curl_easy_setopt(handle, option, x),
+		Arg::Blob(x)     => curl_easy_setopt(handle, option, x),
+		Arg::File(x)     => curl_easy_setopt(handle, option, x),
+		Arg::Pointer(x)  => curl_easy_setopt(handle, option, x),
+		Arg::Function(x) => curl_easy_setopt(handle, option, x),
+	}
+}
+
+/// A list of strings in the memory layout of a `struct curl_slist`.
+///
+/// The nodes are allocated by us rather than with `curl_slist_append()`,
+/// which is fine because libcurl never frees a list passed to `curl_easy_setopt()`.
+pub struct Slist {
+	_items: Vec<CString>,
+	nodes: Box<[curl_slist]>,
+}
+
+// SAFETY: the list is never modified after construction, and the pointers only refer to data owned by the list itself.
+unsafe impl Send for Slist {}
+unsafe impl Sync for Slist {}
+
+impl Slist {
+	pub fn new(items: Vec<CString>) -> Self {
+		let mut nodes: Box<[curl_slist]> = items.iter()
+			.map(|x| curl_slist { data: x.as_ptr().cast_mut(), next: std::ptr::null_mut() })
+			.collect();
+		for i in 1..nodes.len() {
+			nodes[i - 1].next = &mut nodes[i];
+		}
+		Self { _items: items, nodes }
+	}
+
+	/// Get a pointer to the first node of the list, or a null pointer if the list is empty.
+	pub fn as_ptr(&self) -> *const curl_slist {
+		self.nodes.first().map_or(std::ptr::null(), |x| x)
+	}
+}
+
+/// Iterate over the items of a `struct curl_slist` owned by someone else.
+///
+/// # Safety
+/// The list must be null or a valid list, and it must not be modified or freed while the iterator is used.
+pub unsafe fn slist_items<'a>(list: *const curl_slist) -> impl Iterator<Item = &'a CStr> {
+	let mut node = list;
+	std::iter::from_fn(move || {
+		while let Some(current) = node.as_ref() {
+			node = current.next;
+			if !current.data.is_null() {
+				return Some(CStr::from_ptr(current.data));
+			}
+		}
+		None
+	})
+}
+
+/// Binary data with a `struct curl_blob` that refers to it.
+///
+/// The blob uses CURL_BLOB_COPY, so libcurl does not hold on to our memory.
+pub struct Blob {
+	_data: Vec<u8>,
+	blob: curl_blob,
+}
+
+// SAFETY: the blob is never modified after construction, and the pointer only refers to data owned by the blob itself.
+unsafe impl Send for Blob {}
+unsafe impl Sync for Blob {}
+
+impl Blob {
+	pub fn new(mut data: Vec<u8>) -> Self {
+		let blob = curl_blob {
+			data: data.as_mut_ptr().cast::<c_void>(),
+			len: data.len(),
+			flags: curl_sys::CURL_BLOB_COPY,
+		};
+		Self { _data: data, blob }
+	}
+
+	/// Get a pointer to the `struct curl_blob` that refers to the data.
+	pub fn as_ptr(&self) -> *const curl_blob {
+		&self.blob
+	}
+}
+
+impl Clone for Blob {
+	fn clone(&self) -> Self {
+		Self::new(self._data.clone())
+	}
+}
+
+/// A C `FILE *` that is kept open for the rest of the process.
+///
+/// The file is handed to libcurl, which may write to it at any time, so it is never closed.
+pub struct File {
+	file: *mut libc::FILE,
+}
+
+// SAFETY: stdio streams are locked internally by the C library.
+unsafe impl Send for File {}
+unsafe impl Sync for File {}
+
+impl File {
+	/// Open a file for appending.
+	pub fn open(path: &CStr) -> Result<Self, String> {
+		let file = unsafe { libc::fopen(path.as_ptr(), c"a".as_ptr()) };
+		if file.is_null() {
+			return Err(format!("failed to open {}: {}", path.to_string_lossy(), std::io::Error::last_os_error()));
+		}
+		Ok(Self { file })
+	}
+}
+
+/// The value of an option, converted to the representation that libcurl expects.
+enum Prepared {
+	Long(c_long),
+	OffT(curl_off_t),
+	String(CString),
+	Slist(Slist),
+	Blob(Blob),
+	File(File),
+}
+
+/// An option that is ready to be passed to `curl_easy_setopt()`.
+pub struct PreparedOption {
+	/// The option as it was given to us.
+	pub option: SetOption,
+
+	/// The value converted to its C representation.
+	value: Prepared,
+}
+
+impl PreparedOption {
+	/// Convert an option to the C representation of its value.
+	///
+	/// This opens the file for file options and reads it for blob options.
+	pub fn new(option: SetOption) -> Result<Self, String> {
+		let value = match &option.value {
+			Value::CString(x) => Prepared::String(x.clone()),
+			Value::CLong(x)   => Prepared::Long(*x),
+			Value::COffT(x)   => Prepared::OffT(*x),
+			Value::CSlist(x)  => Prepared::Slist(Slist::new(x.clone())),
+			Value::CBlob(x)   => Prepared::Blob(Blob::new(std::fs::read(OsStr::from_bytes(x.as_bytes())).map_err(|e| format!("option {}: failed to read {}: {}", option.name, x.to_string_lossy(), e))?)),
+			Value::CFile(x)   => Prepared::File(File::open(x).map_err(|e| format!("option {}: {}", option.name, e))?),
+		};
+		Ok(Self { option, value })
+	}
+
+	/// Get the argument to pass to `curl_easy_setopt()`.
+	pub fn arg(&self) -> Arg<'_> {
+		match &self.value {
+			Prepared::Long(x)   => Arg::Long(*x),
+			Prepared::OffT(x)   => Arg::OffT(*x),
+			Prepared::String(x) => Arg::String(x),
+			Prepared::Slist(x)  => Arg::Slist(x.as_ptr()),
+			Prepared::Blob(x)   => Arg::Blob(&x.blob),
+			Prepared::File(x)   => Arg::File(x.file),
+		}
+	}
+
+	/// Set the option on a handle.
+	pub fn set(&self, curl_easy_setopt: CurlEasySetOpt, handle: *mut CURL) -> CURLcode {
+		call(curl_easy_setopt, handle, self.option.option, self.arg())
+	}
+}
+
+/// A copy of a value that the application set with `curl_easy_setopt()`, so it can be restored later.
+///
+/// Strings and blobs are copied, because libcurl copies them too and the application may free them afterwards.
+/// Lists and files belong to the application, which must keep them alive for as long as libcurl may use them anyway.
+#[derive(Clone)]
+pub enum Saved {
+	Long(c_long),
+	OffT(curl_off_t),
+	String(CString),
+	Blob(Blob),
+	Pointer(usize),
+	Function(Option<Function>),
+	Default(ArgType),
+}
+
+impl Saved {
+	/// Save an argument that the application passed for an option of the given kind.
+	///
+	/// # Safety
+	/// Pointer arguments must be valid pointers for an option of the given kind, or null.
+	pub unsafe fn new(kind: Kind, arg: Arg) -> Self {
+		match arg {
+			Arg::Long(x)     => Self::Long(x),
+			Arg::OffT(x)     => Self::OffT(x),
+			Arg::String(x)   => Self::String(x.into()),
+			Arg::Slist(x)    => Self::Pointer(x as usize),
+			Arg::File(x)     => Self::Pointer(x as usize),
+			Arg::Function(x) => Self::Function(x),
+			Arg::Blob(x) => match x.as_ref() {
+				Some(x) => Self::Blob(Blob::new(std::slice::from_raw_parts(x.data.cast::<u8>(), x.len).to_vec())),
+				None    => Self::Default(ArgType::Blob),
+			},
+			Arg::Pointer(x) => match kind {
+				Kind::CString if !x.is_null() => Self::String(CStr::from_ptr(x.cast()).into()),
+				_                             => Self::Pointer(x as usize),
+			},
+		}
+	}
+
+	/// The value that libcurl uses when an option was never set: zero or a null pointer.
+	pub fn default_for(option: CURLoption) -> Option<Self> {
+		Some(Self::Default(ArgType::of(option)?))
+	}
+
+	/// Get the argument to pass to `curl_easy_setopt()` to restore the value.
+	pub fn arg(&self) -> Arg<'_> {
+		match self {
+			Self::Long(x)     => Arg::Long(*x),
+			Self::OffT(x)     => Arg::OffT(*x),
+			Self::String(x)   => Arg::String(x),
+			Self::Blob(x)     => Arg::Blob(&x.blob),
+			Self::Pointer(x)  => Arg::Pointer(*x as *mut c_void),
+			Self::Function(x) => Arg::Function(*x),
+			Self::Default(ArgType::Long)          => Arg::Long(0),
+			Self::Default(ArgType::OffT)          => Arg::OffT(0),
+			Self::Default(ArgType::ObjectPoint)   => Arg::Pointer(std::ptr::null_mut()),
+			Self::Default(ArgType::Blob)          => Arg::Blob(std::ptr::null()),
+			Self::Default(ArgType::FunctionPoint) => Arg::Function(None),
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use curl_inject_opt_shared::{Kind, OPTIONS};
+	use crate::testing::{TempPath, fake_handle};
+
+	/// What the recording stand-in for `curl_easy_setopt()` read with `va_arg()`.
+	#[repr(C)]
+	#[derive(Debug)]
+	struct Record {
+		handle: *mut CURL,
+		option: CURLoption,
+		long: c_long,
+		off_t: curl_off_t,
+		pointer: *mut c_void,
+		function: Option<Function>,
+	}
+
+	#[link(name = "curl_inject_opt_setopt_test", kind = "static")]
+	unsafe extern "C" {
+		safe fn curl_inject_opt_test_record_setopt(handle: *mut CURL, option: CURLoption, ...) -> CURLcode;
+		fn curl_inject_opt_test_last_setopt() -> Record;
+		safe fn curl_easy_setopt(handle: *mut CURL, option: CURLoption, ...) -> CURLcode;
+	}
+
+	fn record(option: &PreparedOption) -> Record {
+		let handle = fake_handle(1);
+		assert!(option.set(curl_inject_opt_test_record_setopt, handle) == curl_sys::CURLE_OK);
+		let record = unsafe { curl_inject_opt_test_last_setopt() };
+		assert!(record.handle == handle);
+		assert!(record.option == option.option.option);
+		record
+	}
+
+	fn prepare(name: &str, value: Value) -> PreparedOption {
+		PreparedOption::new(SetOption::parse_name(name, value).unwrap()).unwrap()
+	}
+
+	#[test]
+	fn test_long() {
+		let record = record(&prepare("timeout", Value::CLong(-1234)));
+		assert!(record.long == -1234);
+	}
+
+	#[test]
+	fn test_off_t() {
+		let record = record(&prepare("max-recv-speed", Value::COffT(0x1234_5678_9abc)));
+		assert!(record.off_t == 0x1234_5678_9abc);
+	}
+
+	#[test]
+	fn test_string() {
+		let option = prepare("proxy", Value::CString(c"socks5://localhost".into()));
+		let record = record(&option);
+		assert!(unsafe { CStr::from_ptr(record.pointer.cast()) } == c"socks5://localhost");
+	}
+
+	#[test]
+	fn test_slist() {
+		let option = prepare("resolve", Value::CSlist(vec![c"a:1:127.0.0.1".into(), c"b:2:127.0.0.2".into()]));
+		let record = record(&option);
+		let mut items = Vec::new();
+		let mut node = record.pointer.cast::<curl_slist>().cast_const();
+		while !node.is_null() {
+			let node_ref = unsafe { &*node };
+			items.push(unsafe { CStr::from_ptr(node_ref.data) }.to_owned());
+			node = node_ref.next;
+		}
+		assert!(items == [c"a:1:127.0.0.1", c"b:2:127.0.0.2"]);
+	}
+
+	#[test]
+	fn test_blob() {
+		let path = TempPath::new("blob");
+		std::fs::write(&path, b"\0binary\xffdata").unwrap();
+		let option = prepare("ca-info-blob", Value::CBlob(CString::new(path.as_os_str().as_bytes()).unwrap()));
+		let record = record(&option);
+		let blob = unsafe { &*record.pointer.cast::<curl_blob>() };
+		let data = unsafe { std::slice::from_raw_parts(blob.data.cast::<u8>(), blob.len) };
+		assert!(data == b"\0binary\xffdata");
+		assert!(blob.flags == curl_sys::CURL_BLOB_COPY);
+	}
+
+	#[test]
+	fn test_file() {
+		let file = File { file: unsafe { libc::fdopen(libc::dup(2), c"a".as_ptr()) } };
+		let option = PreparedOption {
+			option: SetOption::parse_name("stderr", Value::CFile(c"/dev/stderr".into())).unwrap(),
+			value: Prepared::File(File { file: file.file }),
+		};
+		let record = record(&option);
+		assert!(record.pointer == file.file.cast());
+	}
+
+	#[test]
+	fn test_function() {
+		extern "C" fn callback() {}
+		let handle = fake_handle(1);
+		let arg = Arg::Function(Some(callback));
+		assert!(call(curl_inject_opt_test_record_setopt, handle, curl_sys::CURLOPT_WRITEFUNCTION, arg) == curl_sys::CURLE_OK);
+		let record = unsafe { curl_inject_opt_test_last_setopt() };
+		assert_eq!(record.function.map(|x| x as usize), Some(callback as *const () as usize));
+	}
+
+	#[test]
+	fn test_wrong_type_is_refused() {
+		let handle = fake_handle(1);
+		assert!(call(curl_inject_opt_test_record_setopt, handle, curl_sys::CURLOPT_MAX_RECV_SPEED_LARGE, Arg::Long(10)) == curl_sys::CURLE_BAD_FUNCTION_ARGUMENT);
+		assert!(call(curl_inject_opt_test_record_setopt, handle, curl_sys::CURLOPT_PROXYTYPE, Arg::String(c"http")) == curl_sys::CURLE_BAD_FUNCTION_ARGUMENT);
+	}
+
+	/// Check that the kind of every known option matches the argument type encoded in the option ID.
+	#[test]
+	fn test_option_kinds_match_option_ids() {
+		for meta in OPTIONS {
+			let expected = match meta.kind {
+				Kind::CLong   => ArgType::Long,
+				Kind::COffT   => ArgType::OffT,
+				Kind::CString => ArgType::ObjectPoint,
+				Kind::CSlist  => ArgType::ObjectPoint,
+				Kind::CBlob   => ArgType::Blob,
+				Kind::CFile   => ArgType::ObjectPoint,
+			};
+			assert_eq!(ArgType::of(meta.option), Some(expected), "wrong kind for {}", meta.curl_name);
+		}
+	}
+
+	/// Set every known option with a sample value on a real libcurl handle.
+	#[test]
+	fn test_set_options_on_real_libcurl() {
+		let samples = [
+			("verbose", Value::CLong(0)),
+			("stderr", Value::CFile(c"/dev/null".into())),
+			("timeout", Value::CLong(1000)),
+			("max-recv-speed", Value::COffT(1 << 40)),
+			("resolve", Value::CSlist(vec![c"example.com:443:127.0.0.1".into()])),
+			("proxy", Value::CString(c"http://localhost:1234".into())),
+			("proxy-type", Value::CLong(curl_sys::CURLPROXY_HTTP as c_long)),
+			("accept-encoding", Value::CString(c"gzip".into())),
+			("protocols-str", Value::CString(c"http,https".into())),
+			("ca-info-blob", Value::CBlob(c"/dev/null".into())),
+		];
+		unsafe {
+			let handle = curl_sys::curl_easy_init();
+			assert!(!handle.is_null());
+			for (name, value) in samples {
+				let option = prepare(name, value);
+				let code = option.set(curl_easy_setopt, handle);
+				assert!(code == curl_sys::CURLE_OK, "failed to set {}", name);
+			}
+			curl_sys::curl_easy_cleanup(handle);
+		}
+	}
+}
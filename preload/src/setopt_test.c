@@ -0,0 +1,43 @@
+/* A recording stand-in for curl_easy_setopt(), used by the tests in setopt.rs.
+ *
+ * It reads the variadic argument with va_arg() exactly like libcurl does,
+ * based on the argument type encoded in the option ID.
+ */
+#include <stdarg.h>
+#include <stdint.h>
+
+struct record {
+	void * handle;
+	int option;
+	long long_value;
+	int64_t off_t_value;
+	void * pointer;
+	void (*function)(void);
+};
+
+static _Thread_local struct record last;
+
+int curl_inject_opt_test_record_setopt(void * handle, int option, ...) {
+	struct record record = {0};
+	record.handle = handle;
+	record.option = option;
+
+	va_list args;
+	va_start(args, option);
+	switch (option / 10000) {
+		case 0: record.long_value  = va_arg(args, long);               break;
+		case 1: record.pointer     = va_arg(args, void *);             break;
+		case 2: record.function    = va_arg(args, void (*)(void));     break;
+		case 3: record.off_t_value = va_arg(args, int64_t);            break;
+		case 4: record.pointer     = va_arg(args, void *);             break;
+		default: va_end(args); return 48; /* CURLE_UNKNOWN_OPTION */
+	}
+	va_end(args);
+
+	last = record;
+	return 0;
+}
+
+struct record curl_inject_opt_test_last_setopt(void) {
+	return last;
+}
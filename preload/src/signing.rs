@@ -0,0 +1,137 @@
+use curl_inject_opt_shared::UrlParts;
+use curl_inject_opt_shared::headers::has_name;
+use curl_inject_opt_shared::reexports::curl_sys::CURL;
+
+use crate::{post_fields, CurlInjectOpt};
+use crate::date::Date;
+use crate::handles::{PostFields, RequestKind};
+use crate::random::hex;
+
+/// The value of the content hash header for a body that is not known before the transfer starts.
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+/// Signs requests with HMAC-SHA256 for the `header-sign` rules.
+#[derive(Default)]
+pub struct Signer {
+	/// The secret key, or `None` if no key was configured.
+	key: Option<Vec<u8>>,
+
+	/// The ID of the key, to tell the server which key was used.
+	key_id: Option<String>,
+}
+
+/// The body of a request, as far as it is known before the transfer starts.
+#[derive(Clone, Copy, Debug)]
+pub enum Body<'a> {
+	/// The request has no body.
+	Empty,
+
+	/// The full body, as set with CURLOPT_POSTFIELDS or CURLOPT_COPYPOSTFIELDS.
+	Data(&'a [u8]),
+
+	/// The body is read from a callback or built from MIME parts while the transfer runs.
+	Unknown,
+}
+
+impl Signer {
+	/// Read the key from the file in CURL_INJECT_OPT_SIGN_KEY, and the key ID from CURL_INJECT_OPT_SIGN_KEY_ID.
+	///
+	/// A single trailing newline in the key file is ignored.
+	pub fn from_env() -> Result<Self, String> {
+		let Some(path) = std::env::var_os("CURL_INJECT_OPT_SIGN_KEY").filter(|x| !x.is_empty()) else {
+			return Ok(Self::default());
+		};
+		let mut key = std::fs::read(&path).map_err(|e| format!("failed to read signing key {}: {}", path.to_string_lossy(), e))?;
+		if key.ends_with(b"\n") {
+			key.pop();
+			if key.ends_with(b"\r") {
+				key.pop();
+			}
+		}
+		if key.is_empty() {
+			return Err(format!("signing key {} is empty", path.to_string_lossy()));
+		}
+		let key_id = std::env::var("CURL_INJECT_OPT_SIGN_KEY_ID").ok().filter(|x| !x.is_empty());
+		if key_id.as_ref().is_some_and(|x| x.contains(|c: char| c.is_ascii_control() || c == ',')) {
+			return Err(String::from("invalid signing key ID: it can not contain commas or control characters"));
+		}
+		Ok(Self { key: Some(key), key_id })
+	}
+
+	/// Check if a signing key is configured.
+	pub fn is_enabled(&self) -> bool {
+		self.key.is_some()
+	}
+
+	/// Sign a request, replacing the `Date`, `X-Content-SHA256` and signature headers.
+	///
+	/// The signature is the HMAC-SHA256 of the method, the path with the query, the date and the SHA-256 of the body, separated by newlines.
+	pub fn sign(&self, headers: &mut Vec<Vec<u8>>, header: &str, method: &str, target: &str, body: Body, date: &Date) -> Result<(), String> {
+		let Some(key) = &self.key else {
+			return Err(format!("can not sign request for {} header: no signing key configured", header));
+		};
+		let body_hash = match body {
+			Body::Empty      => hex(&hmac_sha256::Hash::hash(b"")),
+			Body::Data(data) => hex(&hmac_sha256::Hash::hash(data)),
+			Body::Unknown    => String::from(UNSIGNED_PAYLOAD),
+		};
+		let date = date.http();
+		let string_to_sign = format!("{}\n{}\n{}\n{}", method, target, date, body_hash);
+		let signature = hex(&hmac_sha256::HMAC::mac(string_to_sign.as_bytes(), key));
+		let value = match &self.key_id {
+			Some(key_id) => format!("HMAC-SHA256 KeyId={}, Signature={}", key_id, signature),
+			None         => format!("HMAC-SHA256 Signature={}", signature),
+		};
+
+		for (name, value) in [("Date", date), ("X-Content-SHA256", body_hash), (header, value)] {
+			headers.retain(|x| !has_name(x, name));
+			headers.push(format!("{}: {}", name, value).into_bytes());
+		}
+		Ok(())
+	}
+}
+
+impl CurlInjectOpt {
+	/// Sign the request of a transfer, and add the signature to the request headers.
+	pub fn sign_request(&self, handle: *mut CURL, url: Option<&UrlParts>, header: &str, headers: &mut Vec<Vec<u8>>) -> Result<(), String> {
+		let url = url.ok_or_else(|| format!("can not sign request for {} header: URL of transfer is unknown", header))?;
+		let target = match url.query.is_empty() {
+			true  => url.path.clone(),
+			false => format!("{}?{}", url.path, url.query),
+		};
+		let request = self.handles.with(handle, |state| state.request.clone());
+		let body = match (request.kind, &request.fields) {
+			(RequestKind::Get | RequestKind::Head, _)            => Body::Empty,
+			(RequestKind::Post, Some(PostFields::Copy(data)))    => Body::Data(data),
+			// SAFETY: libcurl requires the data of CURLOPT_POSTFIELDS to stay valid until the transfer is done.
+			(RequestKind::Post, Some(PostFields::Pointer(data))) => Body::Data(unsafe { post_fields(*data, request.fields_size) }),
+			_                                                    => Body::Unknown,
+		};
+		self.signer.sign(headers, header, &request.method(), &target, body, &Date::now())
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_sign() {
+		let signer = Signer { key: Some(b"secret".to_vec()), key_id: Some(String::from("agent-1")) };
+		let mut headers = vec![b"date: yesterday".to_vec(), b"Content-Type: application/json".to_vec()];
+		let date = Date::from_unix(1_792_411_200);
+		signer.sign(&mut headers, "Authorization", "POST", "/v1/items?x=1", Body::Data(br#"{"a":1}"#), &date).unwrap();
+		let headers: Vec<_> = headers.into_iter().map(|x| String::from_utf8(x).unwrap()).collect();
+		assert_eq!(headers, [
+			"Content-Type: application/json",
+			"Date: Mon, 19 Oct 2026 12:00:00 GMT",
+			"X-Content-SHA256: 015abd7f5cc57a2dd94b7590f04ad8084273905ee33ec5cebeae62276a97f862",
+			"Authorization: HMAC-SHA256 KeyId=agent-1, Signature=ed04e26bb6c2c91bdfafa2762daed90362ceef2bc7e7de35951ec6fd08703c97",
+		]);
+
+		let mut headers = Vec::new();
+		signer.sign(&mut headers, "X-Signature", "PUT", "/upload", Body::Unknown, &date).unwrap();
+		assert_eq!(headers[1], b"X-Content-SHA256: UNSIGNED-PAYLOAD");
+		assert!(Signer::default().sign(&mut headers, "X-Signature", "GET", "/", Body::Empty, &date).is_err());
+	}
+}
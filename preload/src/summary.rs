@@ -0,0 +1,40 @@
+use std::fs::File;
+use std::io::Write;
+
+use crate::request_log::Transfer;
+
+/// Records finished transfers for the summary that `curl-inject-opt --summary` prints when the command exits.
+#[derive(Default)]
+pub struct SummaryRecorder {
+	/// The file that all processes of the command append their records to, or `None` if there is no summary.
+	file: Option<File>,
+}
+
+impl SummaryRecorder {
+	/// Open the summary file from the CURL_INJECT_OPT_SUMMARY environment variable.
+	pub fn from_env() -> Result<Self, String> {
+		let Some(path) = std::env::var_os("CURL_INJECT_OPT_SUMMARY").filter(|x| !x.is_empty()) else {
+			return Ok(Self::default());
+		};
+		let file = std::fs::OpenOptions::new()
+			.append(true)
+			.open(&path)
+			.map_err(|e| format!("failed to open summary file {}: {}", path.to_string_lossy(), e))?;
+		Ok(Self { file: Some(file) })
+	}
+
+	/// Check if transfers are recorded.
+	pub fn is_enabled(&self) -> bool {
+		self.file.is_some()
+	}
+
+	/// Record a transfer.
+	///
+	/// Records are written as soon as a transfer finishes, so processes that never exit normally are included too.
+	pub fn record(&self, transfer: &Transfer) -> Result<(), String> {
+		let Some(mut file) = self.file.as_ref() else {
+			return Ok(());
+		};
+		file.write_all(transfer.to_record().to_line().as_bytes()).map_err(|e| format!("failed to write summary record: {}", e))
+	}
+}
@@ -0,0 +1,194 @@
+use std::ffi::{CStr, CString};
+use std::marker::PhantomData;
+use std::os::raw::{c_int, c_void};
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+/// A symbol that is resolved lazily from the next object in the search order or from a library loaded with `dlopen()`.
+///
+/// Resolution is retried on every use until it succeeds,
+/// so a libcurl that is loaded after the program started can still be found.
+pub struct Symbol {
+	name: &'static CStr,
+	version: Option<&'static CStr>,
+	address: AtomicPtr<c_void>,
+}
+
+/// A lazily resolved function with a known type.
+pub struct NextFn<T> {
+	pub symbol: Symbol,
+	_type: PhantomData<T>,
+}
+
+impl Symbol {
+	pub const fn new(name: &'static CStr, version: Option<&'static CStr>) -> Self {
+		Self {
+			name,
+			version,
+			address: AtomicPtr::new(std::ptr::null_mut()),
+		}
+	}
+
+	/// Get the address of the symbol, trying to resolve it if that has not happened yet.
+	pub fn address(&self) -> Result<*mut c_void, String> {
+		let address = self.address.load(Ordering::Acquire);
+		if !address.is_null() {
+			return Ok(address);
+		}
+
+		// A libcurl loaded with `dlopen()` and RTLD_LOCAL is not in the search order, so try each loaded library too.
+		let address = lookup(libc::RTLD_NEXT, self.name, self.version)
+			.and_then(|x| reject_own(x, self.name, self.version))
+			.or_else(|e| lookup_loaded(self.name, self.version).map_err(|_| e))?;
+		self.offer(address);
+		Ok(self.address.load(Ordering::Acquire))
+	}
+
+	/// Use the given address for the symbol, unless it has been resolved already.
+	pub fn offer(&self, address: *mut c_void) {
+		let _ = self.address.compare_exchange(std::ptr::null_mut(), address, Ordering::AcqRel, Ordering::Acquire);
+	}
+}
+
+impl<T: Copy> NextFn<T> {
+	pub const fn new(name: &'static CStr, version: Option<&'static CStr>) -> Self {
+		Self {
+			symbol: Symbol::new(name, version),
+			_type: PhantomData,
+		}
+	}
+
+	/// Get the function, trying to resolve it if that has not happened yet.
+	pub fn get(&self) -> Result<T, String> {
+		let address = self.symbol.address()?;
+		// SAFETY: T is a function pointer type matching the C declaration of the symbol.
+		Ok(unsafe { std::mem::transmute_copy(&address) })
+	}
+}
+
+/// Look up a symbol with `dlsym()`, or with `dlvsym()` if a version is given.
+pub fn lookup(handle: *mut c_void, name: &CStr, version: Option<&CStr>) -> Result<*mut c_void, String> {
+	unsafe {
+		// Clear dlerror() before calling dlsym().
+		libc::dlerror();
+
+		// Look-up the wanted symbol.
+		let symbol = match version {
+			Some(version) => libc::dlvsym(handle, name.as_ptr(), version.as_ptr()),
+			None          => libc::dlsym(handle, name.as_ptr()),
+		};
+
+		// Check dlerror().
+		let error = libc::dlerror();
+		let name  = display_name(name, version);
+		if !error.is_null() {
+			Err(format!("failed to look up symbol {}: {}", name, CStr::from_ptr(error).to_string_lossy()))
+		} else if symbol.is_null() {
+			Err(format!("failed to look up symbol {}: symbol is null", name))
+		} else {
+			Ok(symbol)
+		}
+	}
+}
+
+/// Look up a symbol in each library that is loaded, except this one.
+///
+/// The libraries are opened again with RTLD_NOLOAD, so this never loads a library.
+fn lookup_loaded(name: &CStr, version: Option<&CStr>) -> Result<*mut c_void, String> {
+	for path in loaded_objects() {
+		// SAFETY: The path is a null-terminated string, and RTLD_NOLOAD only returns libraries that are loaded already.
+		let handle = unsafe { libc::dlopen(path.as_ptr(), libc::RTLD_LAZY | libc::RTLD_NOLOAD) };
+		if handle.is_null() {
+			continue;
+		}
+		let address = lookup(handle, name, version).and_then(|x| reject_own(x, name, version));
+		// SAFETY: The handle was returned by dlopen() above.
+		unsafe { libc::dlclose(handle) };
+		if address.is_ok() {
+			return address;
+		}
+	}
+
+	// Don't leave errors from our own lookups behind for the program.
+	// SAFETY: The function is safe to call at any time.
+	unsafe { libc::dlerror() };
+	Err(format!("failed to look up symbol {}: not found in any loaded library", display_name(name, version)))
+}
+
+/// Get the paths of the loaded libraries, without the main program and the vDSO.
+fn loaded_objects() -> Vec<CString> {
+	extern "C" fn add(info: *mut libc::dl_phdr_info, _: usize, data: *mut c_void) -> c_int {
+		// SAFETY: dl_iterate_phdr() passes valid info, and the data is the vector of loaded_objects().
+		let (name, paths) = unsafe { ((*info).dlpi_name, &mut *data.cast::<Vec<CString>>()) };
+		if !name.is_null() {
+			// SAFETY: The name of a loaded object is a null-terminated string.
+			let name = unsafe { CStr::from_ptr(name) };
+			if name.to_bytes().contains(&b'/') {
+				paths.push(name.to_owned());
+			}
+		}
+		0
+	}
+
+	let mut paths = Vec::<CString>::new();
+	// SAFETY: The callback only touches the vector, which outlives the call.
+	unsafe { libc::dl_iterate_phdr(Some(add), (&mut paths as *mut Vec<CString>).cast()) };
+	paths
+}
+
+/// Refuse an address in this library, which would be one of our own hooks instead of the function of libcurl.
+fn reject_own(address: *mut c_void, name: &CStr, version: Option<&CStr>) -> Result<*mut c_void, String> {
+	match is_own(address) {
+		true  => Err(format!("failed to look up symbol {}: found the hook of curl-inject-opt", display_name(name, version))),
+		false => Ok(address),
+	}
+}
+
+/// Check if an address belongs to the object that contains this library.
+fn is_own(address: *const c_void) -> bool {
+	let object = |address: *const c_void| {
+		// SAFETY: dladdr() only reads the address, and fills in the info if it finds the object.
+		let mut info: libc::Dl_info = unsafe { std::mem::zeroed() };
+		match unsafe { libc::dladdr(address, &mut info) } {
+			0 => None,
+			_ => Some(info.dli_fbase),
+		}
+	};
+	let own = object(is_own as *const c_void);
+	own.is_some() && object(address) == own
+}
+
+fn display_name(name: &CStr, version: Option<&CStr>) -> String {
+	match version {
+		Some(version) => format!("{}@{}", name.to_string_lossy(), version.to_string_lossy()),
+		None          => name.to_string_lossy().into_owned(),
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	/// `dlsym(RTLD_NEXT)` looks up the symbol after the object of the caller, which a wrapper in this library can not do.
+	/// A `dlsym()` defined by this crate would take over the calls in the test binary, so check that they go to the C library.
+	#[test]
+	fn test_dlsym_is_not_wrapped() {
+		let mut info: libc::Dl_info = unsafe { std::mem::zeroed() };
+		assert!(unsafe { libc::dladdr(libc::dlsym as *const c_void, &mut info) } != 0);
+		let object = unsafe { CStr::from_ptr(info.dli_fname) }.to_string_lossy();
+		assert!(object.contains("libc.so"), "dlsym() is defined in {}", object);
+
+		let malloc = unsafe { libc::dlsym(libc::RTLD_DEFAULT, c"malloc".as_ptr()) };
+		assert!(lookup(libc::RTLD_NEXT, c"malloc", None) == Ok(malloc));
+	}
+
+	#[test]
+	fn test_lookup_loaded() {
+		let malloc = unsafe { libc::dlsym(libc::RTLD_DEFAULT, c"malloc".as_ptr()) };
+		assert!(lookup_loaded(c"malloc", None) == Ok(malloc));
+		assert!(lookup_loaded(c"curl_inject_opt_no_such_symbol", None).is_err());
+
+		// The hooks of this library must never be taken for the functions they wrap.
+		assert!(is_own(test_lookup_loaded as *const c_void));
+		assert!(!is_own(malloc));
+	}
+}
@@ -0,0 +1,46 @@
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+use curl_inject_opt_shared::reexports::curl_sys::CURL;
+
+/// A fake easy handle, for tests that only use handles as keys and never pass them to libcurl.
+///
+/// Different numbers give different handles, and handle 1 is `0x1234`.
+pub fn fake_handle(number: usize) -> *mut CURL {
+	(0x1234 * number) as *mut CURL
+}
+
+/// A path in the temporary directory, which is removed with everything in it when dropped.
+pub struct TempPath {
+	path: PathBuf,
+}
+
+impl TempPath {
+	/// Get a path for a test, unique to the test process.
+	pub fn new(name: &str) -> Self {
+		Self { path: std::env::temp_dir().join(format!("curl-inject-opt-{}-test-{}", name, std::process::id())) }
+	}
+}
+
+impl Deref for TempPath {
+	type Target = Path;
+
+	fn deref(&self) -> &Path {
+		&self.path
+	}
+}
+
+impl AsRef<Path> for TempPath {
+	fn as_ref(&self) -> &Path {
+		&self.path
+	}
+}
+
+impl Drop for TempPath {
+	fn drop(&mut self) {
+		let _ = match self.path.is_dir() {
+			true  => std::fs::remove_dir_all(&self.path),
+			false => std::fs::remove_file(&self.path),
+		};
+	}
+}
@@ -0,0 +1,346 @@
+use std::ffi::{CStr, CString};
+use std::os::raw::c_long;
+
+use curl_inject_opt_shared::reexports::curl_sys;
+
+use curl_sys::CURLoption;
+
+use crate::setopt::Arg;
+
+/// The lowest 16 bits of CURLOPT_SSLVERSION hold the minimum version, the next 16 bits the maximum version.
+const CURL_SSLVERSION_MAX_SHIFT: u32 = 16;
+
+/// The maximum version that stands for the newest version the TLS library supports, which curl-sys does not define.
+const CURL_SSLVERSION_MAX_DEFAULT: c_long = 1 << CURL_SSLVERSION_MAX_SHIFT;
+
+/// Cipher names (or parts of them) that indicate a weak cipher in an OpenSSL style cipher list.
+const WEAK_CIPHERS: &[&str] = &["NULL", "EXP", "RC2", "RC4", "DES", "MD5", "ADH", "AECDH", "LOW", "SSLV2", "SSLV3"];
+
+/// What to do when the application sets an insecure value.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Action {
+	/// Do nothing.
+	Off,
+
+	/// Print a warning and pass the value on.
+	Warn,
+
+	/// Silently replace the value with a secure one.
+	Fix,
+
+	/// Refuse the option with CURLE_BAD_FUNCTION_ARGUMENT.
+	Reject,
+}
+
+/// A kind of insecure TLS setting.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Check {
+	/// CURLOPT_SSL_VERIFYPEER set to 0.
+	VerifyPeer,
+
+	/// CURLOPT_SSL_VERIFYHOST set to something else than 2.
+	VerifyHost,
+
+	/// CURLOPT_SSLVERSION or CURLOPT_PROXY_SSLVERSION allowing versions below the minimum.
+	MinVersion,
+
+	/// CURLOPT_SSL_CIPHER_LIST or CURLOPT_PROXY_SSL_CIPHER_LIST enabling weak ciphers.
+	Ciphers,
+
+	/// CURLOPT_PROXY_SSL_VERIFYPEER set to 0.
+	ProxyVerifyPeer,
+}
+
+/// A secure replacement for an insecure value.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Fixed {
+	Long(c_long),
+	String(Option<CString>),
+}
+
+/// An insecure value set by the application.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Insecure {
+	/// The check that found the problem.
+	pub check: Check,
+
+	/// The name of the option.
+	pub option: &'static str,
+
+	/// A description of the value.
+	pub message: String,
+
+	/// A secure value to use instead.
+	pub fixed: Fixed,
+}
+
+/// The policy for TLS settings of the application.
+#[derive(Debug)]
+pub struct TlsPolicy {
+	/// The action for each check, in the order of [`Check::ALL`].
+	actions: [Action; 5],
+
+	/// The minimum TLS version as `CURL_SSLVERSION_*` value.
+	pub min_version: c_long,
+}
+
+impl Action {
+	/// Parse an action from a string.
+	pub fn parse(value: &str) -> Result<Self, String> {
+		if value.eq_ignore_ascii_case("off") {
+			Ok(Self::Off)
+		} else if value.eq_ignore_ascii_case("warn") {
+			Ok(Self::Warn)
+		} else if value.eq_ignore_ascii_case("fix") {
+			Ok(Self::Fix)
+		} else if value.eq_ignore_ascii_case("reject") {
+			Ok(Self::Reject)
+		} else {
+			Err(format!("invalid TLS policy action: {}, expected off, warn, fix or reject", value))
+		}
+	}
+}
+
+impl Check {
+	/// All checks.
+	pub const ALL: [Self; 5] = [Self::VerifyPeer, Self::VerifyHost, Self::MinVersion, Self::Ciphers, Self::ProxyVerifyPeer];
+
+	/// The name of the check in the policy.
+	pub fn name(self) -> &'static str {
+		match self {
+			Self::VerifyPeer      => "verify-peer",
+			Self::VerifyHost      => "verify-host",
+			Self::MinVersion      => "min-version",
+			Self::Ciphers         => "ciphers",
+			Self::ProxyVerifyPeer => "proxy-verify-peer",
+		}
+	}
+
+	/// A short description of the problem, for the audit report.
+	pub fn description(self) -> &'static str {
+		match self {
+			Self::VerifyPeer      => "TLS peer verification disabled",
+			Self::VerifyHost      => "TLS host name verification disabled",
+			Self::MinVersion      => "TLS versions below the minimum allowed",
+			Self::Ciphers         => "weak TLS ciphers enabled",
+			Self::ProxyVerifyPeer => "TLS peer verification disabled for the proxy",
+		}
+	}
+
+	fn index(self) -> usize {
+		Self::ALL.iter().position(|x| *x == self).unwrap()
+	}
+}
+
+/// Parse a TLS version like `1.2` into a `CURL_SSLVERSION_*` value.
+pub fn parse_version(value: &str) -> Result<c_long, String> {
+	match value {
+		"1.0" => Ok(curl_sys::CURL_SSLVERSION_TLSv1_0 as c_long),
+		"1.1" => Ok(curl_sys::CURL_SSLVERSION_TLSv1_1 as c_long),
+		"1.2" => Ok(curl_sys::CURL_SSLVERSION_TLSv1_2 as c_long),
+		"1.3" => Ok(curl_sys::CURL_SSLVERSION_TLSv1_3 as c_long),
+		_     => Err(format!("invalid TLS version: {}, expected 1.0, 1.1, 1.2 or 1.3", value)),
+	}
+}
+
+/// Get the rank of a `CURL_SSLVERSION_*` minimum version, so versions can be compared.
+///
+/// Returns `None` for the default, which is left to libcurl.
+fn min_version_rank(version: c_long) -> Option<c_long> {
+	match version {
+		0 => None,
+		x if x == curl_sys::CURL_SSLVERSION_TLSv1 as c_long => Some(curl_sys::CURL_SSLVERSION_TLSv1_0 as c_long),
+		x => Some(x),
+	}
+}
+
+/// Get the rank of a `CURL_SSLVERSION_MAX_*` maximum version, shifted down to the range of the minimum versions.
+///
+/// Returns `None` for no maximum and for `CURL_SSLVERSION_MAX_DEFAULT`, which both allow the newest version.
+fn max_version_rank(version: c_long) -> Option<c_long> {
+	match version {
+		0 => None,
+		x if x << CURL_SSLVERSION_MAX_SHIFT == CURL_SSLVERSION_MAX_DEFAULT => None,
+		x => Some(x),
+	}
+}
+
+impl TlsPolicy {
+	/// A policy that does nothing.
+	pub fn off() -> Self {
+		Self {
+			actions: [Action::Off; 5],
+			min_version: curl_sys::CURL_SSLVERSION_TLSv1_2 as c_long,
+		}
+	}
+
+	/// Parse the policy from a comma separated list of `ACTION` or `CHECK=ACTION` entries and a minimum TLS version.
+	///
+	/// An `ACTION` without check applies to all checks, later entries override earlier ones.
+	pub fn parse(policy: &str, min_version: &str) -> Result<Self, String> {
+		let mut result = Self::off();
+		result.min_version = parse_version(min_version)?;
+		for entry in policy.split(',').filter(|x| !x.is_empty()) {
+			match entry.split_once('=') {
+				None => result.actions = [Action::parse(entry)?; 5],
+				Some((name, action)) => {
+					let check = Check::ALL.iter().find(|x| x.name() == name).ok_or_else(|| format!("unknown TLS policy check: {}", name))?;
+					result.actions[check.index()] = Action::parse(action)?;
+				},
+			}
+		}
+		Ok(result)
+	}
+
+	/// Get the policy from the CURL_INJECT_OPT_TLS_POLICY and CURL_INJECT_OPT_TLS_MIN_VERSION environment variables.
+	pub fn from_env() -> Result<Self, String> {
+		let policy = std::env::var("CURL_INJECT_OPT_TLS_POLICY").unwrap_or_default();
+		let min_version = std::env::var("CURL_INJECT_OPT_TLS_MIN_VERSION").unwrap_or_else(|_| String::from("1.2"));
+		Self::parse(&policy, &min_version)
+	}
+
+	/// Get the action for a check.
+	pub fn action(&self, check: Check) -> Action {
+		self.actions[check.index()]
+	}
+
+	/// Check if the policy does anything at all.
+	pub fn is_enabled(&self) -> bool {
+		self.actions.iter().any(|x| *x != Action::Off)
+	}
+
+	/// Check a value set by the application with `curl_easy_setopt()`.
+	///
+	/// # Safety
+	/// If the argument is a pointer for a string option, it must be null or point to a null-terminated string.
+	pub unsafe fn check(&self, option: CURLoption, arg: Arg) -> Option<Insecure> {
+		match (option, arg) {
+			(curl_sys::CURLOPT_SSL_VERIFYPEER, Arg::Long(0)) => Some(Insecure {
+				check: Check::VerifyPeer,
+				option: "CURLOPT_SSL_VERIFYPEER",
+				message: String::from("CURLOPT_SSL_VERIFYPEER set to 0"),
+				fixed: Fixed::Long(1),
+			}),
+			(curl_sys::CURLOPT_SSL_VERIFYHOST, Arg::Long(value)) if value != 2 => Some(Insecure {
+				check: Check::VerifyHost,
+				option: "CURLOPT_SSL_VERIFYHOST",
+				message: format!("CURLOPT_SSL_VERIFYHOST set to {}", value),
+				fixed: Fixed::Long(2),
+			}),
+			(curl_sys::CURLOPT_PROXY_SSL_VERIFYPEER, Arg::Long(0)) => Some(Insecure {
+				check: Check::ProxyVerifyPeer,
+				option: "CURLOPT_PROXY_SSL_VERIFYPEER",
+				message: String::from("CURLOPT_PROXY_SSL_VERIFYPEER set to 0"),
+				fixed: Fixed::Long(1),
+			}),
+			(curl_sys::CURLOPT_SSLVERSION | curl_sys::CURLOPT_PROXY_SSLVERSION, Arg::Long(value)) => {
+				let fixed = self.fix_version(value)?;
+				let name = if option == curl_sys::CURLOPT_SSLVERSION { "CURLOPT_SSLVERSION" } else { "CURLOPT_PROXY_SSLVERSION" };
+				Some(Insecure {
+					check: Check::MinVersion,
+					option: name,
+					message: format!("{} set to {:#x}, which allows TLS versions below the minimum", name, value),
+					fixed: Fixed::Long(fixed),
+				})
+			},
+			(curl_sys::CURLOPT_SSL_CIPHER_LIST | curl_sys::CURLOPT_PROXY_SSL_CIPHER_LIST, Arg::Pointer(ciphers)) if !ciphers.is_null() => {
+				let ciphers = CStr::from_ptr(ciphers.cast()).to_string_lossy();
+				let fixed = fix_ciphers(&ciphers)?;
+				let name = if option == curl_sys::CURLOPT_SSL_CIPHER_LIST { "CURLOPT_SSL_CIPHER_LIST" } else { "CURLOPT_PROXY_SSL_CIPHER_LIST" };
+				Some(Insecure {
+					check: Check::Ciphers,
+					option: name,
+					message: format!("{} set to {}, which enables weak ciphers", name, ciphers),
+					fixed: Fixed::String(fixed),
+				})
+			},
+			_ => None,
+		}
+	}
+
+	/// Get a secure replacement for a CURLOPT_SSLVERSION value, or `None` if it is already secure.
+	fn fix_version(&self, value: c_long) -> Option<c_long> {
+		let min = value & 0xffff;
+		let max = value >> CURL_SSLVERSION_MAX_SHIFT;
+		let min_too_low = min_version_rank(min).is_some_and(|x| x < self.min_version);
+		let max_too_low = max_version_rank(max).is_some_and(|x| x < self.min_version);
+		if !min_too_low && !max_too_low {
+			return None;
+		}
+		let min = if min_too_low { self.min_version } else { min };
+		let max = if max_too_low { 0 } else { max };
+		Some(min | max << CURL_SSLVERSION_MAX_SHIFT)
+	}
+}
+
+/// Remove weak ciphers from an OpenSSL style cipher list.
+///
+/// Returns `None` if the list has no weak ciphers, or `Some(None)` if no ciphers are left, so the libcurl default should be used.
+fn fix_ciphers(ciphers: &str) -> Option<Option<CString>> {
+	let is_weak = |cipher: &str| {
+		let cipher = cipher.to_ascii_uppercase();
+		!cipher.starts_with(['!', '-']) && WEAK_CIPHERS.iter().any(|x| cipher.contains(x))
+	};
+	let entries: Vec<&str> = ciphers.split([':', ',', ' ']).filter(|x| !x.is_empty()).collect();
+	if !entries.iter().any(|x| is_weak(x)) {
+		return None;
+	}
+	let secure: Vec<&str> = entries.into_iter().filter(|x| !is_weak(x)).collect();
+	if secure.is_empty() {
+		return Some(None);
+	}
+	Some(CString::new(secure.join(":")).ok())
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_parse() {
+		let policy = TlsPolicy::parse("warn,verify-peer=fix,ciphers=off", "1.3").unwrap();
+		assert!(policy.action(Check::VerifyPeer) == Action::Fix);
+		assert!(policy.action(Check::VerifyHost) == Action::Warn);
+		assert!(policy.action(Check::Ciphers) == Action::Off);
+		assert!(policy.min_version == curl_sys::CURL_SSLVERSION_TLSv1_3 as c_long);
+		assert!(!TlsPolicy::parse("", "1.2").unwrap().is_enabled());
+		assert!(TlsPolicy::parse("verify-everything=fix", "1.2").is_err());
+		assert!(TlsPolicy::parse("fix", "2.0").is_err());
+	}
+
+	#[test]
+	fn test_check() {
+		let policy = TlsPolicy::parse("fix", "1.2").unwrap();
+		let check = |option, arg| unsafe { policy.check(option, arg) }.map(|x| (x.check, x.fixed));
+		assert!(check(curl_sys::CURLOPT_SSL_VERIFYPEER, Arg::Long(0)) == Some((Check::VerifyPeer, Fixed::Long(1))));
+		assert!(check(curl_sys::CURLOPT_SSL_VERIFYPEER, Arg::Long(1)).is_none());
+		assert!(check(curl_sys::CURLOPT_SSL_VERIFYHOST, Arg::Long(1)) == Some((Check::VerifyHost, Fixed::Long(2))));
+		assert!(check(curl_sys::CURLOPT_SSL_VERIFYHOST, Arg::Long(2)).is_none());
+		assert!(check(curl_sys::CURLOPT_PROXY_SSL_VERIFYPEER, Arg::Long(0)) == Some((Check::ProxyVerifyPeer, Fixed::Long(1))));
+
+		// TLS 1.0 or later, up to TLS 1.0.
+		let tls_1_0 = curl_sys::CURL_SSLVERSION_TLSv1_0 as c_long;
+		let tls_1_2 = curl_sys::CURL_SSLVERSION_TLSv1_2 as c_long;
+		let tls_1_3 = curl_sys::CURL_SSLVERSION_TLSv1_3 as c_long;
+		assert!(check(curl_sys::CURLOPT_SSLVERSION, Arg::Long(tls_1_0 | tls_1_0 << 16)) == Some((Check::MinVersion, Fixed::Long(tls_1_2))));
+		assert!(check(curl_sys::CURLOPT_SSLVERSION, Arg::Long(tls_1_0 | tls_1_3 << 16)) == Some((Check::MinVersion, Fixed::Long(tls_1_2 | tls_1_3 << 16))));
+		assert!(check(curl_sys::CURLOPT_PROXY_SSLVERSION, Arg::Long(1)) == Some((Check::MinVersion, Fixed::Long(tls_1_2))));
+		assert!(check(curl_sys::CURLOPT_SSLVERSION, Arg::Long(tls_1_2)).is_none());
+		assert!(check(curl_sys::CURLOPT_SSLVERSION, Arg::Long(0)).is_none());
+
+		// The maximum is the same value as the minimum, shifted by 16 bits, except for CURL_SSLVERSION_MAX_DEFAULT.
+		let max_default = CURL_SSLVERSION_MAX_DEFAULT;
+		let max_tls_1_0 = tls_1_0 << CURL_SSLVERSION_MAX_SHIFT;
+		assert!(policy.fix_version(tls_1_2 | max_default).is_none());
+		assert!(policy.fix_version(tls_1_3 | max_default).is_none());
+		assert!(policy.fix_version(tls_1_0 | max_default) == Some(tls_1_2 | max_default));
+		assert!(policy.fix_version(tls_1_0 | max_tls_1_0) == Some(tls_1_2));
+		assert!(policy.fix_version(tls_1_2 | max_tls_1_0) == Some(tls_1_2));
+
+		let ciphers = c"ECDHE-RSA-AES128-GCM-SHA256:RC4-SHA:DES-CBC3-SHA:!aNULL";
+		let fixed = CString::new("ECDHE-RSA-AES128-GCM-SHA256:!aNULL").unwrap();
+		assert!(check(curl_sys::CURLOPT_SSL_CIPHER_LIST, Arg::Pointer(ciphers.as_ptr() as *mut _)) == Some((Check::Ciphers, Fixed::String(Some(fixed)))));
+		assert!(check(curl_sys::CURLOPT_SSL_CIPHER_LIST, Arg::Pointer(c"RC4-MD5".as_ptr() as *mut _)) == Some((Check::Ciphers, Fixed::String(None))));
+		assert!(check(curl_sys::CURLOPT_SSL_CIPHER_LIST, Arg::Pointer(c"HIGH:!aNULL:!MD5".as_ptr() as *mut _)).is_none());
+	}
+}
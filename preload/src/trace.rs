@@ -0,0 +1,285 @@
+use std::borrow::Cow;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::Write;
+use std::os::raw::{c_char, c_int, c_void};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use curl_inject_opt_shared::UrlParts;
+use curl_inject_opt_shared::headers::has_name;
+use curl_inject_opt_shared::reexports::curl_sys;
+
+use curl_sys::CURL;
+use curl_sys::CURLcode;
+use curl_sys::curl_infotype;
+
+use crate::{stderr, with_state, CurlInjectOpt};
+use crate::curl::{self, Library};
+use crate::error_policy;
+use crate::setopt::{self, Arg};
+
+/// Request headers whose value is replaced by `REDACTED` in traces.
+const REDACTED_HEADERS: [&str; 2] = ["Authorization", "Proxy-Authorization"];
+
+/// Writes a trace of the transfers of each handle to a file, like `curl --trace` or `curl --trace-ascii`.
+#[derive(Default)]
+pub struct Trace {
+	/// The directory to write the trace files to, or `None` if tracing is disabled.
+	dir: Option<PathBuf>,
+
+	/// If true, write only the text of the data, like `curl --trace-ascii`, instead of a hex dump.
+	ascii: bool,
+}
+
+impl Trace {
+	/// Read the directory from CURL_INJECT_OPT_TRACE_DIR, and the format from CURL_INJECT_OPT_TRACE_ASCII.
+	///
+	/// The directory is created if it does not exist yet.
+	pub fn from_env() -> Result<Self, String> {
+		let Some(dir) = std::env::var_os("CURL_INJECT_OPT_TRACE_DIR").filter(|x| !x.is_empty()) else {
+			return Ok(Self::default());
+		};
+		let dir = PathBuf::from(dir);
+		std::fs::create_dir_all(&dir).map_err(|e| format!("failed to create trace directory {}: {}", dir.display(), e))?;
+		Ok(Self { dir: Some(dir), ascii: crate::env_bool("CURL_INJECT_OPT_TRACE_ASCII") })
+	}
+
+	/// Check if transfers are traced.
+	pub fn is_enabled(&self) -> bool {
+		self.dir.is_some()
+	}
+
+	/// Open the trace file of a handle, named `<pid>-<handle>.trace`, for appending.
+	pub fn open(&self, handle: *mut CURL) -> Result<File, String> {
+		let Some(dir) = &self.dir else {
+			return Err(String::from("tracing is disabled"));
+		};
+		let path = dir.join(format!("{}-{:x}.trace", std::process::id(), handle as usize));
+		std::fs::OpenOptions::new()
+			.create(true)
+			.append(true)
+			.open(&path)
+			.map_err(|e| format!("failed to open trace file {}: {}", path.display(), e))
+	}
+
+	/// Append an event from the debug callback to a trace file.
+	///
+	/// Each event is written with a single `write()` call, so events of handles that share a file after a fork are not mixed up.
+	pub fn write(&self, mut file: &File, kind: curl_infotype, data: &[u8]) -> Result<(), String> {
+		let event = format_event(self.ascii, &timestamp(SystemTime::now()), kind, data);
+		file.write_all(event.as_bytes()).map_err(|e| format!("failed to write trace: {}", e))
+	}
+}
+
+/// Format the time of day in UTC with microseconds, like `curl --trace-time`.
+fn timestamp(time: SystemTime) -> String {
+	let time = time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+	let seconds = time.as_secs() % 86_400;
+	format!("{:02}:{:02}:{:02}.{:06}", seconds / 3600, seconds / 60 % 60, seconds % 60, time.subsec_micros())
+}
+
+/// Format an event from the debug callback in the format of `curl --trace` or `curl --trace-ascii`.
+fn format_event(ascii: bool, time: &str, kind: curl_infotype, data: &[u8]) -> String {
+	let text = match kind {
+		curl_sys::CURLINFO_TEXT => {
+			let mut event = format!("{} == Info: {}", time, String::from_utf8_lossy(data));
+			if !event.ends_with('\n') {
+				event.push('\n');
+			}
+			return event;
+		},
+		curl_sys::CURLINFO_HEADER_OUT   => "=> Send header",
+		curl_sys::CURLINFO_DATA_OUT     => "=> Send data",
+		curl_sys::CURLINFO_HEADER_IN    => "<= Recv header",
+		curl_sys::CURLINFO_DATA_IN      => "<= Recv data",
+		curl_sys::CURLINFO_SSL_DATA_OUT => "=> Send SSL data",
+		curl_sys::CURLINFO_SSL_DATA_IN  => "<= Recv SSL data",
+		_                               => return String::new(),
+	};
+	let data = match kind {
+		curl_sys::CURLINFO_HEADER_OUT => redact_headers(data),
+		_                             => Cow::Borrowed(data),
+	};
+
+	let mut event = format!("{} {}, {} bytes ({:#x})\n", time, text, data.len(), data.len());
+	let width = if ascii { 0x40 } else { 0x10 };
+	let printable = |x: u8| if (0x20..0x7f).contains(&x) { x as char } else { '.' };
+	let mut start = 0;
+	while start < data.len() {
+		let _ = write!(event, "{:04x}: ", start);
+		if !ascii {
+			for i in start..start + width {
+				match data.get(i) {
+					Some(x) => { let _ = write!(event, "{:02x} ", x); },
+					None    => event.push_str("   "),
+				}
+			}
+		}
+
+		// In ASCII mode, a CRLF ends the line of the dump, like in curl.
+		let is_crlf = |i: usize| ascii && i + 1 < data.len() && data[i] == b'\r' && data[i + 1] == b'\n';
+		let mut next = start + width;
+		for i in start..data.len().min(start + width) {
+			if is_crlf(i) {
+				next = i + 2;
+				break;
+			}
+			event.push(printable(data[i]));
+			if is_crlf(i + 1) {
+				next = i + 3;
+				break;
+			}
+		}
+		event.push('\n');
+		start = next;
+	}
+	event
+}
+
+/// Replace the value of credential headers in the request headers sent by libcurl.
+fn redact_headers(data: &[u8]) -> Cow<'_, [u8]> {
+	if !data.split(|x| *x == b'\n').any(|line| REDACTED_HEADERS.iter().any(|name| has_name(line, name))) {
+		return Cow::Borrowed(data);
+	}
+	let mut redacted = Vec::with_capacity(data.len());
+	for line in data.split_inclusive(|x| *x == b'\n') {
+		match REDACTED_HEADERS.iter().find(|name| has_name(line, name)) {
+			Some(name) => {
+				redacted.extend_from_slice(&line[..name.len()]);
+				redacted.extend_from_slice(b": REDACTED");
+				redacted.extend_from_slice(if line.ends_with(b"\r\n") { b"\r\n" } else { b"\n" });
+			},
+			None => redacted.extend_from_slice(line),
+		}
+	}
+	Cow::Owned(redacted)
+}
+
+impl CurlInjectOpt {
+	/// Install our CURLOPT_DEBUGFUNCTION callback to trace the next transfer or record its request headers, and turn on CURLOPT_VERBOSE for it.
+	///
+	/// If the transfer would be verbose without it, the output still goes to the debug callback of the application,
+	/// or to the CURLOPT_STDERR file like libcurl does by default.
+	pub fn install_debug_callback(&self, library: &Library, handle: *mut CURL, url: Option<&UrlParts>) -> CURLcode {
+		if !self.trace.is_enabled() && !self.har.is_enabled() {
+			return curl_sys::CURLE_OK;
+		}
+
+		// Our own options may have turned on verbose output or redirected it.
+		let mut verbose = None;
+		let mut output = None;
+		for (_, option) in self.options.options_for(url) {
+			match (option.option.option, option.arg()) {
+				(curl_sys::CURLOPT_VERBOSE, Arg::Long(x)) => verbose = Some(x != 0),
+				(curl_sys::CURLOPT_STDERR, Arg::File(x))  => output = Some(x as usize),
+				_ => (),
+			}
+		}
+
+		let file = match self.handles.with(handle, |state| state.trace.clone()) {
+			Some(file) => Some(file),
+			None if !self.trace.is_enabled() => None,
+			None => match self.trace.open(handle) {
+				Ok(file) => Some(Arc::new(file)),
+				Err(e)   => {
+					error_policy::report(e);
+					return curl_sys::CURLE_WRITE_ERROR;
+				},
+			},
+		};
+		self.handles.with(handle, |state| {
+			state.verbose_output = verbose.unwrap_or(state.verbose).then_some(output.unwrap_or(state.stderr));
+			state.trace = file;
+		});
+		let code = self.setopt(library, handle, curl_sys::CURLOPT_DEBUGFUNCTION, Arg::Function(Some(debug_callback_fn())), "set CURLOPT_DEBUGFUNCTION");
+		if code != curl_sys::CURLE_OK {
+			return code;
+		}
+		self.setopt(library, handle, curl_sys::CURLOPT_VERBOSE, Arg::Long(1), "set CURLOPT_VERBOSE")
+	}
+}
+
+/// Our CURLOPT_DEBUGFUNCTION callback, which writes the trace, records the request headers for the HAR file,
+/// and forwards verbose output to where libcurl would send it.
+///
+/// The user data pointer is the CURLOPT_DEBUGDATA of the application, since libcurl passes the handle separately.
+extern "C" fn debug_callback(handle: *mut CURL, kind: curl_sys::curl_infotype, data: *mut c_char, size: usize, user_data: *mut c_void) -> c_int {
+	// SAFETY: libcurl passes a buffer of `size` bytes.
+	let bytes = unsafe { std::slice::from_raw_parts(data.cast::<u8>(), size) };
+	let application = with_state(|init| {
+		let state = init.handles.with_existing(handle, |state| (state.trace.clone(), state.har.clone(), state.debug, state.verbose_output));
+		let Some((trace, har, debug, verbose_output)) = state else {
+			return (None, None);
+		};
+		if let Some(trace) = trace {
+			if let Err(e) = init.trace.write(&trace, kind, bytes) {
+				error_policy::report(e);
+			}
+		}
+		if let Some(har) = har.filter(|_| kind == curl_sys::CURLINFO_HEADER_OUT) {
+			har.request_header(bytes);
+		}
+		(debug, verbose_output)
+	});
+
+	match application {
+		// SAFETY: The application set this function as CURLOPT_DEBUGFUNCTION, so it has this signature.
+		Some((Some(debug), Some(_))) => unsafe {
+			let debug = std::mem::transmute::<setopt::Function, curl::CurlDebugCallback>(debug);
+			debug(handle, kind, data, size, user_data)
+		},
+		// Without a debug callback, libcurl writes the text and headers to CURLOPT_STDERR with a prefix.
+		Some((None, Some(output))) => {
+			let prefix: &[u8] = match kind {
+				curl_sys::CURLINFO_TEXT       => b"* ",
+				curl_sys::CURLINFO_HEADER_IN  => b"< ",
+				curl_sys::CURLINFO_HEADER_OUT => b"> ",
+				_                             => return 0,
+			};
+			// SAFETY: The application set the file or left the default, and libcurl passes a buffer of `size` bytes.
+			unsafe {
+				let file = if output == 0 { stderr } else { output as *mut libc::FILE };
+				libc::fwrite(prefix.as_ptr().cast(), prefix.len(), 1, file);
+				libc::fwrite(data.cast(), size, 1, file);
+			}
+			0
+		},
+		_ => 0,
+	}
+}
+
+/// Get our CURLOPT_DEBUGFUNCTION callback as a generic function pointer.
+fn debug_callback_fn() -> setopt::Function {
+	let callback: curl::CurlDebugCallback = debug_callback;
+	// SAFETY: Function pointers all have the same representation, libcurl calls it with the right signature.
+	unsafe { std::mem::transmute::<curl::CurlDebugCallback, setopt::Function>(callback) }
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_format_event() {
+		let time = timestamp(SystemTime::UNIX_EPOCH + std::time::Duration::from_micros(86_400_000_000 + 3_723_000_042));
+		assert_eq!(time, "01:02:03.000042");
+
+		let header = b"GET / HTTP/1.1\r\nHost: example.com\r\nAuthorization: Bearer secret\r\n\r\n";
+		assert_eq!(format_event(true, &time, curl_sys::CURLINFO_HEADER_OUT, header), [
+			"01:02:03.000042 => Send header, 62 bytes (0x3e)",
+			"0000: GET / HTTP/1.1",
+			"0010: Host: example.com",
+			"0023: Authorization: REDACTED",
+			"003c: ",
+			"",
+		].join("\n"));
+		assert_eq!(format_event(false, &time, curl_sys::CURLINFO_DATA_IN, b"upload data\n"), [
+			"01:02:03.000042 <= Recv data, 12 bytes (0xc)",
+			"0000: 75 70 6c 6f 61 64 20 64 61 74 61 0a             upload data.",
+			"",
+		].join("\n"));
+		assert_eq!(format_event(true, &time, curl_sys::CURLINFO_TEXT, b"Connected"), "01:02:03.000042 == Info: Connected\n");
+	}
+}
@@ -36,7 +36,7 @@ trait SliceExt<T>: Sized {
 	}
 }
 
-impl<'a, T> SliceExt<T> for &'a [T] {
+impl<T> SliceExt<T> for &[T] {
 	fn trim_left(self, mut fun: impl FnMut(&T) -> bool) -> Self {
 		if let Some(position) = self.iter().position(|b| !fun(b)) {
 			&self[position..]
@@ -0,0 +1,230 @@
+/// A rule to change the request headers that the application sets with CURLOPT_HTTPHEADER.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum HeaderRule {
+	/// Add a header line, even if a header with the same name is already set.
+	Add(String),
+
+	/// Replace all headers with the same name by a header line, or add it if there are none.
+	Set(String),
+
+	/// Remove all headers with a name, and stop libcurl from adding it by itself.
+	Remove(String),
+
+	/// Sign the request with HMAC-SHA256, and put the signature in a header with this name.
+	Sign(String),
+}
+
+/// The values for the `%{NAME}` placeholders in header lines, taken for each transfer.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Placeholders {
+	/// A random version 4 UUID, for `%{uuid}`.
+	pub uuid: String,
+
+	/// The current time in RFC 3339 format, for `%{time}`.
+	pub time: String,
+
+	/// The number of the transfer in the process, starting at 1, for `%{counter}`.
+	pub counter: u64,
+
+	/// The process ID, for `%{pid}`.
+	pub pid: u32,
+}
+
+impl Placeholders {
+	/// The names of all placeholders.
+	pub const NAMES: [&'static str; 4] = ["uuid", "time", "counter", "pid"];
+
+	fn get(&self, name: &str) -> Option<String> {
+		match name {
+			"uuid"    => Some(self.uuid.clone()),
+			"time"    => Some(self.time.clone()),
+			"counter" => Some(self.counter.to_string()),
+			"pid"     => Some(self.pid.to_string()),
+			_         => None,
+		}
+	}
+
+	/// Replace the placeholders in a header line with their values.
+	pub fn expand(&self, line: &str) -> String {
+		let mut result = String::with_capacity(line.len());
+		let mut rest = line;
+		while let Some(start) = rest.find("%{") {
+			let Some((name, after)) = rest[start + 2..].split_once('}') else {
+				break;
+			};
+			result.push_str(&rest[..start]);
+			match self.get(name) {
+				Some(value) => result.push_str(&value),
+				None        => result.push_str(&rest[start..rest.len() - after.len()]),
+			}
+			rest = after;
+		}
+		result.push_str(rest);
+		result
+	}
+}
+
+impl HeaderRule {
+	/// Create a rule to add a header from a `NAME: VALUE` line.
+	pub fn add(line: &str) -> Result<Self, String> {
+		check_line(line)?;
+		Ok(Self::Add(line.to_string()))
+	}
+
+	/// Create a rule to set a header from a `NAME: VALUE` line.
+	pub fn set(line: &str) -> Result<Self, String> {
+		check_line(line)?;
+		Ok(Self::Set(line.to_string()))
+	}
+
+	/// Create a rule to remove all headers with a name.
+	pub fn remove(name: &str) -> Result<Self, String> {
+		let name = name.strip_suffix(':').unwrap_or(name);
+		check_name(name)?;
+		Ok(Self::Remove(name.to_string()))
+	}
+
+	/// Create a rule to sign the request, with the name of the header for the signature.
+	pub fn sign(name: &str) -> Result<Self, String> {
+		check_name(name)?;
+		Ok(Self::Sign(name.to_string()))
+	}
+
+	/// The names of the rules in the serialized format and on the command line.
+	pub const NAMES: [&'static str; 4] = ["header-add", "header-set", "header-remove", "header-sign"];
+
+	/// The name of the rule in the serialized format and on the command line.
+	pub fn name(&self) -> &'static str {
+		match self {
+			Self::Add(_)    => Self::NAMES[0],
+			Self::Set(_)    => Self::NAMES[1],
+			Self::Remove(_) => Self::NAMES[2],
+			Self::Sign(_)   => Self::NAMES[3],
+		}
+	}
+
+	/// The argument of the rule, as given on the command line.
+	pub fn arg(&self) -> &str {
+		match self {
+			Self::Add(x) | Self::Set(x) | Self::Remove(x) | Self::Sign(x) => x,
+		}
+	}
+
+	/// Create a rule from its name and argument, as returned by [`Self::name`] and [`Self::arg`].
+	pub fn from_arg(name: &str, arg: &str) -> Result<Self, String> {
+		match name {
+			"header-add"    => Self::add(arg),
+			"header-set"    => Self::set(arg),
+			"header-remove" => Self::remove(arg),
+			"header-sign"   => Self::sign(arg),
+			_ => Err(format!("unknown header rule: {}", name)),
+		}
+	}
+
+	/// Apply the rule to a list of header lines, as used for CURLOPT_HTTPHEADER.
+	///
+	/// Signing needs the method and body of the request, so [`Self::Sign`] is left to the caller.
+	pub fn apply(&self, headers: &mut Vec<Vec<u8>>, placeholders: &Placeholders) {
+		match self {
+			Self::Add(line) => headers.push(placeholders.expand(line).into_bytes()),
+			Self::Set(line) => {
+				let name = line.split_once(':').map_or(line.as_str(), |(name, _)| name);
+				headers.retain(|x| !has_name(x, name));
+				headers.push(placeholders.expand(line).into_bytes());
+			},
+			Self::Remove(name) => {
+				headers.retain(|x| !has_name(x, name));
+				// An empty header tells libcurl not to send a header that it would add by itself.
+				headers.push(format!("{}:", name).into_bytes());
+			},
+			Self::Sign(_) => (),
+		}
+	}
+}
+
+/// Check if a header line from a CURLOPT_HTTPHEADER list has a name, ignoring case.
+///
+/// This also matches `NAME;`, which libcurl uses to send a header without value.
+pub fn has_name(line: &[u8], name: &str) -> bool {
+	let name = name.as_bytes();
+	line.len() > name.len() && line[..name.len()].eq_ignore_ascii_case(name) && matches!(line[name.len()], b':' | b';')
+}
+
+fn check_name(name: &str) -> Result<(), String> {
+	if name.is_empty() || name.bytes().any(|x| x <= b' ' || x == b':' || x == b';' || x >= 0x7f) {
+		return Err(format!("invalid header name: {:?}", name));
+	}
+	Ok(())
+}
+
+fn check_line(line: &str) -> Result<(), String> {
+	let (name, _) = line.split_once(':').ok_or_else(|| format!("invalid header: {:?}, expected NAME: VALUE", line))?;
+	check_name(name)?;
+	if line.contains(['\r', '\n']) {
+		return Err(format!("invalid header: {:?}, header can not contain newlines", line));
+	}
+	let mut rest = line;
+	while let Some(start) = rest.find("%{") {
+		let (name, after) = rest[start + 2..].split_once('}').ok_or_else(|| format!("invalid header: {:?}, unterminated placeholder", line))?;
+		if !Placeholders::NAMES.contains(&name) {
+			return Err(format!("invalid header: {:?}, unknown placeholder %{{{}}}, expected one of: {}", line, name, Placeholders::NAMES.join(", ")));
+		}
+		rest = after;
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn apply(rules: &[HeaderRule], headers: &[&str]) -> Vec<String> {
+		let placeholders = Placeholders { uuid: "0b5f6e4a-9c1d-4e2f-8a3b-7c6d5e4f3a2b".into(), time: "2026-10-19T12:00:00Z".into(), counter: 7, pid: 42 };
+		let mut headers = headers.iter().map(|x| x.as_bytes().to_vec()).collect();
+		for rule in rules {
+			rule.apply(&mut headers, &placeholders);
+		}
+		headers.into_iter().map(|x| String::from_utf8(x).unwrap()).collect()
+	}
+
+	#[test]
+	fn test_apply() {
+		let rules = [
+			HeaderRule::remove("X-Debug-Token").unwrap(),
+			HeaderRule::set("User-Agent: tool/2.0").unwrap(),
+			HeaderRule::remove("Expect").unwrap(),
+			HeaderRule::add("Accept: text/plain").unwrap(),
+			HeaderRule::set("X-Request-Id: %{uuid}").unwrap(),
+			HeaderRule::add("X-Trace: %{pid}-%{counter} at %{time}").unwrap(),
+			HeaderRule::sign("Authorization").unwrap(),
+		];
+		let headers = ["Accept: */*", "x-debug-token: secret", "user-agent: tool/1.0", "X-Empty;"];
+		assert_eq!(apply(&rules, &headers), [
+			"Accept: */*",
+			"X-Empty;",
+			"X-Debug-Token:",
+			"User-Agent: tool/2.0",
+			"Expect:",
+			"Accept: text/plain",
+			"X-Request-Id: 0b5f6e4a-9c1d-4e2f-8a3b-7c6d5e4f3a2b",
+			"X-Trace: 42-7 at 2026-10-19T12:00:00Z",
+		]);
+	}
+
+	#[test]
+	fn test_parse() {
+		assert!(HeaderRule::add("X-Test: a").is_ok());
+		assert!(HeaderRule::add("X-Test a").is_err());
+		assert!(HeaderRule::set(": a").is_err());
+		assert!(HeaderRule::set("X-Test: a\r\nX-Injected: b").is_err());
+		assert_eq!(HeaderRule::remove("Expect:"), Ok(HeaderRule::Remove("Expect".into())));
+		assert!(HeaderRule::remove("X Test").is_err());
+		assert!(HeaderRule::set("X-Test: %{uuid} %{pid}").is_ok());
+		assert!(HeaderRule::set("X-Test: %{host}").is_err());
+		assert!(HeaderRule::sign("X-Signature").is_ok());
+		assert!(HeaderRule::sign("X-Signature: a").is_err());
+		for rule in [HeaderRule::Add("A: b".into()), HeaderRule::Set("A: b".into()), HeaderRule::Remove("A".into()), HeaderRule::Sign("A".into())] {
+			assert_eq!(HeaderRule::from_arg(rule.name(), rule.arg()), Ok(rule));
+		}
+	}
+}
@@ -1,44 +1,250 @@
 pub mod config;
 pub mod url_encode;
+pub mod headers;
+pub mod summary;
 mod options;
+mod rewrite;
+mod rules;
 
-pub use self::options::{Kind, Value, Meta, SetOption, OPTIONS};
+pub use self::headers::HeaderRule;
+pub use self::options::{Kind, Value, Meta, SetOption, OPTIONS, merge_list};
+pub use self::rewrite::Rewrite;
+pub use self::rules::{OptionSet, Rule, RuleBlock, UrlParts};
 
 pub mod reexports {
 	pub use curl_sys;
 }
 
+/// Encode a list of items separated by semicolons.
+fn encode_list_append<'a>(buffer: &mut Vec<u8>, list: impl IntoIterator<Item = &'a [u8]>) {
+	// Items are separated by semicolons, so escape them in the items first.
+	let mut items = Vec::new();
+	for (i, item) in list.into_iter().enumerate() {
+		if i > 0 {
+			items.push(b';');
+		}
+		url_encode::encode_append(&mut items, item, url_encode::escape_semicolon);
+	}
+	url_encode::encode_append(buffer, &items, url_encode::escape_comma)
+}
+
+/// Decode a list of items separated by semicolons, after decoding the whole value.
+fn decode_list(data: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+	data.split(|b| *b == b';').map(url_encode::decode).collect()
+}
+
 fn encode_option_append(buffer: &mut Vec<u8>, option: &SetOption) {
 	buffer.extend(option.name.as_bytes());
+	if option.merge {
+		buffer.push(b'+');
+	}
 	buffer.push(b'=');
 
 	match &option.value {
 		Value::CString(x) => url_encode::encode_append(buffer, x.as_bytes(), url_encode::escape_comma),
 		Value::CLong(x)   => buffer.extend(format!("{}", x).as_bytes()),
+		Value::COffT(x)   => buffer.extend(format!("{}", x).as_bytes()),
+		Value::CSlist(x)  => encode_list_append(buffer, x.iter().map(|x| x.as_bytes())),
+		Value::CBlob(x)   => url_encode::encode_append(buffer, x.as_bytes(), url_encode::escape_comma),
+		Value::CFile(x)   => url_encode::encode_append(buffer, x.as_bytes(), url_encode::escape_comma),
 	}
 }
 
-fn decode_option(data: &[u8]) -> Result<SetOption, String> {
-	let split_at = data.iter().position(|b| *b == b'=').ok_or_else(|| String::from("invalid option syntax, expected name=value"))?;
-	let name     = std::str::from_utf8(&data[..split_at]).map_err(|_| String::from("option name contains invalid UTF-8"))?;
-	let value    = url_encode::decode(&data[split_at + 1..]).map_err(|e| format!("failed to decode value for option {}: {}", name, e))?;
+fn decode_slist(name: &str, data: &[u8]) -> Result<SetOption, String> {
+	let items = decode_list(data).map_err(|e| format!("failed to decode value for option {}: {}", name, e))?;
+	let items = items.into_iter()
+		.map(|item| std::ffi::CString::new(item).map_err(|_| format!("value for option {} contains a null byte", name)))
+		.collect::<Result<_, _>>()?;
+	SetOption::parse_name(name, Value::CSlist(items))
+}
+
+fn decode_option(name: &str, value: &[u8]) -> Result<SetOption, String> {
+	let value = url_encode::decode(value).map_err(|e| format!("failed to decode value for option {}: {}", name, e))?;
 
-	SetOption::parse_name_value(name, &value)
+	let is_slist = OPTIONS.iter().any(|x| x.kind == Kind::CSlist && name.eq_ignore_ascii_case(x.name));
+	if is_slist {
+		decode_slist(name, &value)
+	} else {
+		SetOption::parse_name_value(name, &value)
+	}
 }
 
-pub fn serialize_options<'a>(options: impl Iterator<Item = &'a SetOption>) -> Vec<u8> {
+fn encode_rewrite_append(buffer: &mut Vec<u8>, rewrite: &Rewrite) {
+	buffer.push(b'@');
+	buffer.extend(rewrite.name().as_bytes());
+	buffer.push(b'=');
+	encode_list_append(buffer, rewrite.args().map(str::as_bytes));
+}
+
+fn decode_rewrite(name: &str, value: &[u8]) -> Result<Rewrite, String> {
+	let value = url_encode::decode(value).map_err(|e| format!("failed to decode {} rule: {}", name, e))?;
+	let args  = decode_list(&value).map_err(|e| format!("failed to decode {} rule: {}", name, e))?;
+	let args  = args.into_iter()
+		.map(|x| String::from_utf8(x).map_err(|_| format!("{} rule contains invalid UTF-8", name)))
+		.collect::<Result<Vec<_>, _>>()?;
+	let [a, b] = args.as_slice() else {
+		return Err(format!("invalid {} rule: expected 2 arguments, got {}", name, args.len()));
+	};
+	Rewrite::from_args(name, [a, b])
+}
+
+fn encode_header_append(buffer: &mut Vec<u8>, header: &HeaderRule) {
+	buffer.push(b'@');
+	buffer.extend(header.name().as_bytes());
+	buffer.push(b'=');
+	url_encode::encode_append(buffer, header.arg().as_bytes(), url_encode::escape_comma);
+}
+
+fn decode_header(name: &str, value: &[u8]) -> Result<HeaderRule, String> {
+	let value = url_encode::decode(value).map_err(|e| format!("failed to decode {} rule: {}", name, e))?;
+	let value = String::from_utf8(value).map_err(|_| format!("{} rule contains invalid UTF-8", name))?;
+	HeaderRule::from_arg(name, &value)
+}
+
+/// The name used in the serialized format to start a new rule block.
+///
+/// Option names never start with `@`, so this can not conflict with an option.
+/// Rewrite rules and header rules are also prefixed with `@`.
+const RULE_NAME: &str = "match";
+
+pub fn serialize_options(options: &OptionSet) -> Vec<u8> {
 	let mut buffer = Vec::new();
 	let mut first  = true;
-	for option in options {
+	let mut separator = |buffer: &mut Vec<u8>| {
 		if !std::mem::replace(&mut first, false) {
 			buffer.push(b',');
 		}
+	};
+
+	for option in &options.options {
+		separator(&mut buffer);
 		encode_option_append(&mut buffer, option);
 	}
+	for rewrite in &options.rewrites {
+		separator(&mut buffer);
+		encode_rewrite_append(&mut buffer, rewrite);
+	}
+	for header in &options.headers {
+		separator(&mut buffer);
+		encode_header_append(&mut buffer, header);
+	}
+
+	for block in &options.blocks {
+		separator(&mut buffer);
+		buffer.push(b'@');
+		buffer.extend(RULE_NAME.as_bytes());
+		buffer.push(b'=');
+		url_encode::encode_append(&mut buffer, block.rule.to_string().as_bytes(), url_encode::escape_comma);
+		for option in &block.options {
+			separator(&mut buffer);
+			encode_option_append(&mut buffer, option);
+		}
+		for rewrite in &block.rewrites {
+			separator(&mut buffer);
+			encode_rewrite_append(&mut buffer, rewrite);
+		}
+		for header in &block.headers {
+			separator(&mut buffer);
+			encode_header_append(&mut buffer, header);
+		}
+	}
 
 	buffer
 }
 
-pub fn parse_options(data: &[u8]) -> Result<Vec<SetOption>, String> {
-	data.split(|b| *b == b',').filter(|x| !x.is_empty()).map(decode_option).collect()
+pub fn parse_options(data: &[u8]) -> Result<OptionSet, String> {
+	let mut result = OptionSet::default();
+	for entry in data.split(|b| *b == b',').filter(|x| !x.is_empty()) {
+		let split_at = entry.iter().position(|b| *b == b'=').ok_or_else(|| String::from("invalid option syntax, expected name=value"))?;
+		let name     = std::str::from_utf8(&entry[..split_at]).map_err(|_| String::from("option name contains invalid UTF-8"))?;
+		let value    = &entry[split_at + 1..];
+
+		if name == format!("@{}", RULE_NAME) {
+			let pattern = url_encode::decode(value).map_err(|e| format!("failed to decode URL pattern: {}", e))?;
+			let pattern = String::from_utf8(pattern).map_err(|_| String::from("URL pattern contains invalid UTF-8"))?;
+			result.blocks.push(RuleBlock::new(Rule::parse(&pattern)?));
+		} else if let Some(name) = name.strip_prefix('@').filter(|x| HeaderRule::NAMES.contains(x)) {
+			let header = decode_header(name, value)?;
+			match result.blocks.last_mut() {
+				Some(block) => block.headers.push(header),
+				None        => result.headers.push(header),
+			}
+		} else if let Some(name) = name.strip_prefix('@') {
+			let rewrite = decode_rewrite(name, value)?;
+			match result.blocks.last_mut() {
+				Some(block) => block.rewrites.push(rewrite),
+				None        => result.rewrites.push(rewrite),
+			}
+		} else {
+			let (name, merge) = match name.strip_suffix('+') {
+				Some(name) => (name, true),
+				None       => (name, false),
+			};
+			let option = SetOption { merge, ..decode_option(name, value)? };
+			match result.blocks.last_mut() {
+				Some(block) => block.options.push(option),
+				None        => result.options.push(option),
+			}
+		}
+	}
+
+	Ok(result)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::ffi::CString;
+
+	#[test]
+	fn test_serialize_roundtrip() {
+		let options = vec![
+			SetOption::parse_name("proxy", Value::CString(CString::new("http://a,b").unwrap())).unwrap(),
+			SetOption::parse_name("max-recv-speed", Value::COffT(1 << 40)).unwrap(),
+			SetOption::parse_name("resolve", Value::CSlist(vec![CString::new("a;b,c").unwrap(), CString::new("%d").unwrap()])).unwrap(),
+			SetOption::parse_name("ca-info-blob", Value::CBlob(CString::new("/etc/a,b;c%.pem").unwrap())).unwrap(),
+			SetOption { merge: true, ..SetOption::parse_name("no-proxy", Value::CString(CString::new("a,b").unwrap())).unwrap() },
+		];
+		let options = OptionSet { options, ..OptionSet::default() };
+		let parsed = parse_options(&serialize_options(&options)).unwrap();
+		assert_eq!(parsed.options.len(), options.options.len());
+		for (parsed, option) in parsed.options.iter().zip(&options.options) {
+			assert_eq!(parsed.option, option.option);
+			assert_eq!(parsed.value, option.value);
+			assert_eq!(parsed.merge, option.merge);
+		}
+	}
+
+	#[test]
+	fn test_serialize_rules() {
+		let verbose = SetOption::parse_name("verbose", Value::CLong(1)).unwrap();
+		let proxy   = SetOption::parse_name("proxy", Value::CString(CString::new("http://proxy").unwrap())).unwrap();
+		let options = OptionSet {
+			options: vec![verbose.clone()],
+			rewrites: vec![Rewrite::prefix("https://a/", "https://b;c,d/")],
+			blocks: vec![
+				RuleBlock { options: vec![proxy.clone()], ..RuleBlock::new(Rule::parse("!localhost").unwrap()) },
+				RuleBlock {
+					options: vec![verbose, proxy],
+					rewrites: vec![Rewrite::query("a=b").unwrap()],
+					headers: vec![HeaderRule::set("X-List: a,b").unwrap()],
+					..RuleBlock::new(Rule::parse("https://api.*,x/v2/").unwrap())
+				},
+			],
+			headers: vec![HeaderRule::remove("Expect").unwrap()],
+		};
+		let serialized = serialize_options(&options);
+		assert_eq!(serialized, &b"verbose=1,@rewrite-prefix=https://a/;https://b%253Bc%2Cd/,@header-remove=Expect,@match=!localhost,proxy=http://proxy,@match=https://api.*%2Cx/v2/,verbose=1,proxy=http://proxy,@add-query=a;b,@header-set=X-List: a%2Cb"[..]);
+
+		let parsed = parse_options(&serialized).unwrap();
+		assert_eq!(parsed.options.len(), 1);
+		assert_eq!(parsed.blocks.len(), 2);
+		assert_eq!(parsed.blocks[0].rule, options.blocks[0].rule);
+		assert_eq!(parsed.blocks[1].rule, options.blocks[1].rule);
+		assert_eq!(parsed.blocks[1].options.len(), 2);
+		assert_eq!(parsed.rewrites[0].args(), ["https://a/", "https://b;c,d/"]);
+		assert_eq!(parsed.blocks[1].rewrites[0].args(), ["a", "b"]);
+		assert_eq!(parsed.headers, options.headers);
+		assert_eq!(parsed.blocks[1].headers, options.blocks[1].headers);
+	}
 }
@@ -2,6 +2,7 @@ use std::ffi::CString;
 use std::os::raw::c_long;
 
 use curl_sys::CURLoption;
+use curl_sys::curl_off_t;
 
 macro_rules! curl_option {
 	( $name:literal, $curl_name:ident, $type:expr, $help:literal ) => {
@@ -13,36 +14,77 @@ macro_rules! curl_option {
 			help      : concat!(stringify!($curl_name), ": ", $help),
 		}
 	};
+	// For options that are too new to be known by curl-sys.
+	( $name:literal, $curl_name:ident = $option:expr, $type:expr, $help:literal ) => {
+		Meta {
+			name      : $name,
+			curl_name : stringify!($curl_name),
+			option    : $option,
+			kind      : $type,
+			help      : concat!(stringify!($curl_name), ": ", $help),
+		}
+	};
 }
 
 /// Global list of known CURL options.
 pub const OPTIONS : &[Meta] = &[
 	curl_option!("verbose",          CURLOPT_VERBOSE,           Kind::CLong,   "Enable verbose output from CURL."),
 
+	curl_option!("stderr",           CURLOPT_STDERR,            Kind::CFile,   "Write verbose output and other messages to a file instead of stderr."),
+
 	curl_option!("timeout",          CURLOPT_TIMEOUT_MS,        Kind::CLong,   "Timeout in milliseconds for the whole request."),
 	curl_option!("connect-timeout",  CURLOPT_CONNECTTIMEOUT_MS, Kind::CLong,   "Timeout in milliseconds for the connection phase of the request."),
 
+	curl_option!("max-recv-speed",   CURLOPT_MAX_RECV_SPEED_LARGE, Kind::COffT, "Limit the download speed in bytes per second."),
+	curl_option!("max-send-speed",   CURLOPT_MAX_SEND_SPEED_LARGE, Kind::COffT, "Limit the upload speed in bytes per second."),
+
+	curl_option!("resolve",          CURLOPT_RESOLVE,           Kind::CSlist,  "Use a custom address for a host and port: HOST:PORT:ADDRESS."),
+	curl_option!("connect-to",       CURLOPT_CONNECT_TO,        Kind::CSlist,  "Connect to a different host and port: HOST:PORT:CONNECT-TO-HOST:CONNECT-TO-PORT."),
+
 	curl_option!("proxy",            CURLOPT_PROXY,             Kind::CString, "Set the proxy to use."),
 	curl_option!("proxy-port",       CURLOPT_PROXYPORT,         Kind::CLong,   "Set the proxy port."),
-	curl_option!("proxy-type",       CURLOPT_PROXYTYPE,         Kind::CString, "Set the proxy type."),
+	curl_option!("proxy-type",       CURLOPT_PROXYTYPE,         Kind::CLong,   "Set the proxy type."),
 	curl_option!("proxy-tunnel",     CURLOPT_HTTPPROXYTUNNEL,   Kind::CLong,   "Use CONNECT to tunnel through a configured HTTP proxy."),
 	curl_option!("no-proxy",         CURLOPT_NOPROXY,           Kind::CString, "Contact these hosts directly, bypassing the proxy."),
 
+	curl_option!("accept-encoding",  CURLOPT_ACCEPT_ENCODING,   Kind::CString, "Request a compressed response with these encodings."),
+	curl_option!("protocols-str",    CURLOPT_PROTOCOLS_STR = curl_sys::CURLOPTTYPE_OBJECTPOINT + 318, Kind::CString, "Only allow these protocols, separated by commas."),
+	curl_option!("ciphers",          CURLOPT_SSL_CIPHER_LIST,   Kind::CString, "Use these TLS ciphers, separated by colons."),
+
 	curl_option!("client-cert",      CURLOPT_SSLCERT,           Kind::CString, "Use a client certificate for requests."),
 	curl_option!("client-cert-type", CURLOPT_SSLCERTTYPE,       Kind::CString, "Specify the type of the client certificate."),
 	curl_option!("client-key",       CURLOPT_SSLKEY,            Kind::CString, "Use a separate file as key with the client certificate."),
 	curl_option!("client-key-type",  CURLOPT_SSLKEYTYPE,        Kind::CString, "Specify the type of the client key."),
 
+	curl_option!("ca-info-blob",     CURLOPT_CAINFO_BLOB,       Kind::CBlob,   "Use the CA certificates from a PEM file, passed to libcurl as in-memory data."),
+
 	//curl_option!("proxy-client-cert",      CURLOPT_PROXY_SSLCERT,      Kind::CString, "Use a client certificate to authenticate with the proxy."),
 	//curl_option!("proxy-client-cert-type", CURLOPT_PROXY_SSLCERTTYPE,  Kind::CString, "Specify the type of the proxy client certificate."),
 	//curl_option!("proxy-client-key",       CURLOPT_PROXY_SSLKEY,       Kind::CString, "Use the given key with the proxy client certificate."),
 ];
 
 /// The possible kinds of CURL options.
+///
+/// Each kind corresponds to the C type that `curl_easy_setopt()` expects for the option.
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd)]
 pub enum Kind {
+	/// A null-terminated string (`char *`).
 	CString,
+
+	/// A long integer (`long`).
 	CLong,
+
+	/// A large integer (`curl_off_t`).
+	COffT,
+
+	/// A list of null-terminated strings (`struct curl_slist *`).
+	CSlist,
+
+	/// Binary data (`struct curl_blob *`), read from a file given by path.
+	CBlob,
+
+	/// A file opened for writing (`FILE *`), given by path.
+	CFile,
 }
 
 impl std::fmt::Display for Kind {
@@ -50,25 +92,35 @@ impl std::fmt::Display for Kind {
 		match self {
 			Kind::CString => "string".fmt(f),
 			Kind::CLong   => "integer".fmt(f),
+			Kind::COffT   => "large integer".fmt(f),
+			Kind::CSlist  => "string list".fmt(f),
+			Kind::CBlob   => "binary data".fmt(f),
+			Kind::CFile   => "file".fmt(f),
 		}
 	}
 }
 
 /// The value for a CURL option.
-///
-/// It can be either a null-terminated string, or a long integer as defined by C.
 #[derive(Clone, Debug, PartialEq, PartialOrd)]
 pub enum Value {
 	CString(CString),
 	CLong(c_long),
+	COffT(curl_off_t),
+	CSlist(Vec<CString>),
+	CBlob(CString),
+	CFile(CString),
 }
 
 impl Value {
 	/// Get the kind of the the value.
-	fn kind(&self) -> Kind {
+	pub fn kind(&self) -> Kind {
 		match self {
 			Value::CString(_) => Kind::CString,
 			Value::CLong(_)   => Kind::CLong,
+			Value::COffT(_)   => Kind::COffT,
+			Value::CSlist(_)  => Kind::CSlist,
+			Value::CBlob(_)   => Kind::CBlob,
+			Value::CFile(_)   => Kind::CFile,
 		}
 	}
 }
@@ -78,6 +130,18 @@ impl std::fmt::Display for Value {
 		match self {
 			Value::CString(x) => x.to_string_lossy().fmt(f),
 			Value::CLong(x)   => x.fmt(f),
+			Value::COffT(x)   => x.fmt(f),
+			Value::CSlist(x)  => {
+				for (i, item) in x.iter().enumerate() {
+					if i > 0 {
+						", ".fmt(f)?;
+					}
+					item.to_string_lossy().fmt(f)?;
+				}
+				Ok(())
+			},
+			Value::CBlob(x)   => x.to_string_lossy().fmt(f),
+			Value::CFile(x)   => x.to_string_lossy().fmt(f),
 		}
 	}
 }
@@ -94,16 +158,33 @@ pub struct Meta {
 	/// The CURLoption value for the option.
 	pub option: CURLoption,
 
-	/// The type of the options: CString or CLong.
+	/// The type of the option.
 	pub kind: Kind,
 
 	/// A description of the option for humans.
 	pub help: &'static str,
 }
 
+impl Meta {
+	/// Get the separator of the items in a string option that holds a list, if it is one.
+	pub fn list_separator(&self) -> Option<u8> {
+		match self.name {
+			"no-proxy" | "accept-encoding" | "protocols-str" => Some(b','),
+			"ciphers" => Some(b':'),
+			_ => None,
+		}
+	}
+
+	/// Check if the value of the option can be merged with the value set by the application.
+	pub fn is_mergeable(&self) -> bool {
+		self.kind == Kind::CSlist || self.list_separator().is_some()
+	}
+}
+
 /// A CURL option with an embedded value.
 ///
 /// Can be used to set the option on a CURL handle.
+#[derive(Clone, Debug)]
 pub struct SetOption {
 	/// A human friendly name for the option.
 	pub name: &'static str,
@@ -113,6 +194,23 @@ pub struct SetOption {
 
 	/// The value to set for the option.
 	pub value: Value,
+
+	/// Merge the value with the value set by the application, instead of replacing it.
+	pub merge: bool,
+}
+
+/// Merge two lists of items separated by `separator`, keeping the order and dropping duplicates and empty items.
+///
+/// Items are compared without surrounding whitespace and ignoring case.
+pub fn merge_list(separator: u8, application: &[u8], ours: &[u8]) -> Vec<u8> {
+	let mut items: Vec<&[u8]> = Vec::new();
+	for item in application.split(|x| *x == separator).chain(ours.split(|x| *x == separator)) {
+		let item = item.trim_ascii();
+		if !item.is_empty() && !items.iter().any(|x| x.eq_ignore_ascii_case(item)) {
+			items.push(item);
+		}
+	}
+	items.join(&separator)
 }
 
 fn parse_long(bytes: &[u8]) -> Result<c_long, ()> {
@@ -120,15 +218,27 @@ fn parse_long(bytes: &[u8]) -> Result<c_long, ()> {
 	c_long::from_str_radix(string, 10).map_err(|_| ())
 }
 
+fn parse_off_t(bytes: &[u8]) -> Result<curl_off_t, ()> {
+	let string = std::str::from_utf8(bytes).map_err(|_| ())?;
+	curl_off_t::from_str_radix(string, 10).map_err(|_| ())
+}
+
 impl SetOption {
 	/// Parse the value for an option with known metadata.
+	///
+	/// For string lists, the value is parsed as a single item.
 	pub fn parse_value(meta: Meta, value: &[u8]) -> Result<Self, String> {
+		let string = || CString::new(value).map_err(|_| format!("value for option {} contains a null byte", meta.name));
 		let value = match meta.kind {
-			Kind::CString => Value::CString(CString::new(value).map_err(|_| format!("value for option {} contains a null byte", meta.name))?),
+			Kind::CString => Value::CString(string()?),
 			Kind::CLong   => Value::CLong(parse_long(value).map_err(|_| format!("invalid integer value for option {}", meta.name))?),
+			Kind::COffT   => Value::COffT(parse_off_t(value).map_err(|_| format!("invalid integer value for option {}", meta.name))?),
+			Kind::CSlist  => Value::CSlist(vec![string()?]),
+			Kind::CBlob   => Value::CBlob(string()?),
+			Kind::CFile   => Value::CFile(string()?),
 		};
 
-		Ok(Self{name: meta.name, option: meta.option, value})
+		Ok(Self{name: meta.name, option: meta.option, value, merge: false})
 	}
 
 	/// Parse an option from the name and value.
@@ -157,6 +267,7 @@ impl SetOption {
 					name: candidate.name,
 					option: candidate.option,
 					value,
+					merge: false,
 				})
 			}
 		}
@@ -164,3 +275,18 @@ impl SetOption {
 		Err(format!("unknown option: {}", name))
 	}
 }
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_merge_list() {
+		assert_eq!(merge_list(b',', b"localhost, .corp", b".CORP,internal.example"), b"localhost,.corp,internal.example");
+		assert_eq!(merge_list(b',', b"", b"gzip,br"), b"gzip,br");
+		assert_eq!(merge_list(b':', b"ECDHE-RSA-AES128-GCM-SHA256:", b"ECDHE-RSA-AES256-GCM-SHA384"), b"ECDHE-RSA-AES128-GCM-SHA256:ECDHE-RSA-AES256-GCM-SHA384");
+		let meta = OPTIONS.iter().find(|x| x.name == "protocols-str").unwrap();
+		assert!(meta.is_mergeable());
+		assert!(!OPTIONS.iter().find(|x| x.name == "proxy").unwrap().is_mergeable());
+	}
+}
@@ -0,0 +1,156 @@
+use crate::url_encode;
+
+/// A rule to rewrite the URL of a transfer before it starts.
+#[derive(Clone, Debug)]
+pub enum Rewrite {
+	/// Replace a prefix of the URL.
+	Prefix {
+		from: String,
+		to: String,
+	},
+
+	/// Replace the first match of a regular expression.
+	///
+	/// The replacement can refer to capture groups with `$1` or `${name}`.
+	Regex {
+		regex: regex::Regex,
+		replacement: String,
+	},
+
+	/// Add a query parameter, replacing all parameters with the same name.
+	Query {
+		name: String,
+		value: String,
+	},
+}
+
+impl Rewrite {
+	/// Create a rule to replace a prefix of the URL.
+	pub fn prefix(from: &str, to: &str) -> Self {
+		Self::Prefix {
+			from: from.to_string(),
+			to: to.to_string(),
+		}
+	}
+
+	/// Create a rule to replace the first match of a regular expression.
+	pub fn regex(regex: &str, replacement: &str) -> Result<Self, String> {
+		let regex = regex::Regex::new(regex).map_err(|e| format!("invalid regular expression: {}", e))?;
+		Ok(Self::Regex {
+			regex,
+			replacement: replacement.to_string(),
+		})
+	}
+
+	/// Create a rule to add a query parameter from `NAME=VALUE`.
+	pub fn query(parameter: &str) -> Result<Self, String> {
+		let (name, value) = parameter.split_once('=').ok_or_else(|| format!("invalid query parameter: {}, expected NAME=VALUE", parameter))?;
+		if name.is_empty() {
+			return Err(format!("invalid query parameter: {}: empty name", parameter));
+		}
+		Ok(Self::Query {
+			name: name.to_string(),
+			value: value.to_string(),
+		})
+	}
+
+	/// The name of the rule in the serialized format and on the command line.
+	pub fn name(&self) -> &'static str {
+		match self {
+			Self::Prefix { .. } => "rewrite-prefix",
+			Self::Regex { .. }  => "rewrite-regex",
+			Self::Query { .. }  => "add-query",
+		}
+	}
+
+	/// Get the arguments of the rule, in the same form as they are given on the command line.
+	pub fn args(&self) -> [&str; 2] {
+		match self {
+			Self::Prefix { from, to }           => [from, to],
+			Self::Regex { regex, replacement }  => [regex.as_str(), replacement],
+			Self::Query { name, value }         => [name, value],
+		}
+	}
+
+	/// Create a rule from its name and arguments, as returned by [`Self::name`] and [`Self::args`].
+	pub fn from_args(name: &str, args: [&str; 2]) -> Result<Self, String> {
+		match name {
+			"rewrite-prefix" => Ok(Self::prefix(args[0], args[1])),
+			"rewrite-regex"  => Self::regex(args[0], args[1]),
+			"add-query"      => Ok(Self::Query { name: args[0].to_string(), value: args[1].to_string() }),
+			_ => Err(format!("unknown rewrite rule: {}", name)),
+		}
+	}
+
+	/// Apply the rule to a URL.
+	///
+	/// Returns `None` if the rule does not change the URL.
+	pub fn apply(&self, url: &str) -> Option<String> {
+		let result = match self {
+			Self::Prefix { from, to } => format!("{}{}", to, url.strip_prefix(from.as_str())?),
+			Self::Regex { regex, replacement } => regex.replace(url, replacement.as_str()).into_owned(),
+			Self::Query { name, value } => set_query_parameter(url, name, value),
+		};
+		(result != url).then_some(result)
+	}
+}
+
+/// Escape everything except unreserved characters, for names and values in a query string.
+fn escape_query(byte: u8) -> bool {
+	!(byte.is_ascii_alphanumeric() || b"-._~".contains(&byte))
+}
+
+/// Set a query parameter in a URL, replacing all parameters with the same name.
+fn set_query_parameter(url: &str, name: &str, value: &str) -> String {
+	let (url, fragment) = match url.find('#') {
+		Some(i) => url.split_at(i),
+		None    => (url, ""),
+	};
+	let (base, query) = match url.split_once('?') {
+		Some((base, query)) => (base, Some(query)),
+		None                => (url, None),
+	};
+
+	let mut parameter = url_encode::encode(name.as_bytes(), escape_query);
+	parameter.push(b'=');
+	url_encode::encode_append(&mut parameter, value.as_bytes(), escape_query);
+	let parameter = String::from_utf8(parameter).unwrap();
+
+	let name = url_encode::encode(name.as_bytes(), escape_query);
+	let mut parameters: Vec<&str> = query.into_iter()
+		.flat_map(|x| x.split('&'))
+		.filter(|x| !x.is_empty() && x.split('=').next().map(str::as_bytes) != Some(&name))
+		.collect();
+	parameters.push(&parameter);
+
+	format!("{}?{}{}", base, parameters.join("&"), fragment)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_prefix() {
+		let rule = Rewrite::prefix("https://github.com/", "https://mirror.corp/github/");
+		assert_eq!(rule.apply("https://github.com/rust-lang/rust").as_deref(), Some("https://mirror.corp/github/rust-lang/rust"));
+		assert_eq!(rule.apply("https://gitlab.com/rust-lang/rust"), None);
+	}
+
+	#[test]
+	fn test_regex() {
+		let rule = Rewrite::regex(r"^https://([a-z]+)\.example\.com/", "https://mirror.corp/$1/").unwrap();
+		assert_eq!(rule.apply("https://pypi.example.com/simple/").as_deref(), Some("https://mirror.corp/pypi/simple/"));
+		assert_eq!(rule.apply("https://example.com/simple/"), None);
+		assert!(Rewrite::regex("(", "").is_err());
+	}
+
+	#[test]
+	fn test_query() {
+		let rule = Rewrite::query("token=a b&c").unwrap();
+		assert_eq!(rule.apply("https://example.com/path").as_deref(), Some("https://example.com/path?token=a%20b%26c"));
+		assert_eq!(rule.apply("https://example.com/?x=1&token=old#frag").as_deref(), Some("https://example.com/?x=1&token=a%20b%26c#frag"));
+		assert_eq!(rule.apply("https://example.com/?token=a%20b%26c"), None);
+		assert!(Rewrite::query("token").is_err());
+	}
+}
@@ -0,0 +1,239 @@
+use std::collections::BTreeMap;
+use std::os::raw::c_long;
+
+use curl_sys::CURLcode;
+use curl_sys::curl_off_t;
+
+use crate::UrlParts;
+
+/// The number of slowest transfers to list in a summary.
+const SLOWEST: usize = 5;
+
+/// A finished transfer, as recorded by the preload library for the summary.
+///
+/// Each record is one line with tab separated fields, so records of many processes can be appended to one file.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Record {
+	/// The `CURLcode` result of the transfer.
+	pub result: CURLcode,
+
+	/// The last response code, or 0 if no response was received.
+	pub response_code: c_long,
+
+	/// The number of bytes uploaded.
+	pub bytes_up: curl_off_t,
+
+	/// The number of bytes downloaded.
+	pub bytes_down: curl_off_t,
+
+	/// The total time of the transfer in microseconds.
+	pub total: curl_off_t,
+
+	/// The request method, or `-` if it is unknown.
+	pub method: String,
+
+	/// The last URL of the transfer, or `-` if it is unknown.
+	pub url: String,
+}
+
+impl Record {
+	/// Encode the record as a line, including the newline.
+	pub fn to_line(&self) -> String {
+		let clean = |x: &str| x.replace(['\t', '\n'], " ");
+		format!(
+			"{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+			self.result, self.response_code, self.bytes_up, self.bytes_down, self.total, clean(&self.method), clean(&self.url),
+		)
+	}
+
+	/// Parse a line as written by [`Self::to_line`], without the newline.
+	pub fn parse(line: &str) -> Result<Self, String> {
+		let fields: Vec<_> = line.split('\t').collect();
+		let [result, response_code, bytes_up, bytes_down, total, method, url] = fields.as_slice() else {
+			return Err(format!("invalid summary record: expected 7 fields, got {}", fields.len()));
+		};
+		let number = |x: &str| x.parse().map_err(|_| format!("invalid number in summary record: {}", x));
+		Ok(Self {
+			result: result.parse().map_err(|_| format!("invalid result in summary record: {}", result))?,
+			response_code: number(response_code)?,
+			bytes_up: number(bytes_up)?,
+			bytes_down: number(bytes_down)?,
+			total: number(total)?,
+			method: method.to_string(),
+			url: url.to_string(),
+		})
+	}
+
+	/// Get the host of the URL, or `-` if it is unknown.
+	pub fn host(&self) -> String {
+		UrlParts::parse(&self.url).map_or_else(|_| String::from("-"), |x| x.host)
+	}
+}
+
+/// Totals for the transfers to one host.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+struct HostTotals {
+	transfers: usize,
+	failed: usize,
+	bytes_up: curl_off_t,
+	bytes_down: curl_off_t,
+}
+
+/// A summary of all transfers of a process tree.
+#[derive(Clone, Debug, Default)]
+pub struct Summary {
+	records: Vec<Record>,
+}
+
+impl Summary {
+	/// Parse the records of a summary file.
+	///
+	/// Lines that can not be parsed, for example because a process was killed while writing, are skipped.
+	pub fn parse(data: &str) -> Self {
+		Self {
+			records: data.lines().filter_map(|x| Record::parse(x).ok()).collect(),
+		}
+	}
+
+	fn hosts(&self) -> BTreeMap<String, HostTotals> {
+		let mut hosts = BTreeMap::<String, HostTotals>::new();
+		for record in &self.records {
+			let totals = hosts.entry(record.host()).or_default();
+			totals.transfers += 1;
+			totals.failed += usize::from(record.result != 0);
+			totals.bytes_up += record.bytes_up;
+			totals.bytes_down += record.bytes_down;
+		}
+		hosts
+	}
+
+	fn failures(&self) -> BTreeMap<CURLcode, usize> {
+		let mut failures = BTreeMap::new();
+		for record in self.records.iter().filter(|x| x.result != 0) {
+			*failures.entry(record.result).or_default() += 1;
+		}
+		failures
+	}
+}
+
+impl std::fmt::Display for Summary {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		let failed: usize = self.records.iter().filter(|x| x.result != 0).count();
+		let bytes_up: curl_off_t = self.records.iter().map(|x| x.bytes_up).sum();
+		let bytes_down: curl_off_t = self.records.iter().map(|x| x.bytes_down).sum();
+		writeln!(f, "curl-inject-opt: {} transfers, {} failed, {} downloaded, {} uploaded", self.records.len(), failed, Size(bytes_down), Size(bytes_up))?;
+		if self.records.is_empty() {
+			return Ok(());
+		}
+
+		let hosts = self.hosts();
+		let width = hosts.keys().map(|x| x.len()).max().unwrap_or(0).max(4);
+		writeln!(f)?;
+		writeln!(f, "{:width$}  {:>9}  {:>6}  {:>10}  {:>10}", "Host", "Transfers", "Failed", "Down", "Up")?;
+		for (host, totals) in &hosts {
+			writeln!(f, "{:width$}  {:>9}  {:>6}  {:>10}  {:>10}", host, totals.transfers, totals.failed, Size(totals.bytes_down), Size(totals.bytes_up))?;
+		}
+
+		let failures = self.failures();
+		if !failures.is_empty() {
+			writeln!(f)?;
+			writeln!(f, "{:>8}  {:>5}  Error", "CURLcode", "Count")?;
+			for (code, count) in &failures {
+				writeln!(f, "{:>8}  {:>5}  {}", code, count, code_name(*code).unwrap_or("-"))?;
+			}
+		}
+
+		let mut slowest: Vec<_> = self.records.iter().collect();
+		slowest.sort_by_key(|x| std::cmp::Reverse(x.total));
+		writeln!(f)?;
+		writeln!(f, "{:>9}  {:>8}  {:>6}  URL", "Time", "Result", "Method")?;
+		for record in slowest.iter().take(SLOWEST) {
+			let result = match record.result {
+				0 => record.response_code.to_string(),
+				x => format!("error {}", x),
+			};
+			writeln!(f, "{:>8.3}s  {:>8}  {:>6}  {}", record.total as f64 / 1e6, result, record.method, record.url)?;
+		}
+		Ok(())
+	}
+}
+
+/// A number of bytes, formatted with a binary unit.
+struct Size(curl_off_t);
+
+impl std::fmt::Display for Size {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+		let mut value = self.0 as f64;
+		let mut unit = 0;
+		while value.abs() >= 1024.0 && unit + 1 < UNITS.len() {
+			value /= 1024.0;
+			unit += 1;
+		}
+		let text = match unit {
+			0 => format!("{} {}", self.0, UNITS[0]),
+			_ => format!("{:.1} {}", value, UNITS[unit]),
+		};
+		f.pad(&text)
+	}
+}
+
+/// The name of common `CURLcode` errors.
+fn code_name(code: CURLcode) -> Option<&'static str> {
+	let name = match code {
+		1  => "CURLE_UNSUPPORTED_PROTOCOL",
+		3  => "CURLE_URL_MALFORMAT",
+		5  => "CURLE_COULDNT_RESOLVE_PROXY",
+		6  => "CURLE_COULDNT_RESOLVE_HOST",
+		7  => "CURLE_COULDNT_CONNECT",
+		18 => "CURLE_PARTIAL_FILE",
+		22 => "CURLE_HTTP_RETURNED_ERROR",
+		23 => "CURLE_WRITE_ERROR",
+		28 => "CURLE_OPERATION_TIMEDOUT",
+		35 => "CURLE_SSL_CONNECT_ERROR",
+		42 => "CURLE_ABORTED_BY_CALLBACK",
+		47 => "CURLE_TOO_MANY_REDIRECTS",
+		52 => "CURLE_GOT_NOTHING",
+		56 => "CURLE_RECV_ERROR",
+		60 => "CURLE_PEER_FAILED_VERIFICATION",
+		_  => return None,
+	};
+	Some(name)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn record(result: CURLcode, bytes_down: curl_off_t, total: curl_off_t, url: &str) -> Record {
+		Record { result, response_code: if result == 0 { 200 } else { 0 }, bytes_up: 0, bytes_down, total, method: "GET".into(), url: url.into() }
+	}
+
+	#[test]
+	fn test_record() {
+		let record = record(0, 1234, 56_789, "https://example.com/a\tb");
+		let line = record.to_line();
+		assert_eq!(line, "0\t200\t0\t1234\t56789\tGET\thttps://example.com/a b\n");
+		assert_eq!(Record::parse(line.trim_end()), Ok(Record { url: "https://example.com/a b".into(), ..record }));
+		assert!(Record::parse("0\t200\t0").is_err());
+	}
+
+	#[test]
+	fn test_summary() {
+		let data = [
+			record(0, 2048, 1_500_000, "https://example.com/big"),
+			record(0, 10, 1_000, "https://example.com/small"),
+			record(7, 0, 500, "http://127.0.0.1:1/"),
+			record(7, 0, 400, "http://127.0.0.1:2/"),
+		].iter().map(Record::to_line).collect::<String>() + "0\t200\ttruncated";
+		let summary = Summary::parse(&data);
+		assert_eq!(summary.records.len(), 4);
+		assert_eq!(summary.failures(), BTreeMap::from([(7, 2)]));
+		assert_eq!(summary.hosts()["example.com"], HostTotals { transfers: 2, failed: 0, bytes_up: 0, bytes_down: 2058 });
+
+		let text = summary.to_string();
+		assert!(text.starts_with("curl-inject-opt: 4 transfers, 2 failed, 2.0 KiB downloaded, 0 B uploaded\n"));
+		assert!(text.contains("\n       7      2  CURLE_COULDNT_CONNECT\n"));
+		assert!(text.contains("\n   1.500s       200     GET  https://example.com/big\n"));
+	}
+}
@@ -2,6 +2,15 @@ pub fn escape_comma(byte: u8) -> bool {
 	byte == b',' || byte == b'%'
 }
 
+pub fn escape_semicolon(byte: u8) -> bool {
+	byte == b';' || byte == b'%'
+}
+
+/// Escape commas and everything that is not printable ASCII, so binary data can be stored in an environment variable.
+pub fn escape_binary(byte: u8) -> bool {
+	escape_comma(byte) || !(byte.is_ascii_graphic() || byte == b' ')
+}
+
 #[allow(clippy::identity_op)]
 pub fn encode_append(buffer: &mut Vec<u8>, data: &[u8], should_escape: impl Fn(u8) -> bool) {
 	let escape_count = data.iter().filter(|byte| should_escape(**byte)).count();
@@ -76,6 +85,8 @@ mod test {
 		assert_eq!(&encode(b",foo,bar,",       escape_comma), b"%2Cfoo%2Cbar%2C");
 		assert_eq!(&encode(b"%2Cfoo%2Cbar%2C", escape_comma), b"%252Cfoo%252Cbar%252C");
 		assert_eq!(&encode(b"%,foo%,%bar,%", escape_comma),   b"%25%2Cfoo%25%2C%25bar%2C%25");
+		assert_eq!(&encode(b"a;b,c%",        escape_semicolon), b"a%3Bb,c%25");
+		assert_eq!(&encode(b"a b\0\n,",       escape_binary),  b"a b%00%0A%2C");
 	}
 
 	#[test]